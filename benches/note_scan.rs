@@ -0,0 +1,63 @@
+//! Trial decryption of notes, one by one and in batches, as a wallet
+//! scans a slab. Both do one Diffie-Hellman key agreement per note, and
+//! that scalar multiplication dominates either way, so batching only
+//! trims the work around it. Run with `cargo bench --bench note_scan`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ff::Field;
+use rand::rngs::OsRng;
+
+use drk::crypto::{
+    keys::{Address, OutgoingViewingKey, ViewingKey},
+    note::{EncryptedNote, Memo, Note},
+};
+
+// Notes sent to someone else, which is what scanning mostly goes through
+fn other_notes(count: usize) -> Vec<EncryptedNote> {
+    let secret = jubjub::Fr::random(&mut OsRng);
+    let address = Address::from_secret(&secret);
+    let ovk = OutgoingViewingKey::from_secret(&secret);
+
+    (0..count)
+        .map(|_| {
+            let note = Note {
+                serial: jubjub::Fr::random(&mut OsRng),
+                value: 110,
+                token_id: jubjub::Fr::random(&mut OsRng),
+                coin_blind: jubjub::Fr::random(&mut OsRng),
+                valcom_blind: jubjub::Fr::random(&mut OsRng),
+                memo: Memo::default(),
+            };
+            note.encrypt(&address, &ovk).unwrap()
+        })
+        .collect()
+}
+
+fn scan(c: &mut Criterion) {
+    let view_key = ViewingKey::from_secret(&jubjub::Fr::random(&mut OsRng));
+
+    let mut group = c.benchmark_group("note_scan");
+    for count in [2, 64, 1024] {
+        let notes = other_notes(count);
+        group.throughput(Throughput::Elements(count as u64));
+
+        group.bench_with_input(BenchmarkId::new("decrypt", count), &notes, |b, notes| {
+            b.iter(|| {
+                notes
+                    .iter()
+                    .filter(|note| note.decrypt(&view_key).is_ok())
+                    .count()
+            })
+        });
+
+        group.bench_with_input(
+            BenchmarkId::new("decrypt_batch", count),
+            &notes,
+            |b, notes| b.iter(|| EncryptedNote::decrypt_batch(notes, &view_key)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, scan);
+criterion_main!(benches);
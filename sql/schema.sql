@@ -3,6 +3,10 @@ CREATE TABLE IF NOT EXISTS keys(
 	key_public BLOB NOT NULL,
 	key_private BLOB NOT NULL
 );
+CREATE TABLE IF NOT EXISTS seed(
+	seed_id INTEGER PRIMARY KEY NOT NULL,
+	mnemonic TEXT NOT NULL
+);
 PRAGMA foreign_keys=on;
 CREATE TABLE IF NOT EXISTS coins(
 	coin BLOB PRIMARY KEY NOT NULL,
@@ -14,5 +18,29 @@ CREATE TABLE IF NOT EXISTS coins(
 	witness BLOB NOT NULL,
 	secret BLOB NOT NULL,
 	is_spent BOOLEAN NOT NULL,
-	nullifier BLOB NOT NULL
+	nullifier BLOB NOT NULL,
+	memo BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS transactions(
+	tx_id BLOB NOT NULL,
+	coin BLOB NOT NULL,
+	token_id BLOB NOT NULL,
+	value INTEGER NOT NULL,
+	recipient BLOB NOT NULL,
+	memo BLOB NOT NULL,
+	PRIMARY KEY(tx_id, coin)
+);
+CREATE TABLE IF NOT EXISTS view_keys(
+	key_id INTEGER PRIMARY KEY NOT NULL,
+	view_key BLOB NOT NULL UNIQUE
+);
+CREATE TABLE IF NOT EXISTS viewed_notes(
+	coin BLOB PRIMARY KEY NOT NULL,
+	view_public BLOB NOT NULL,
+	serial BLOB NOT NULL,
+	value INTEGER NOT NULL,
+	token_id BLOB NOT NULL,
+	coin_blind BLOB NOT NULL,
+	valcom_blind BLOB NOT NULL,
+	memo BLOB NOT NULL
 );
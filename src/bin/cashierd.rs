@@ -2,22 +2,24 @@ use async_std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use async_executor::Executor;
 use async_trait::async_trait;
 use clap::clap_app;
 use easy_parallel::Parallel;
 use ff::Field;
-use log::debug;
+use log::{debug, warn};
 use rand::rngs::OsRng;
 use serde_json::{json, Value};
+use smol::Timer;
 
 use drk::{
-    blockchain::{rocks::columns, Rocks, RocksColumn},
+    blockchain::Rocks,
     cli::{CashierdConfig, Config},
     client::{Client, State},
     crypto::{
-        load_params, merkle::CommitmentTree, save_params, setup_mint_prover, setup_spend_prover,
+        keys::Address, load_params, note::Memo, save_params, setup_mint_prover, setup_spend_prover,
     },
     rpc::{
         jsonrpc::{error as jsonerr, response as jsonresp},
@@ -31,6 +33,11 @@ use drk::{
     Error, Result,
 };
 
+// How many times the coins of a deposit are sent to the gateway before the
+// cashier gives up on it, and how long it waits in between
+const DEPOSIT_ATTEMPTS: u32 = 5;
+const DEPOSIT_RETRY_DELAY: Duration = Duration::from_secs(10);
+
 fn handle_bridge_error(error_code: u32) -> Result<()> {
     match error_code {
         1 => Err(Error::BridgeError("Not Supported Client".into())),
@@ -145,7 +152,7 @@ impl Cashierd {
     async fn listen_for_receiving_coins(
         bridge: Arc<Bridge>,
         cashier_wallet: Arc<CashierDb>,
-        recv_coin: async_channel::Receiver<(jubjub::SubgroupPoint, u64)>,
+        recv_coin: async_channel::Receiver<(Address, u64)>,
         executor: Arc<Executor<'_>>,
     ) -> Result<()> {
         // received drk coin
@@ -154,7 +161,8 @@ impl Cashierd {
         debug!(target: "CASHIER DAEMON", "Receive coin with amount: {}", amount);
 
         // get public key, and token_id of the token
-        let token = cashier_wallet.get_withdraw_token_public_key_by_dkey_public(&drk_pub_key)?;
+        let token =
+            cashier_wallet.get_withdraw_token_public_key_by_dkey_public(&drk_pub_key.public)?;
 
         // send a request to bridge to send equivalent amount of
         // received drk coin to token publickey
@@ -259,7 +267,7 @@ impl Cashierd {
                 mint_address = "";
             }
             let drk_pub_key = bs58::decode(&drk_pub_key).into_vec()?;
-            let drk_pub_key: jubjub::SubgroupPoint = deserialize(&drk_pub_key)?;
+            let drk_pub_key: Address = deserialize(&drk_pub_key)?;
 
             // check if the drk public key already exist
             let check = self
@@ -393,16 +401,16 @@ impl Cashierd {
 
             let address = serialize(&address.to_string());
 
-            let cashier_public: jubjub::SubgroupPoint;
+            let cashier_address: Address;
 
             if let Some(addr) = self
                 .cashier_wallet
                 .get_withdraw_keys_by_token_public_key(&address, &network)?
             {
-                cashier_public = addr.public;
+                cashier_address = addr.address();
             } else {
                 let cashier_secret = jubjub::Fr::random(&mut OsRng);
-                cashier_public =
+                let cashier_public =
                     zcash_primitives::constants::SPENDING_KEY_GENERATOR * cashier_secret;
 
                 self.cashier_wallet.put_withdraw_keys(
@@ -413,10 +421,12 @@ impl Cashierd {
                     &token_id,
                     mint_address.into(),
                 )?;
+
+                cashier_address = Address::from_secret(&cashier_secret);
             }
 
-            let cashier_public_str = bs58::encode(serialize(&cashier_public)).into_string();
-            Ok(cashier_public_str)
+            let cashier_address_str = bs58::encode(serialize(&cashier_address)).into_string();
+            Ok(cashier_address_str)
         }
         .await;
 
@@ -453,6 +463,47 @@ impl Cashierd {
         }
     }
 
+    // Send the coins of a deposit and wait until they are in a slab. The
+    // gateway may drop the transaction, e.g. when the state moved on while
+    // it waited, so it is built and sent again a few times.
+    async fn send_deposit(
+        client: Arc<Mutex<Client>>,
+        state: Arc<Mutex<State>>,
+        address: Address,
+        amount: u64,
+        token_id: jubjub::Fr,
+    ) -> Result<u64> {
+        let mut attempt = 1;
+        loop {
+            let result = async {
+                let submission = client
+                    .lock()
+                    .await
+                    .send(
+                        address,
+                        amount,
+                        token_id,
+                        Memo::default(),
+                        true,
+                        state.clone(),
+                    )
+                    .await?;
+                // The client is not locked while the gateway works on it
+                submission.submit().await
+            }
+            .await;
+
+            match result {
+                Err(e) if attempt < DEPOSIT_ATTEMPTS => {
+                    warn!(target: "CASHIER DAEMON", "Send deposit, attempt {}: {}", attempt, e);
+                    Timer::after(DEPOSIT_RETRY_DELAY).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
     async fn start(
         &mut self,
         mut client: Client,
@@ -558,9 +609,9 @@ impl Cashierd {
             executor.clone(),
         ));
 
-        client.start().await?;
+        client.start(state.clone(), false).await?;
 
-        let (notify, recv_coin) = async_channel::unbounded::<(jubjub::SubgroupPoint, u64)>();
+        let (notify, recv_coin) = async_channel::unbounded::<(Address, u64)>();
 
         client
             .connect_to_subscriber_from_cashier(
@@ -571,6 +622,8 @@ impl Cashierd {
             )
             .await?;
 
+        let client = Arc::new(Mutex::new(client));
+
         let cashier_wallet = self.cashier_wallet.clone();
         let bridge = self.bridge.clone();
         let ex = executor.clone();
@@ -601,15 +654,27 @@ impl Cashierd {
                         token_notification.decimals,
                     )?;
 
-                    client
-                        .send(
-                            token_notification.drk_pub_key,
+                    let deposit = Self::send_deposit(
+                        client.clone(),
+                        state.clone(),
+                        token_notification.drk_pub_key,
+                        received_balance,
+                        token_notification.token_id,
+                    )
+                    .await;
+
+                    // The deposit stays unconfirmed, and the bridge goes on
+                    // with the next one
+                    if let Err(e) = deposit {
+                        warn!(
+                            target: "CASHIER DAEMON",
+                            "Deposit of {} on {} to {} was not sent: {}",
                             received_balance,
-                            token_notification.token_id,
-                            true,
-                            state.clone(),
-                        )
-                        .await?;
+                            token_notification.network,
+                            bs58::encode(serialize(&token_notification.drk_pub_key)).into_string(),
+                            e
+                        );
+                    }
                 }
                 Ok(())
             });
@@ -666,22 +731,20 @@ async fn start(
         client_wallet.clone(),
         mint_params,
         spend_params,
+        // The cashier only sends deposits, which pay no fee
+        0,
     )
     .await?;
 
-    let merkle_roots = RocksColumn::<columns::MerkleRoots>::new(rocks.clone());
-    let nullifiers = RocksColumn::<columns::Nullifiers>::new(rocks);
-
     let cashier_public_keys = vec![client.main_keypair.public];
 
-    let state = Arc::new(Mutex::new(State {
-        tree: CommitmentTree::empty(),
-        merkle_roots,
-        nullifiers,
-        mint_pvk,
-        spend_pvk,
-        public_keys: cashier_public_keys,
-    }));
+    let mut state = State::new(rocks, mint_pvk, spend_pvk, cashier_public_keys)?;
+
+    // Pick up the merkle tree where the last run left it
+    let last_applied = state.load_tree()?;
+    debug!(target: "CASHIER DAEMON", "Last applied slab: {}", last_applied);
+
+    let state = Arc::new(Mutex::new(state));
 
     if get_address_flag {
         let cashier_public = client.main_keypair.public;
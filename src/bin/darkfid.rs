@@ -14,11 +14,14 @@ use serde_json::{json, Value};
 use url::Url;
 
 use drk::{
-    blockchain::{rocks::columns, Rocks, RocksColumn},
+    blockchain::Rocks,
     cli::{Config, DarkfidConfig},
-    client::{Client, State},
+    client::{Client, ClientFailed, State},
     crypto::{
-        load_params, merkle::CommitmentTree, save_params, setup_mint_prover, setup_spend_prover,
+        keys::{Address, ViewingKey},
+        load_params,
+        note::Memo,
+        save_params, setup_mint_prover, setup_spend_prover,
     },
     rpc::{
         jsonrpc::{error as jsonerr, request as jsonreq, response as jsonresp, send_raw_request},
@@ -26,7 +29,7 @@ use drk::{
         rpcserver::{listen_and_serve, RequestHandler, RpcServerConfig},
     },
     serial::{deserialize, serialize},
-    state::ProgramState,
+    state::StateQuery,
     util::{
         assign_id, decode_base10, encode_base10, expand_path, join_config_path, DrkTokenList,
         NetworkName, TokenList,
@@ -56,12 +59,19 @@ impl RequestHandler for Darkfid {
             Some("create_wallet") => return self.create_wallet(req.id, req.params).await,
             Some("key_gen") => return self.key_gen(req.id, req.params).await,
             Some("get_key") => return self.get_key(req.id, req.params).await,
+            Some("get_mnemonic") => return self.get_mnemonic(req.id, req.params).await,
+            Some("new_account") => return self.new_account(req.id, req.params).await,
+            Some("get_accounts") => return self.get_accounts(req.id, req.params).await,
             Some("get_balances") => return self.get_balances(req.id, req.params).await,
+            Some("get_history") => return self.get_history(req.id, req.params).await,
+            Some("export_viewing_key") => return self.export_viewing_key(req.id, req.params).await,
+            Some("import_viewing_key") => return self.import_viewing_key(req.id, req.params).await,
             Some("get_token_id") => return self.get_token_id(req.id, req.params).await,
             Some("features") => return self.features(req.id, req.params).await,
             Some("deposit") => return self.deposit(req.id, req.params).await,
             Some("withdraw") => return self.withdraw(req.id, req.params).await,
             Some("transfer") => return self.transfer(req.id, req.params).await,
+            Some("dry_run_transfer") => return self.dry_run_transfer(req.id, req.params).await,
             Some(_) | None => return JsonResult::Err(jsonerr(MethodNotFound, None, req.id)),
         };
     }
@@ -93,8 +103,12 @@ impl Darkfid {
         })
     }
 
-    async fn start(&mut self, executor: Arc<Executor<'_>>) -> Result<()> {
-        self.client.lock().await.start().await?;
+    async fn start(&mut self, executor: Arc<Executor<'_>>, full_replay: bool) -> Result<()> {
+        self.client
+            .lock()
+            .await
+            .start(self.state.clone(), full_replay)
+            .await?;
         self.client
             .lock()
             .await
@@ -151,11 +165,89 @@ impl Darkfid {
     // --> {"method": "get_key", "params": []}
     // <-- {"result": "vdNS7oBj7KvsMWWmo9r96SV4SqATLrGsH2a3PGpCfJC"}
     async fn get_key(&self, id: Value, _params: Value) -> JsonResult {
-        let pk = self.client.lock().await.main_keypair.public;
-        let b58 = bs58::encode(serialize(&pk)).into_string();
+        let address = self.client.lock().await.main_keypair.address();
+        let b58 = bs58::encode(serialize(&address)).into_string();
         JsonResult::Resp(jsonresp(json!(b58), id))
     }
 
+    // --> {"method": "get_mnemonic", "params": []}
+    // <-- {"result": "abandon ability able about above absent absorb abstract ..."}
+    async fn get_mnemonic(&self, id: Value, _params: Value) -> JsonResult {
+        match self.client.lock().await.get_mnemonic() {
+            Ok(Some(phrase)) => JsonResult::Resp(jsonresp(json!(phrase), id)),
+            Ok(None) => JsonResult::Err(jsonerr(
+                InternalError,
+                Some(ClientFailed::NoSeed.to_string()),
+                id,
+            )),
+            Err(e) => JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "new_account", "params": []}
+    // <-- {"result": "vdNS7oBj7KvsMWWmo9r96SV4SqATLrGsH2a3PGpCfJC"}
+    async fn new_account(&self, id: Value, _params: Value) -> JsonResult {
+        match self.client.lock().await.new_account() {
+            Ok(address) => {
+                let b58 = bs58::encode(serialize(&address)).into_string();
+                JsonResult::Resp(jsonresp(json!(b58), id))
+            }
+            Err(e) => JsonResult::Err(jsonerr(ServerError(-32002), Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "get_accounts", "params": []}
+    // <-- {"result": ["vdNS7oBj7KvsMWWmo9r96SV4SqATLrGsH2a3PGpCfJC", ..]}
+    async fn get_accounts(&self, id: Value, _params: Value) -> JsonResult {
+        match self.client.lock().await.get_addresses() {
+            Ok(addresses) => {
+                let addresses: Vec<String> = addresses
+                    .iter()
+                    .map(|address| bs58::encode(serialize(address)).into_string())
+                    .collect();
+                JsonResult::Resp(jsonresp(json!(addresses), id))
+            }
+            Err(e) => JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id)),
+        }
+    }
+
+    // --> {"method": "export_viewing_key", "params": []}
+    // <-- {"result": "8ZbxuRUtvwZ7AFW4MJ9BiFSm9d6ZLFYUQ5kqbbmqb5kG"}
+    async fn export_viewing_key(&self, id: Value, _params: Value) -> JsonResult {
+        let view_key = self.client.lock().await.export_viewing_key();
+        let b58 = bs58::encode(serialize(&view_key)).into_string();
+        JsonResult::Resp(jsonresp(json!(b58), id))
+    }
+
+    // --> {"method": "import_viewing_key", "params": ["8ZbxuRUtvwZ7AFW4MJ9BiFSm9d6ZLFYUQ5kqbbmqb5kG"]}
+    // <-- {"result": true}
+    async fn import_viewing_key(&self, id: Value, params: Value) -> JsonResult {
+        let args = params.as_array();
+        if args.is_none() {
+            return JsonResult::Err(jsonerr(InvalidParams, None, id));
+        }
+        let args = args.unwrap();
+        if args.len() != 1 {
+            return JsonResult::Err(jsonerr(InvalidParams, None, id));
+        }
+
+        let view_key = match args[0].as_str() {
+            Some(view_key) => view_key,
+            None => return JsonResult::Err(jsonerr(InvalidParams, None, id)),
+        };
+
+        let result: Result<()> = async {
+            let view_key: ViewingKey = deserialize(&bs58::decode(view_key).into_vec()?)?;
+            self.client.lock().await.import_viewing_key(&view_key)
+        }
+        .await;
+
+        match result {
+            Ok(()) => JsonResult::Resp(jsonresp(json!(true), id)),
+            Err(err) => JsonResult::Err(jsonerr(InternalError, Some(err.to_string()), json!(id))),
+        }
+    }
+
     // --> {"method": "get_balances", "params": []}
     // <-- {"result": "get_balances": "[ {"btc": (value, network)}, .. ]"}
     async fn get_balances(&self, id: Value, _params: Value) -> JsonResult {
@@ -191,6 +283,60 @@ impl Darkfid {
         }
     }
 
+    // --> {"method": "get_history", "params": []}
+    // <-- {"result": [{"direction": "sent", "tx_id": "ab..", "token": "btc",
+    //                  "amount": "0.1", "address": "vdNS..", "memo": "rent"}, ..]}
+    async fn get_history(&self, id: Value, _params: Value) -> JsonResult {
+        let result: Result<Vec<Value>> = async {
+            let client = self.client.lock().await;
+            let mut history = vec![];
+
+            for note in client.get_received_notes()? {
+                history.push(json!({
+                    "direction": "received",
+                    "token": self.token_symbol(note.token_id)?,
+                    "amount": encode_base10(BigUint::from(note.value), 8),
+                    "memo": String::from_utf8_lossy(note.memo.as_bytes()),
+                }));
+            }
+
+            // Received by the addresses of imported viewing keys
+            for viewed in client.get_viewed_notes()? {
+                history.push(json!({
+                    "direction": "viewed",
+                    "token": self.token_symbol(viewed.note.token_id)?,
+                    "amount": encode_base10(BigUint::from(viewed.note.value), 8),
+                    "memo": String::from_utf8_lossy(viewed.note.memo.as_bytes()),
+                }));
+            }
+
+            for tx in client.get_transactions()? {
+                history.push(json!({
+                    "direction": "sent",
+                    "tx_id": hex::encode(tx.tx_id),
+                    "token": self.token_symbol(tx.token_id)?,
+                    "amount": encode_base10(BigUint::from(tx.value), 8),
+                    "address": bs58::encode(serialize(&tx.recipient)).into_string(),
+                    "memo": String::from_utf8_lossy(tx.memo.as_bytes()),
+                }));
+            }
+
+            Ok(history)
+        }
+        .await;
+        match result {
+            Ok(res) => JsonResult::Resp(jsonresp(json!(res), id)),
+            Err(err) => JsonResult::Err(jsonerr(InternalError, Some(err.to_string()), json!(id))),
+        }
+    }
+
+    fn token_symbol(&self, token_id: jubjub::Fr) -> Result<String> {
+        Ok(self
+            .drk_tokenlist
+            .symbol_from_id(token_id)?
+            .unwrap_or_else(|| token_id.to_string()))
+    }
+
     // --> {"method": "get_token_id", "params": [network, token]}
     // <-- {"result": "Ht5G1RhkcKnpLVLMhqJc5aqZ4wYUEbxbtZwGCVbgU7DL"}
     async fn get_token_id(&self, id: Value, params: Value) -> JsonResult {
@@ -308,8 +454,8 @@ impl Darkfid {
 
         // TODO: Optional sanity checking here, but cashier *must* do so too.
 
-        let pk = self.client.lock().await.main_keypair.public;
-        let pubkey = bs58::encode(serialize(&pk)).into_string();
+        let address = self.client.lock().await.main_keypair.address();
+        let pubkey = bs58::encode(serialize(&address)).into_string();
 
         // Send request to cashier. If the cashier supports the requested network
         // (and token), it shall return a valid address where tokens can be deposited.
@@ -419,24 +565,33 @@ impl Darkfid {
 
         // send drk to cashier_public
         if let JsonResult::Resp(cashier_public) = &rep {
-            let result: Result<()> = async {
+            let result: Result<[u8; 32]> = async {
                 let cashier_public = cashier_public.result.as_str().unwrap();
 
-                let cashier_public: jubjub::SubgroupPoint =
+                let cashier_public: Address =
                     deserialize(&bs58::decode(cashier_public).into_vec()?)?;
 
-                self.client
+                // Let the cashier know where the withdrawal goes
+                let memo = Memo::new(address.as_bytes())?;
+
+                let submission = self
+                    .client
                     .lock()
                     .await
                     .transfer(
                         *token_id,
                         cashier_public,
                         amount_in_apo.try_into()?,
+                        memo,
                         self.state.clone(),
                     )
                     .await?;
 
-                Ok(())
+                // Only reply once the transaction is in a slab. The client
+                // is not locked while the gateway is working on it.
+                let tx_id = submission.tx_id;
+                submission.submit().await?;
+                Ok(tx_id)
             }
             .await;
 
@@ -444,14 +599,8 @@ impl Darkfid {
                 Err(e) => {
                     rep = JsonResult::Err(jsonerr(InternalError, Some(e.to_string()), id.clone()))
                 }
-                Ok(_) => {
-                    rep = JsonResult::Resp(jsonresp(
-                        json!(format!(
-                            "Sent request to withdraw {} amount of {}",
-                            amount, token_id
-                        )),
-                        id.clone(),
-                    ))
+                Ok(tx_id) => {
+                    rep = JsonResult::Resp(jsonresp(json!(hex::encode(tx_id)), id.clone()))
                 }
             }
         };
@@ -463,16 +612,124 @@ impl Darkfid {
         }
     }
 
-    // --> {"method": "transfer", [dToken, address, amount]}
+    // --> {"method": "transfer", [dToken, address, amount, memo?]}
     // <-- {"result": "txID"}
     async fn transfer(&self, id: Value, params: Value) -> JsonResult {
+        let (token_id, address, amount, memo) = match self.transfer_params(&id, &params) {
+            Ok(args) => args,
+            Err(err) => return *err,
+        };
+
+        let result: Result<[u8; 32]> = async {
+            let drk_address = bs58::decode(&address).into_vec()?;
+            let drk_address: Address = deserialize(&drk_address)?;
+
+            let decimals: usize = 8;
+            let amount = decode_base10(&amount, decimals, true)?;
+
+            self.update_balances().await?;
+
+            let submission = self
+                .client
+                .lock()
+                .await
+                .transfer(
+                    token_id,
+                    drk_address,
+                    amount.try_into()?,
+                    memo,
+                    self.state.clone(),
+                )
+                .await?;
+
+            // Only reply once the transaction is in a slab. The client is
+            // not locked while the gateway is working on it.
+            let tx_id = submission.tx_id;
+            submission.submit().await?;
+            Ok(tx_id)
+        }
+        .await;
+
+        match result {
+            Ok(tx_id) => JsonResult::Resp(jsonresp(json!(hex::encode(tx_id)), id)),
+            Err(err) => JsonResult::Err(jsonerr(InternalError, Some(err.to_string()), json!(id))),
+        }
+    }
+
+    // --> {"method": "dry_run_transfer", [dToken, address, amount, memo?]}
+    // <-- {"result": {"tx_id": "ab..", "fee": "0.001", "inputs": [{"coin": "cd..",
+    //                 "amount": "1"}], "change": "0.899", "valid": true, "error": null}}
+    async fn dry_run_transfer(&self, id: Value, params: Value) -> JsonResult {
+        let (token_id, address, amount, memo) = match self.transfer_params(&id, &params) {
+            Ok(args) => args,
+            Err(err) => return *err,
+        };
+
+        let result: Result<Value> = async {
+            let drk_address = bs58::decode(&address).into_vec()?;
+            let drk_address: Address = deserialize(&drk_address)?;
+
+            let decimals: usize = 8;
+            let amount = decode_base10(&amount, decimals, true)?;
+
+            self.update_balances().await?;
+
+            let dry_run = self
+                .client
+                .lock()
+                .await
+                .dry_run_transfer(
+                    token_id,
+                    drk_address,
+                    amount.try_into()?,
+                    memo,
+                    self.state.clone(),
+                )
+                .await?;
+
+            let inputs: Vec<Value> = dry_run
+                .inputs
+                .iter()
+                .map(|own_coin| {
+                    json!({
+                        "coin": hex::encode(own_coin.coin.repr),
+                        "amount": encode_base10(BigUint::from(own_coin.note.value), 8),
+                    })
+                })
+                .collect();
+
+            Ok(json!({
+                "tx_id": hex::encode(dry_run.tx_id),
+                "fee": encode_base10(BigUint::from(dry_run.fee), 8),
+                "inputs": inputs,
+                "change": dry_run.change.map(|change| encode_base10(BigUint::from(change), 8)),
+                "valid": dry_run.verified.is_ok(),
+                "error": dry_run.verified.err().map(|e| e.to_string()),
+            }))
+        }
+        .await;
+
+        match result {
+            Ok(res) => JsonResult::Resp(jsonresp(res, id)),
+            Err(err) => JsonResult::Err(jsonerr(InternalError, Some(err.to_string()), json!(id))),
+        }
+    }
+
+    // Parse the [dToken, address, amount, memo?] params shared by transfer
+    // and dry_run_transfer
+    fn transfer_params(
+        &self,
+        id: &Value,
+        params: &Value,
+    ) -> std::result::Result<(jubjub::Fr, String, String, Memo), Box<JsonResult>> {
+        let id = id.clone();
         let args = params.as_array();
         if args.is_none() {
-            return JsonResult::Err(jsonerr(InvalidParams, None, id));
+            return Err(Box::new(JsonResult::Err(jsonerr(InvalidParams, None, id))));
         }
         let args = args.unwrap();
-        if args.len() != 3 {
-            return JsonResult::Err(jsonerr(InvalidParams, None, id));
+        if args.len() != 3 && args.len() != 4 {
+            return Err(Box::new(JsonResult::Err(jsonerr(InvalidParams, None, id))));
         }
 
         let token: &str;
@@ -486,57 +743,57 @@ impl Darkfid {
                 amount = val;
             }
             (None, _, _) => {
-                return JsonResult::Err(jsonerr(InvalidTokenIdParam, None, id));
+                return Err(Box::new(JsonResult::Err(jsonerr(
+                    InvalidTokenIdParam,
+                    None,
+                    id,
+                ))));
             }
             (_, None, _) => {
-                return JsonResult::Err(jsonerr(InvalidAddressParam, None, id));
+                return Err(Box::new(JsonResult::Err(jsonerr(
+                    InvalidAddressParam,
+                    None,
+                    id,
+                ))));
             }
             (_, _, None) => {
-                return JsonResult::Err(jsonerr(InvalidAmountParam, None, id));
+                return Err(Box::new(JsonResult::Err(jsonerr(
+                    InvalidAmountParam,
+                    None,
+                    id,
+                ))));
             }
         }
 
-        let token_id: &jubjub::Fr;
+        let memo = match args.get(3).map(|m| m.as_str()) {
+            None => Memo::default(),
+            Some(Some(m)) => match Memo::new(m.as_bytes()) {
+                Ok(m) => m,
+                Err(e) => {
+                    return Err(Box::new(JsonResult::Err(jsonerr(
+                        InvalidParams,
+                        Some(e.to_string()),
+                        id,
+                    ))))
+                }
+            },
+            Some(None) => return Err(Box::new(JsonResult::Err(jsonerr(InvalidParams, None, id)))),
+        };
 
         // get the id for the token
-        if let Some(tk_id) = self.drk_tokenlist.tokens.get(&token.to_uppercase()) {
-            token_id = tk_id;
-        } else {
-            return JsonResult::Err(jsonerr(InvalidParams, None, id));
-        }
-
-        let result: Result<()> = async {
-            let drk_address = bs58::decode(&address).into_vec()?;
-            let drk_address: jubjub::SubgroupPoint = deserialize(&drk_address)?;
-
-            let decimals: usize = 8;
-            let amount = decode_base10(amount, decimals, true)?;
-
-            self.update_balances().await?;
-
-            self.client
-                .lock()
-                .await
-                .transfer(
-                    *token_id,
-                    drk_address,
-                    amount.try_into()?,
-                    self.state.clone(),
-                )
-                .await?;
-
-            Ok(())
-        }
-        .await;
-
-        match result {
-            Ok(_) => JsonResult::Resp(jsonresp(json!("Success"), id)),
-            Err(err) => JsonResult::Err(jsonerr(InternalError, Some(err.to_string()), json!(id))),
+        match self.drk_tokenlist.tokens.get(&token.to_uppercase()) {
+            Some(token_id) => Ok((*token_id, address.into(), amount.into(), memo)),
+            None => Err(Box::new(JsonResult::Err(jsonerr(InvalidParams, None, id)))),
         }
     }
 }
 
-async fn start(executor: Arc<Executor<'_>>, config: &DarkfidConfig) -> Result<()> {
+async fn start(
+    executor: Arc<Executor<'_>>,
+    config: &DarkfidConfig,
+    rollback: Option<u64>,
+    full_replay: bool,
+) -> Result<()> {
     let wallet = WalletDb::new(
         expand_path(&config.wallet_path)?.as_path(),
         config.wallet_password.clone(),
@@ -592,22 +849,25 @@ async fn start(executor: Arc<Executor<'_>>, config: &DarkfidConfig) -> Result<()
         wallet.clone(),
         mint_params,
         spend_params,
+        config.fee,
     )
     .await?;
 
     let client = Arc::new(Mutex::new(client));
 
-    let merkle_roots = RocksColumn::<columns::MerkleRoots>::new(rocks.clone());
-    let nullifiers = RocksColumn::<columns::Nullifiers>::new(rocks);
+    let mut state = State::new(rocks, mint_pvk, spend_pvk, cashier_keys)?;
+
+    // Pick up the merkle tree where the last run left it
+    let last_applied = state.load_tree()?;
+    debug!(target: "DARKFI DAEMON", "Last applied slab: {}", last_applied);
+
+    if let Some(index) = rollback {
+        state.rollback(index, Some(wallet))?;
+        println!("Rolled back to slab {}", index);
+        return Ok(());
+    }
 
-    let state = Arc::new(Mutex::new(State {
-        tree: CommitmentTree::empty(),
-        merkle_roots,
-        nullifiers,
-        mint_pvk,
-        spend_pvk,
-        public_keys: cashier_keys,
-    }));
+    let state = Arc::new(Mutex::new(state));
 
     let mut darkfid = Darkfid::new(client, state, cashiers).await?;
 
@@ -618,7 +878,7 @@ async fn start(executor: Arc<Executor<'_>>, config: &DarkfidConfig) -> Result<()
         identity_pass: config.tls_identity_password.clone(),
     };
 
-    darkfid.start(executor.clone()).await?;
+    darkfid.start(executor.clone(), full_replay).await?;
     listen_and_serve(server_config, Arc::new(darkfid), executor).await
 }
 
@@ -628,6 +888,9 @@ async fn main() -> Result<()> {
         (@arg CONFIG: -c --config +takes_value "Sets a custom config file")
         (@arg verbose: -v --verbose "Increase verbosity")
         (@arg refresh: -r --refresh "Refresh the wallet and slabstore")
+        (@arg rollback: --rollback +takes_value "Roll back the slabstore, state and wallet to the given slab index")
+        (@arg restore: --restore "Restore the wallet keys from a mnemonic phrase read from stdin")
+        (@arg accounts: --accounts +takes_value "Number of accounts to restore, 1 by default")
     )
     .get_matches();
 
@@ -647,6 +910,11 @@ async fn main() -> Result<()> {
 
     let config: DarkfidConfig = Config::<DarkfidConfig>::load(config_path)?;
 
+    let rollback = args
+        .value_of("rollback")
+        .map(str::parse::<u64>)
+        .transpose()?;
+
     if args.is_present("refresh") {
         debug!(target: "DARKFI DAEMON", "Refresh the wallet and the database");
 
@@ -667,6 +935,41 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
+    let full_replay = args.is_present("restore");
+    if full_replay {
+        debug!(target: "DARKFI DAEMON", "Restore the wallet keys");
+
+        let accounts = args
+            .value_of("accounts")
+            .map(str::parse::<u32>)
+            .transpose()?
+            .unwrap_or(1);
+
+        println!("Enter the mnemonic phrase of the wallet:");
+        let mut phrase = String::new();
+        std::io::stdin().read_line(&mut phrase)?;
+
+        let wallet = WalletDb::new(
+            expand_path(&config.wallet_path)?.as_path(),
+            config.wallet_password.clone(),
+        )?;
+
+        wallet.init_db().await?;
+        wallet.restore(&phrase, accounts)?;
+
+        // Replay every slab so the coins of the restored keys are found
+        let database_path = expand_path(&config.database_path)?;
+        if database_path.exists() {
+            debug!(target: "DARKFI DAEMON", "Remove database: {:?}", database_path);
+            std::fs::remove_dir_all(database_path)?;
+        }
+
+        println!(
+            "Wallet restored with {} accounts, replaying the slabs.",
+            accounts
+        );
+    }
+
     let ex = Arc::new(Executor::new());
     let (signal, shutdown) = async_channel::unbounded::<()>();
 
@@ -682,7 +985,7 @@ async fn main() -> Result<()> {
         // Run the main future on the current thread.
         .finish(|| {
             smol::future::block_on(async move {
-                start(ex2, &config).await?;
+                start(ex2, &config, rollback, full_replay).await?;
                 drop(signal);
                 Ok::<(), drk::Error>(())
             })
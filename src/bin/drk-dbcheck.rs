@@ -0,0 +1,62 @@
+use clap::clap_app;
+
+use drk::{blockchain::dbcheck, blockchain::Rocks, serial::deserialize, util::expand_path, Result};
+
+fn main() -> Result<()> {
+    let args = clap_app!(dbcheck =>
+        (@arg DATABASE: +required "Path to the database directory")
+        (@arg cashier: --cashier +takes_value +multiple number_of_values(1) "Public key of a cashier whose deposits are valid, as in the node's config")
+        (@arg repair: --repair "Remove broken slabs and rebuild the state from the rest")
+        (@arg verbose: -v --verbose "Increase verbosity")
+    )
+    .get_matches();
+
+    let loglevel = if args.is_present("verbose") {
+        log::Level::Debug
+    } else {
+        log::Level::Info
+    };
+
+    simple_logger::init_with_level(loglevel)?;
+
+    let mut cashier_keys = Vec::new();
+    for public_key in args.values_of("cashier").into_iter().flatten() {
+        let cashier_public: jubjub::SubgroupPoint =
+            deserialize(&bs58::decode(public_key).into_vec()?)?;
+        cashier_keys.push(cashier_public);
+    }
+    if cashier_keys.is_empty() {
+        log::warn!("No --cashier keys given, deposits will be treated as invalid");
+    }
+
+    // Opened as it is, a database which needs migrating is not checked
+    let rocks = Rocks::open(&expand_path(args.value_of("DATABASE").unwrap())?)?;
+
+    let report = dbcheck::check(rocks, &cashier_keys, args.is_present("repair"))?;
+
+    for problem in report.problems.iter() {
+        println!("{}", problem);
+    }
+
+    println!("Last slab in the chain: {}", report.last_index);
+    if !report.state_checked {
+        println!("No merkle tree and nullifiers to compare against the slabs");
+    }
+
+    if report.problems.is_empty() {
+        println!("No problems found");
+        return Ok(());
+    }
+
+    if report.repaired > 0 {
+        println!("Fixed {} problems", report.repaired);
+    }
+
+    let remaining = report.problems.len() - report.repaired;
+    if remaining > 0 {
+        println!("{} problems remain", remaining);
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
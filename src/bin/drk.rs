@@ -92,6 +92,42 @@ impl Drk {
         Ok(self.request(req).await?)
     }
 
+    // --> {"jsonrpc": "2.0", "method": "get_mnemonic", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": "abandon ability able about above ...", "id": 42}
+    async fn get_mnemonic(&self) -> Result<Value> {
+        let req = jsonrpc::request(json!("get_mnemonic"), json!([]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "new_account", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": "vdNS7oBj7KvsMWWmo9r96SV4SqATLrGsH2a3PGpCfJC", "id": 42}
+    async fn new_account(&self) -> Result<Value> {
+        let req = jsonrpc::request(json!("new_account"), json!([]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "get_accounts", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": ["vdNS7oBj7KvsMWWmo9r96SV4SqATLrGsH2a3PGpCfJC"], "id": 42}
+    async fn get_accounts(&self) -> Result<Value> {
+        let req = jsonrpc::request(json!("get_accounts"), json!([]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "export_viewing_key", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": "8ZbxuRUtvwZ7AFW4MJ9BiFSm9d6ZLFYUQ5kqbbmqb5kG", "id": 42}
+    async fn export_viewing_key(&self) -> Result<Value> {
+        let req = jsonrpc::request(json!("export_viewing_key"), json!([]));
+        Ok(self.request(req).await?)
+    }
+
+    // --> {"jsonrpc": "2.0", "method": "import_viewing_key",
+    //      "params": ["8ZbxuRUtvwZ7AFW4MJ9BiFSm9d6ZLFYUQ5kqbbmqb5kG"], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": true, "id": 42}
+    async fn import_viewing_key(&self, view_key: &str) -> Result<Value> {
+        let req = jsonrpc::request(json!("import_viewing_key"), json!([view_key]));
+        Ok(self.request(req).await?)
+    }
+
     // --> {"jsonrpc": "2.0", "method": "get_key", "params": ["solana", "usdc"], "id": 42}
     // <-- {"jsonrpc": "2.0", "result": "vdNS7oBj7KvsMWWmo9r96SV4SqATLrGsH2a3PGpCfJC", "id": 42}
     async fn get_token_id(&self, network: &str, token: &str) -> Result<Value> {
@@ -134,11 +170,36 @@ impl Drk {
         Ok(self.request(req).await?)
     }
 
+    // --> {"jsonrpc": "2.0", "method": "get_history", "params": [], "id": 42}
+    // <-- {"jsonrpc": "2.0", "result": [{"direction": "sent", "tx_id": "ab..", "token": "btc",
+    //      "amount": "0.1", "address": "vdNS..", "memo": "rent"}, ..], "id": 42}
+    async fn get_history(&self) -> Result<Value> {
+        let req = jsonrpc::request(json!("get_history"), json!([]));
+        Ok(self.request(req).await?)
+    }
+
     // --> {"jsonrpc": "2.0", "method": "transfer",
-    //      "params": ["dusdc", "vdNS7oBj7KvsMWWmo9r96SV4SqATLrGsH2a3PGpCfJC", 13.37], "id": 42}
+    //      "params": ["dusdc", "vdNS7oBj7KvsMWWmo9r96SV4SqATLrGsH2a3PGpCfJC", 13.37, "memo"?], "id": 42}
     // <-- {"jsonrpc": "2.0", "result": "txID", "id": 42}
-    async fn transfer(&self, token: &str, address: &str, amount: &str) -> Result<Value> {
-        let req = jsonrpc::request(json!("transfer"), json!([token, address, amount]));
+    // With dry_run the transfer is only built and verified, see dry_run_transfer
+    async fn transfer(
+        &self,
+        token: &str,
+        address: &str,
+        amount: &str,
+        memo: Option<&str>,
+        dry_run: bool,
+    ) -> Result<Value> {
+        let params = match memo {
+            Some(memo) => json!([token, address, amount, memo]),
+            None => json!([token, address, amount]),
+        };
+        let method = if dry_run {
+            "dry_run_transfer"
+        } else {
+            "transfer"
+        };
+        let req = jsonrpc::request(json!(method), params);
         Ok(self.request(req).await?)
     }
 }
@@ -179,6 +240,47 @@ async fn start(config: &DrkConfig, options: ArgMatches<'_>) -> Result<()> {
             return Ok(());
         }
 
+        if matches.is_present("mnemonic") {
+            let reply = client.get_mnemonic().await?;
+            println!("Mnemonic phrase: {}", reply.as_str().unwrap_or_default());
+            println!("Write it down and keep it secret, it restores every account of the wallet.");
+            return Ok(());
+        }
+
+        if matches.is_present("new_account") {
+            let reply = client.new_account().await?;
+            println!("Account address: {}", &reply.to_string());
+            return Ok(());
+        }
+
+        if matches.is_present("accounts") {
+            let reply = client.get_accounts().await?;
+
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+            table.set_titles(row!["account", "address"]);
+
+            for (i, address) in reply.as_array().unwrap_or(&vec![]).iter().enumerate() {
+                table.add_row(row![i, address.as_str().unwrap_or_default()]);
+            }
+
+            table.printstd();
+
+            return Ok(());
+        }
+
+        if matches.is_present("export_view_key") {
+            let reply = client.export_viewing_key().await?;
+            println!("Viewing key: {}", reply.as_str().unwrap_or_default());
+            return Ok(());
+        }
+
+        if let Some(view_key) = matches.value_of("import_view_key") {
+            client.import_viewing_key(view_key).await?;
+            println!("Imported viewing key");
+            return Ok(());
+        }
+
         if matches.is_present("balances") {
             let reply = client.get_balances().await?;
 
@@ -202,6 +304,27 @@ async fn start(config: &DrkConfig, options: ArgMatches<'_>) -> Result<()> {
 
             return Ok(());
         }
+
+        if matches.is_present("history") {
+            let reply = client.get_history().await?;
+
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+            table.set_titles(row!["direction", "token", "amount", "memo"]);
+
+            for entry in reply.as_array().unwrap_or(&vec![]) {
+                table.add_row(row![
+                    entry["direction"].as_str().unwrap_or_default(),
+                    entry["token"].as_str().unwrap_or_default(),
+                    entry["amount"].as_str().unwrap_or_default(),
+                    entry["memo"].as_str().unwrap_or_default()
+                ]);
+            }
+
+            table.printstd();
+
+            return Ok(());
+        }
     }
 
     if let Some(matches) = options.subcommand_matches("id") {
@@ -265,13 +388,48 @@ async fn start(config: &DrkConfig, options: ArgMatches<'_>) -> Result<()> {
         let token_sym = matches.value_of("TOKENSYM").unwrap();
         let address = matches.value_of("ADDRESS").unwrap();
         let amount = matches.value_of("AMOUNT").unwrap();
+        let memo = matches.value_of("memo");
+        let dry_run = matches.is_present("dry_run");
+
+        let reply = client
+            .transfer(token_sym, address, amount, memo, dry_run)
+            .await?;
 
-        client.transfer(token_sym, address, amount).await?;
+        if dry_run {
+            println!("Dry run, nothing was sent");
+            println!(
+                "Transaction ID: {}",
+                reply["tx_id"].as_str().unwrap_or_default()
+            );
+            println!("Fee: {}", reply["fee"].as_str().unwrap_or_default());
+            println!("Change: {}", reply["change"].as_str().unwrap_or("0"));
+
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+            table.set_titles(row!["input coin", "amount"]);
+
+            for input in reply["inputs"].as_array().unwrap_or(&vec![]) {
+                table.add_row(row![
+                    input["coin"].as_str().unwrap_or_default(),
+                    input["amount"].as_str().unwrap_or_default()
+                ]);
+            }
+
+            table.printstd();
+
+            match reply["error"].as_str() {
+                Some(error) => println!("Verification failed: {}", error),
+                None => println!("Verification passed"),
+            }
+
+            return Ok(());
+        }
 
         println!(
-            "{} {} Transfered successfully",
+            "{} {} Transfered successfully, transaction ID: {}",
             amount.to_string(),
             token_sym.to_string().to_uppercase(),
+            reply.as_str().unwrap_or_default(),
         );
 
         return Ok(());
@@ -295,6 +453,12 @@ async fn main() -> Result<()> {
      (@arg keygen: --keygen "Generate wallet keypair")
      (@arg address: --address "Get wallet address")
      (@arg balances: --balances "Get wallet balances")
+     (@arg mnemonic: --mnemonic "Get the mnemonic phrase to back up the wallet keys")
+     (@arg new_account: --("new-account") "Derive a new account from the wallet seed")
+     (@arg accounts: --accounts "Get the addresses of every account")
+     (@arg history: --history "Get received and sent payments")
+     (@arg export_view_key: --("export-view-key") "Get the viewing key of the wallet address")
+     (@arg import_view_key: --("import-view-key") +takes_value "Watch payments to the address of a viewing key")
     )
     (@subcommand id =>
      (about: "Get hexidecimal ID for token symbol")
@@ -318,6 +482,8 @@ async fn main() -> Result<()> {
      (@arg TOKENSYM: +required "Desired token (btc/sol/usdc...)")
      (@arg ADDRESS: +required "Recipient address")
      (@arg AMOUNT: +required "Amount to send")
+     (@arg memo: +takes_value --memo "Message for the recipient, up to 512 bytes")
+     (@arg dry_run: --("dry-run") "Build and verify the transfer without sending it")
     )
     (@subcommand withdraw =>
      (about: "Withdraw Dark tokens for clear tokens")
@@ -4,24 +4,72 @@ use std::sync::Arc;
 use async_executor::Executor;
 use clap::clap_app;
 use easy_parallel::Parallel;
-use log::debug;
+use log::{debug, warn};
 
 use drk::{
-    blockchain::{rocks::columns, Rocks, RocksColumn},
+    blockchain::{rocks::columns, Rocks, RocksColumn, Snapshot},
     cli::{Config, GatewaydConfig},
+    client::State,
+    crypto::load_params,
+    serial::deserialize,
     service::GatewayService,
     util::{expand_path, join_config_path},
-    Result,
+    Error, Result,
 };
 
+// The state every slab is verified against before the gateway stores it
+fn load_state(rocks: Arc<Rocks>, config: &GatewaydConfig) -> Result<State> {
+    let mut cashier_keys = Vec::new();
+    for public_key in config.cashier_public_keys.iter() {
+        let cashier_public: jubjub::SubgroupPoint =
+            deserialize(&bs58::decode(public_key).into_vec()?)?;
+        cashier_keys.push(cashier_public);
+    }
+    if cashier_keys.is_empty() {
+        warn!(target: "GATEWAY DAEMON", "No cashier_public_keys set, deposits will be refused");
+    }
+
+    // Load trusted setup parameters. Proofs made with other parameters would
+    // not verify, so the gateway never creates its own.
+    let params_paths = (
+        expand_path(&config.mint_params_path.clone())?,
+        expand_path(&config.spend_params_path.clone())?,
+    );
+    for path in [&params_paths.0, &params_paths.1] {
+        if !path.exists() {
+            return Err(Error::ParamsNotFound(path.to_string_lossy().into_owned()));
+        }
+    }
+    let mint_params_path = params_paths.0.to_str().unwrap_or("mint.params");
+    let spend_params_path = params_paths.1.to_str().unwrap_or("spend.params");
+    let (_, mint_pvk) = load_params(mint_params_path)?;
+    let (_, spend_pvk) = load_params(spend_params_path)?;
+
+    let mut state = State::new(rocks, mint_pvk, spend_pvk, cashier_keys)?;
+
+    let last_applied = state.load_tree()?;
+    debug!(target: "GATEWAY DAEMON", "Last applied slab: {}", last_applied);
+
+    Ok(state)
+}
+
 async fn start(executor: Arc<Executor<'_>>, config: &GatewaydConfig) -> Result<()> {
     let rocks = Rocks::new(&expand_path(&config.database_path)?)?;
-    let rocks_slabstore_column = RocksColumn::<columns::Slabs>::new(rocks);
+    let state = load_state(rocks.clone(), config)?;
+    let rocks_snapshots_column = RocksColumn::<columns::Snapshots>::new(rocks);
+
+    let mut min_fees = vec![];
+    for (token_id, min_fee) in config.min_fees.iter() {
+        let token_id: jubjub::Fr = deserialize(&bs58::decode(token_id).into_vec()?)?;
+        min_fees.push((token_id, *min_fee));
+    }
 
     let gateway = GatewayService::new(
         config.protocol_listen_address,
         config.publisher_listen_address,
-        rocks_slabstore_column,
+        state,
+        rocks_snapshots_column,
+        min_fees,
     )?;
 
     Ok(gateway.start(executor.clone()).await?)
@@ -32,6 +80,8 @@ async fn main() -> Result<()> {
     let args = clap_app!(gatewayd =>
         (@arg CONFIG: -c --config +takes_value "Sets a custom config file")
         (@arg verbose: -v --verbose "Increase verbosity")
+        (@arg rollback: --rollback +takes_value "Roll back the slabstore and state to the given slab index")
+        (@arg snapshot: --snapshot +takes_value "Take a snapshot at the given index and prune the slabs it covers")
     )
     .get_matches();
 
@@ -54,6 +104,59 @@ async fn main() -> Result<()> {
 
     let config: GatewaydConfig = Config::<GatewaydConfig>::load(config_path)?;
 
+    if let Some(index) = args.value_of("rollback") {
+        let index: u64 = index.parse()?;
+        let rocks = Rocks::new(&expand_path(&config.database_path)?)?;
+        let mut state = load_state(rocks.clone(), &config)?;
+        let snapshots = RocksColumn::<columns::Snapshots>::new(rocks);
+
+        // Slabs covered by the snapshot have been pruned and can't be replayed
+        if let Some(snapshot) = snapshots.get_value_deserialized::<Snapshot>(Snapshot::KEY)? {
+            if snapshot.get_index() > index {
+                return Err(Error::SlabsStore(format!(
+                    "Can not roll back past the snapshot at slab {}",
+                    snapshot.get_index()
+                )));
+            }
+        }
+
+        // Undo the state the removed slabs produced along with them
+        state.rollback(index, None)?;
+        println!("Rolled back to slab {}", index);
+        return Ok(());
+    }
+
+    if let Some(index) = args.value_of("snapshot") {
+        let index: u64 = index.parse()?;
+        let rocks = Rocks::new(&expand_path(&config.database_path)?)?;
+        let state = load_state(rocks.clone(), &config)?;
+        let slabstore = state.slabstore.clone();
+        let snapshots = RocksColumn::<columns::Snapshots>::new(rocks);
+
+        if let Some(base) = snapshots.get_value_deserialized::<Snapshot>(Snapshot::KEY)? {
+            if base.get_index() >= index {
+                return Err(Error::SlabsStore(format!(
+                    "There is already a snapshot at slab {}",
+                    base.get_index()
+                )));
+            }
+        }
+
+        // Taken from the state the gateway verified every slab against
+        let snapshot = state.snapshot(index)?;
+
+        let mut batch = slabstore.batch();
+        snapshots.put_batch(&mut batch, Snapshot::KEY, snapshot)?;
+        let pruned = slabstore.prune_to_batch(&mut batch, index)?;
+        batch.commit()?;
+
+        println!(
+            "Took a snapshot at slab {} and pruned {} slabs",
+            index, pruned
+        );
+        return Ok(());
+    }
+
     let config_ptr = Arc::new(&config);
 
     let ex2 = ex.clone();
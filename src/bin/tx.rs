@@ -1,20 +1,19 @@
-use bellman::groth16;
-use bls12_381::Bls12;
 use ff::{Field, PrimeField};
 use rand::rngs::OsRng;
 use std::path::Path;
 
 use drk::crypto::{
     coin::Coin,
+    keys::{Address, ViewingKey},
     load_params,
     merkle::{CommitmentTree, IncrementalWitness},
     merkle_node::MerkleNode,
     note::{EncryptedNote, Note},
     nullifier::Nullifier,
-    save_params, setup_mint_prover, setup_spend_prover,
+    save_params, setup_mint_prover, setup_spend_prover, PreparedKey,
 };
 use drk::serial::{Decodable, Encodable};
-use drk::state::{ProgramState, StateUpdate};
+use drk::state::{ProgramState, StateQuery, StateUpdate};
 use drk::tx;
 
 struct MemoryState {
@@ -33,9 +32,9 @@ struct MemoryState {
     own_coins: Vec<(Coin, Note, jubjub::Fr, IncrementalWitness<MerkleNode>)>,
 
     // Mint verifying key used by ZK
-    mint_pvk: groth16::PreparedVerifyingKey<Bls12>,
+    mint_pvk: PreparedKey,
     // Spend verifying key used by ZK
-    spend_pvk: groth16::PreparedVerifyingKey<Bls12>,
+    spend_pvk: PreparedKey,
 
     // Public key of the cashier
     cashier_public: jubjub::SubgroupPoint,
@@ -43,7 +42,7 @@ struct MemoryState {
     secrets: Vec<jubjub::Fr>,
 }
 
-impl ProgramState for MemoryState {
+impl StateQuery for MemoryState {
     fn is_valid_cashier_public_key(&self, public: &jubjub::SubgroupPoint) -> bool {
         public == &self.cashier_public
     }
@@ -54,10 +53,17 @@ impl ProgramState for MemoryState {
         self.nullifiers.iter().any(|n| n.repr == nullifier.repr)
     }
 
-    fn mint_pvk(&self) -> &groth16::PreparedVerifyingKey<Bls12> {
+    // Transactions are applied one by one here, without slabs
+    fn height(&self) -> drk::Result<u64> {
+        Ok(0)
+    }
+}
+
+impl ProgramState for MemoryState {
+    fn mint_pvk(&self) -> &PreparedKey {
         &self.mint_pvk
     }
-    fn spend_pvk(&self) -> &groth16::PreparedVerifyingKey<Bls12> {
+    fn spend_pvk(&self) -> &PreparedKey {
         &self.spend_pvk
     }
 }
@@ -102,7 +108,7 @@ impl MemoryState {
         // Loop through all our secret keys...
         for secret in &self.secrets {
             // ... attempt to decrypt the note ...
-            if let Ok(note) = ciphertext.decrypt(secret) {
+            if let Ok(note) = ciphertext.decrypt(&ViewingKey::from_secret(secret)) {
                 // ... and return the decrypted note for this coin.
                 return Some((note, *secret));
             }
@@ -135,8 +141,8 @@ async fn main() {
 
     // Wallet 1 creates a secret key
     let secret = jubjub::Fr::random(&mut OsRng);
-    // This is their public key
-    let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
+    // This is their address
+    let address = Address::from_secret(&secret);
 
     let mut state = MemoryState {
         tree: CommitmentTree::empty(),
@@ -155,19 +161,20 @@ async fn main() {
     // Clear inputs are visible to everyone on the network
 
     let token_id = jubjub::Fr::random(&mut OsRng);
-    let builder = tx::TransactionBuilder {
-        clear_inputs: vec![tx::TransactionBuilderClearInputInfo {
+    let builder = tx::TransactionBuilder::new(
+        vec![tx::TransactionBuilderClearInputInfo {
             value: 110,
             token_id,
             signature_secret: cashier_secret,
         }],
-        inputs: vec![],
-        outputs: vec![tx::TransactionBuilderOutputInfo {
+        vec![],
+        vec![tx::TransactionBuilderOutputInfo {
             value: 110,
             token_id,
-            public,
+            address,
+            memo: Default::default(),
         }],
-    };
+    );
 
     // We will 'compile' the tx, and then serialize it to this Vec<u8>
     let mut tx_data = vec![];
@@ -263,28 +270,34 @@ async fn main() {
 
     // The receiving wallet has a secret key
     let secret2 = jubjub::Fr::random(&mut OsRng);
-    // This is their public key to receive payment
-    let public2 = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret2;
+    // This is their address to receive payment
+    let address2 = Address::from_secret(&secret2);
 
     // Make a spend tx
 
     //let inputs: Vec<tx::TransactionBuilderInputInfo> = vec![];
     // Construct a new tx spending the coin
     // We need the decrypted note and our private key
-    let builder = tx::TransactionBuilder {
-        clear_inputs: vec![],
-        inputs: vec![tx::TransactionBuilderInputInfo {
+    let mut builder = tx::TransactionBuilder::new(
+        vec![],
+        vec![tx::TransactionBuilderInputInfo {
             merkle_path,
             secret,
             note: state.own_coins[0].1.clone(),
         }],
         // We can add more outputs to this list.
-        // The only constraint is that sum(value in) == sum(value out)
-        outputs: vec![tx::TransactionBuilderOutputInfo {
-            value: 110,
+        // The only constraint is that sum(value in) == sum(value out) + fee
+        vec![tx::TransactionBuilderOutputInfo {
+            value: 100,
             token_id,
-            public: public2,
+            address: address2,
+            memo: Default::default(),
         }],
+    );
+    // The fee is public and paid in one of the tokens of the tx
+    builder.fee = tx::TransactionFee {
+        value: 10,
+        token_id,
     };
     // Build the tx
     let mut tx_data = vec![];
@@ -338,7 +351,7 @@ pub fn state_transition<S: ProgramState>(
         let nullifier = &input.revealed.nullifier;
 
         if state.nullifier_exists(nullifier) {
-            return Err(VerifyFailed::DuplicateNullifier(i));
+            return Err(VerifyFailed::DuplicateNullifier(i, None));
         }
     }
 
@@ -346,6 +359,8 @@ pub fn state_transition<S: ProgramState>(
     // Check the tx verifies correctly
     tx.verify(state.mint_pvk(), state.spend_pvk())?;
 
+    let tx_id = tx.id();
+
     let mut nullifiers = vec![];
     for input in tx.inputs {
         nullifiers.push(input.revealed.nullifier);
@@ -361,6 +376,7 @@ pub fn state_transition<S: ProgramState>(
     }
 
     Ok(StateUpdate {
+        tx_id,
         nullifiers,
         coins,
         enc_notes,
@@ -0,0 +1,428 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use async_std::sync::Arc;
+
+use super::kvstore::{IteratorMode, KvStore};
+use super::rocks::{columns, Column, RocksColumn, WriteBatch};
+use super::slab::Slab;
+use super::slabstore::SlabStore;
+use super::snapshot::Snapshot;
+use crate::client::State;
+use crate::crypto::{
+    coin::Coin, merkle::CommitmentTree, merkle_node::MerkleNode, nullifier::Nullifier,
+};
+use crate::serial::{deserialize, Decodable};
+use crate::tx::Transaction;
+use crate::Result;
+
+/// Something found to be wrong with a database.
+#[derive(Debug)]
+pub enum Problem {
+    BadSlab(u64),
+    MissingSlab(u64),
+    BrokenChain(u64),
+    BadPayload(u64),
+    PrunedSlab(u64),
+    BadTransaction(u64),
+    TreeMismatch(u64),
+    MissingMerkleRoot(MerkleNode),
+    ExtraMerkleRoot(MerkleNode),
+    MissingNullifier(Nullifier, u64),
+    ExtraNullifier(Nullifier),
+    NullifierSlab(Nullifier, u64),
+}
+
+impl Problem {
+    // Slab problems after which the rest of the chain can not be trusted
+    fn breaks_chain(&self) -> bool {
+        matches!(
+            self,
+            Problem::BadSlab(_)
+                | Problem::MissingSlab(_)
+                | Problem::BrokenChain(_)
+                | Problem::BadPayload(_)
+                | Problem::PrunedSlab(_)
+        )
+    }
+
+    /// Whether `check` fixes this problem when asked to repair. A payload
+    /// which is not a transaction stays, as it is part of the chain.
+    pub fn is_repairable(&self) -> bool {
+        !matches!(self, Problem::BadTransaction(_))
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::BadSlab(i) => write!(f, "Slab {} can not be decoded", i),
+            Problem::MissingSlab(i) => write!(f, "Slab {} is missing", i),
+            Problem::BrokenChain(i) => {
+                write!(f, "Slab {} does not link to the slab before it", i)
+            }
+            Problem::BadPayload(i) => write!(f, "Payload of slab {} does not match its hash", i),
+            Problem::PrunedSlab(i) => {
+                write!(f, "Slab {} is pruned but no snapshot covers it", i)
+            }
+            Problem::BadTransaction(i) => {
+                write!(f, "Payload of slab {} is not a transaction", i)
+            }
+            Problem::TreeMismatch(i) => {
+                write!(f, "Saved merkle tree does not match the slabs up to {}", i)
+            }
+            Problem::MissingMerkleRoot(root) => {
+                write!(f, "Merkle root {} is missing", hex::encode(root.repr))
+            }
+            Problem::ExtraMerkleRoot(root) => {
+                write!(
+                    f,
+                    "Merkle root {} is not produced by any slab",
+                    hex::encode(root.repr)
+                )
+            }
+            Problem::MissingNullifier(nullifier, i) => write!(
+                f,
+                "Nullifier {} revealed in slab {} is missing",
+                hex::encode(nullifier.repr),
+                i
+            ),
+            Problem::ExtraNullifier(nullifier) => write!(
+                f,
+                "Nullifier {} is not revealed by any slab",
+                hex::encode(nullifier.repr)
+            ),
+            Problem::NullifierSlab(nullifier, i) => write!(
+                f,
+                "Nullifier {} should point to slab {}",
+                hex::encode(nullifier.repr),
+                i
+            ),
+        }
+    }
+}
+
+pub struct CheckReport {
+    /// Index of the last slab which links to the rest of the chain
+    pub last_index: u64,
+    /// Whether there was a saved tree to compare, and the slabs to rebuild it
+    pub state_checked: bool,
+    pub problems: Vec<Problem>,
+    /// How many of the problems have been fixed
+    pub repaired: usize,
+}
+
+/// Walk the slabs and confirm they form an unbroken chain of transactions,
+/// then rebuild the merkle tree, merkle roots and nullifiers from them and
+/// compare with what is stored.
+///
+/// Slabs are replayed with the checks clients make against the state, so a
+/// slab a client skipped is skipped here too. Deposits count only when
+/// signed by one of `cashier_public_keys`, which should be the keys the
+/// node was run with. Proofs and signatures are not checked, see
+/// `Snapshot::build`.
+///
+/// With `repair`, slabs from the first break in the chain onwards are
+/// removed and the state columns are rewritten to match the slabs that are
+/// left. Coins in wallets are not touched.
+pub fn check<K: KvStore>(
+    store: Arc<K>,
+    cashier_public_keys: &[jubjub::SubgroupPoint],
+    repair: bool,
+) -> Result<CheckReport> {
+    let slabstore = SlabStore::new(RocksColumn::<columns::Slabs, K>::new(store.clone()))?;
+    let snapshots = RocksColumn::<columns::Snapshots, K>::new(store.clone());
+    let saved_tree = RocksColumn::<columns::Tree, K>::new(store.clone());
+
+    let base = snapshots.get_value_deserialized::<Snapshot>(Snapshot::KEY)?;
+
+    let mut problems = vec![];
+    let (first_index, last_index) = check_slabs(&*store, base.as_ref(), &mut problems)?;
+
+    let mut batch = slabstore.batch();
+    if repair && problems.iter().any(Problem::breaks_chain) {
+        slabstore.truncate_to_batch(&mut batch, last_index)?;
+    }
+
+    // Gateways keep no state besides the slabs. Otherwise the state is
+    // rebuilt from the snapshot, or from the start of the chain.
+    let saved = saved_tree
+        .get_value_deserialized::<(u64, CommitmentTree<MerkleNode>)>(State::<K>::TREE_KEY)?;
+    let expected = match (&saved, base) {
+        (None, _) => None,
+        (_, Some(base)) if (first_index..=last_index).contains(&base.get_index()) => Some(base),
+        (_, Some(_)) => None,
+        (_, None) if first_index <= 1 => Some(Snapshot::empty()),
+        (_, None) => None,
+    };
+    let expected = match expected {
+        Some(base) if base.get_index() < last_index => Some(Snapshot::build(
+            &slabstore,
+            Some(base),
+            last_index,
+            cashier_public_keys,
+        )?),
+        expected => expected,
+    };
+
+    let state_checked = expected.is_some();
+    if let (Some((index, tree)), Some(expected)) = (saved, expected) {
+        if index != expected.get_index() || tree.root() != expected.tree.root() {
+            problems.push(Problem::TreeMismatch(expected.get_index()));
+        }
+
+        check_state(&*store, &expected, &mut problems)?;
+
+        if repair {
+            repair_state(&*store, &expected, &problems, &mut batch)?;
+        }
+    }
+
+    let repaired = if repair {
+        problems
+            .iter()
+            .filter(|problem| problem.is_repairable())
+            .count()
+    } else {
+        0
+    };
+    if repaired > 0 {
+        batch.commit()?;
+    }
+
+    Ok(CheckReport {
+        last_index,
+        state_checked,
+        problems,
+        repaired,
+    })
+}
+
+// Returns the first index and the index of the last slab that is part of
+// the unbroken chain.
+fn check_slabs<K: KvStore>(
+    store: &K,
+    base: Option<&Snapshot>,
+    problems: &mut Vec<Problem>,
+) -> Result<(u64, u64)> {
+    let snapshot_index = base.map(Snapshot::get_index).unwrap_or(0);
+    let mut prev: Option<Slab> = None;
+    let mut first_index = 0;
+
+    for (key, value) in store.iterator(columns::Slabs::NAME, IteratorMode::Start)? {
+        let index = match SlabStore::<K>::index_from_key(&key) {
+            Some(index) => index,
+            None => {
+                let index = prev.as_ref().map_or(1, |prev| prev.get_index() + 1);
+                problems.push(Problem::BadSlab(index.max(snapshot_index)));
+                break;
+            }
+        };
+
+        let slab = match deserialize::<Slab>(&value) {
+            Ok(slab) if slab.get_index() == index => slab,
+            _ => {
+                problems.push(Problem::BadSlab(index));
+                break;
+            }
+        };
+
+        let linked = match &prev {
+            Some(prev) if index != prev.get_index() + 1 => {
+                problems.push(Problem::MissingSlab(prev.get_index() + 1));
+                break;
+            }
+            Some(prev) => slab.extends(Some(prev)),
+            // The first slab of a store loaded from a snapshot has no parent
+            None => slab.extends(None) || (slab.is_pruned() && index == snapshot_index),
+        };
+        if !linked {
+            problems.push(Problem::BrokenChain(index));
+            break;
+        }
+
+        if slab.is_pruned() {
+            if index > snapshot_index {
+                problems.push(Problem::PrunedSlab(index));
+                break;
+            }
+        } else if !slab.verify_payload() {
+            problems.push(Problem::BadPayload(index));
+            break;
+        } else if Transaction::decode(&slab.get_payload()[..]).is_err() {
+            // Clients skip such slabs, so the chain itself is still fine
+            problems.push(Problem::BadTransaction(index));
+        }
+
+        if prev.is_none() {
+            first_index = index;
+        }
+        prev = Some(slab);
+    }
+
+    Ok((first_index, prev.map(|slab| slab.get_index()).unwrap_or(0)))
+}
+
+fn check_state<K: KvStore>(
+    store: &K,
+    expected: &Snapshot,
+    problems: &mut Vec<Problem>,
+) -> Result<()> {
+    let expected_roots: HashSet<[u8; 32]> =
+        expected.merkle_roots.iter().map(|root| root.repr).collect();
+    let mut stored_roots = HashSet::new();
+    for (key, _) in store.iterator(columns::MerkleRoots::NAME, IteratorMode::Start)? {
+        let root: MerkleNode = deserialize(&key)?;
+        if !expected_roots.contains(&root.repr) {
+            problems.push(Problem::ExtraMerkleRoot(root));
+        }
+        stored_roots.insert(root.repr);
+    }
+    for root in expected.merkle_roots.iter() {
+        if !stored_roots.contains(&root.repr) {
+            problems.push(Problem::MissingMerkleRoot(*root));
+        }
+    }
+
+    let expected_nullifiers: HashMap<[u8; 32], u64> = expected
+        .nullifiers
+        .iter()
+        .map(|(nullifier, index)| (nullifier.repr, *index))
+        .collect();
+    let mut stored_nullifiers = HashSet::new();
+    for (key, value) in store.iterator(columns::Nullifiers::NAME, IteratorMode::Start)? {
+        let nullifier: Nullifier = deserialize(&key)?;
+        match expected_nullifiers.get(&nullifier.repr) {
+            None => problems.push(Problem::ExtraNullifier(nullifier.clone())),
+            Some(index) if deserialize::<u64>(&value).ok() != Some(*index) => {
+                problems.push(Problem::NullifierSlab(nullifier.clone(), *index))
+            }
+            Some(_) => {}
+        }
+        stored_nullifiers.insert(nullifier.repr);
+    }
+    for (nullifier, index) in expected.nullifiers.iter() {
+        if !stored_nullifiers.contains(&nullifier.repr) {
+            problems.push(Problem::MissingNullifier(nullifier.clone(), *index));
+        }
+    }
+
+    Ok(())
+}
+
+fn repair_state<K: KvStore>(
+    store: &K,
+    expected: &Snapshot,
+    problems: &[Problem],
+    batch: &mut WriteBatch<K>,
+) -> Result<()> {
+    for problem in problems {
+        match problem {
+            Problem::TreeMismatch(index) => {
+                batch.put::<columns::Tree>(State::<K>::TREE_KEY, (*index, expected.tree.clone()))?
+            }
+            Problem::MissingMerkleRoot(root) => {
+                batch.put::<columns::MerkleRoots>(*root, vec![] as Vec<u8>)?
+            }
+            Problem::ExtraMerkleRoot(root) => batch.delete::<columns::MerkleRoots>(*root)?,
+            Problem::MissingNullifier(nullifier, index)
+            | Problem::NullifierSlab(nullifier, index) => {
+                batch.put::<columns::Nullifiers>(nullifier.clone(), *index)?
+            }
+            Problem::ExtraNullifier(nullifier) => {
+                batch.delete::<columns::Nullifiers>(nullifier.clone())?
+            }
+            _ => {}
+        }
+    }
+
+    // Undo records and coins past the last slab belong to removed slabs
+    for (key, _) in store.iterator(columns::Undo::NAME, IteratorMode::Start)? {
+        let index: u64 = deserialize(&key)?;
+        if index > expected.get_index() {
+            batch.delete::<columns::Undo>(index)?;
+        }
+    }
+    for (key, value) in store.iterator(columns::Coins::NAME, IteratorMode::Start)? {
+        let index: u64 = deserialize(&value)?;
+        if index > expected.get_index() {
+            batch.delete::<columns::Coins>(deserialize::<Coin>(&key)?)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::MemStore;
+
+    #[test]
+    fn test_check_and_repair() -> Result<()> {
+        let store = MemStore::new();
+        let slabstore = SlabStore::new(RocksColumn::new(store.clone()))?;
+        let empty_root = CommitmentTree::<MerkleNode>::empty().root();
+
+        let mut prev: Option<Slab> = None;
+        for i in 1..=3u8 {
+            let (index, prev_hash) = match &prev {
+                Some(prev) => (prev.get_index() + 1, prev.hash()),
+                None => (1, [0u8; 32]),
+            };
+            let slab = Slab::new(index, prev_hash, empty_root, vec![i]);
+            slabstore.put(slab.clone())?;
+            prev = Some(slab);
+        }
+
+        // A hole in the chain, and state left over from the slabs after it
+        let slabs = RocksColumn::<columns::Slabs, MemStore>::new(store.clone());
+        slabs.delete(SlabStore::<MemStore>::key(2))?;
+        let saved_tree = RocksColumn::<columns::Tree, MemStore>::new(store.clone());
+        saved_tree.put(
+            State::<MemStore>::TREE_KEY,
+            (3u64, CommitmentTree::<MerkleNode>::empty()),
+        )?;
+        let nullifiers = RocksColumn::<columns::Nullifiers, MemStore>::new(store.clone());
+        nullifiers.put(Nullifier::new([1u8; 32]), 3u64)?;
+
+        let report = check(store.clone(), &[], true)?;
+        assert_eq!(report.last_index, 1);
+        assert!(report.state_checked);
+        assert_eq!(report.repaired, 3);
+        assert!(matches!(
+            report.problems[..],
+            [
+                Problem::BadTransaction(1),
+                Problem::MissingSlab(2),
+                Problem::TreeMismatch(1),
+                Problem::ExtraNullifier(_),
+            ]
+        ));
+
+        // Only the payload which is not a transaction is left
+        let report = check(store.clone(), &[], false)?;
+        assert_eq!(report.last_index, 1);
+        assert!(matches!(report.problems[..], [Problem::BadTransaction(1)]));
+        assert_eq!(slabstore.get_last_index()?, 1);
+
+        // Which repairing can not fix
+        let report = check(store, &[], true)?;
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!(report.repaired, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_short_slab_key() -> Result<()> {
+        let store = MemStore::new();
+        store.put_cf(columns::Slabs::NAME, vec![1], vec![1])?;
+
+        let report = check(store, &[], false)?;
+        assert_eq!(report.last_index, 0);
+        assert!(matches!(report.problems[..], [Problem::BadSlab(1)]));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::Mutex;
+
+use async_std::sync::Arc;
+
+use crate::Result;
+
+pub enum IteratorMode {
+    Start,
+    End,
+    /// Seek to the given key and iterate forward from there
+    From(Vec<u8>),
+}
+
+pub type KvEntry = (Box<[u8]>, Box<[u8]>);
+pub type KvIterator<'a> = Box<dyn Iterator<Item = KvEntry> + 'a>;
+
+/// A single write queued in a batch.
+pub enum BatchOp {
+    Put {
+        column: &'static str,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        column: &'static str,
+        key: Vec<u8>,
+    },
+}
+
+/// Key-value storage split into named columns. Keys within a column are
+/// kept in byte order, which is the order the iterators return them in.
+pub trait KvStore: Send + Sync + 'static {
+    fn put_cf(&self, column: &'static str, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    fn get_cf(&self, column: &'static str, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    fn delete_cf(&self, column: &'static str, key: Vec<u8>) -> Result<()>;
+
+    fn key_exist_cf(&self, column: &'static str, key: Vec<u8>) -> Result<bool> {
+        Ok(self.get_cf(column, key)?.is_some())
+    }
+
+    /// Apply every operation at once: either all of them land or none do.
+    fn write(&self, batch: Vec<BatchOp>) -> Result<()>;
+
+    fn iterator(&self, column: &'static str, iterator_mode: IteratorMode)
+        -> Result<KvIterator<'_>>;
+}
+
+type MemColumn = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Store that keeps everything in memory, for tests and for embedding
+/// without a database directory.
+#[derive(Default)]
+pub struct MemStore {
+    columns: Mutex<HashMap<&'static str, MemColumn>>,
+}
+
+impl MemStore {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+}
+
+impl KvStore for MemStore {
+    fn put_cf(&self, column: &'static str, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(vec![BatchOp::Put { column, key, value }])
+    }
+
+    fn get_cf(&self, column: &'static str, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let columns = self.columns.lock().expect("Lock memstore");
+        Ok(columns.get(column).and_then(|c| c.get(&key)).cloned())
+    }
+
+    fn delete_cf(&self, column: &'static str, key: Vec<u8>) -> Result<()> {
+        self.write(vec![BatchOp::Delete { column, key }])
+    }
+
+    fn write(&self, batch: Vec<BatchOp>) -> Result<()> {
+        let mut columns = self.columns.lock().expect("Lock memstore");
+        for op in batch {
+            match op {
+                BatchOp::Put { column, key, value } => {
+                    columns.entry(column).or_default().insert(key, value);
+                }
+                BatchOp::Delete { column, key } => {
+                    if let Some(c) = columns.get_mut(column) {
+                        c.remove(&key);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn iterator(
+        &self,
+        column: &'static str,
+        iterator_mode: IteratorMode,
+    ) -> Result<KvIterator<'_>> {
+        let (cursor, reverse) = match iterator_mode {
+            IteratorMode::Start => (Bound::Unbounded, false),
+            IteratorMode::End => (Bound::Unbounded, true),
+            IteratorMode::From(key) => (Bound::Included(key), false),
+        };
+
+        Ok(Box::new(MemIterator {
+            store: self,
+            column,
+            cursor,
+            reverse,
+        }))
+    }
+}
+
+// Walks a column one entry at a time, taking the lock for each step. Only
+// the entries returned are copied, and the store can be written to while
+// it is iterated.
+struct MemIterator<'a> {
+    store: &'a MemStore,
+    column: &'static str,
+    // The next entry is the first one past this bound
+    cursor: Bound<Vec<u8>>,
+    reverse: bool,
+}
+
+impl Iterator for MemIterator<'_> {
+    type Item = KvEntry;
+
+    fn next(&mut self) -> Option<KvEntry> {
+        let columns = self.store.columns.lock().expect("Lock memstore");
+        let column = columns.get(self.column)?;
+
+        let (key, value) = if self.reverse {
+            column
+                .range::<Vec<u8>, _>((Bound::Unbounded, self.cursor.as_ref()))
+                .next_back()?
+        } else {
+            column
+                .range::<Vec<u8>, _>((self.cursor.as_ref(), Bound::Unbounded))
+                .next()?
+        };
+
+        self.cursor = Bound::Excluded(key.clone());
+        Some((key.clone().into(), value.clone().into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memstore_iterator_order() -> Result<()> {
+        let store = MemStore::new();
+        for i in [3u8, 1, 2] {
+            store.put_cf("test", vec![i], vec![i * 10])?;
+        }
+
+        let keys: Vec<u8> = store
+            .iterator("test", IteratorMode::Start)?
+            .map(|(k, _)| k[0])
+            .collect();
+        assert_eq!(keys, vec![1, 2, 3]);
+
+        let keys: Vec<u8> = store
+            .iterator("test", IteratorMode::End)?
+            .map(|(k, _)| k[0])
+            .collect();
+        assert_eq!(keys, vec![3, 2, 1]);
+
+        let keys: Vec<u8> = store
+            .iterator("test", IteratorMode::From(vec![2]))?
+            .map(|(k, _)| k[0])
+            .collect();
+        assert_eq!(keys, vec![2, 3]);
+
+        assert!(store
+            .iterator("other", IteratorMode::Start)?
+            .next()
+            .is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_memstore_write_while_iterating() -> Result<()> {
+        let store = MemStore::new();
+        for i in [1u8, 2, 3] {
+            store.put_cf("test", vec![i], vec![i])?;
+        }
+
+        // Entries are read as the iterator advances, so a removed key is
+        // skipped and the store is not locked in between
+        let mut iter = store.iterator("test", IteratorMode::Start)?;
+        assert_eq!(iter.next().map(|(k, _)| k[0]), Some(1));
+        store.delete_cf("test", vec![2])?;
+        let keys: Vec<u8> = iter.map(|(k, _)| k[0]).collect();
+        assert_eq!(keys, vec![3]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memstore_write_batch() -> Result<()> {
+        let store = MemStore::new();
+        store.put_cf("a", vec![1], vec![1])?;
+
+        store.write(vec![
+            BatchOp::Delete {
+                column: "a",
+                key: vec![1],
+            },
+            BatchOp::Put {
+                column: "b",
+                key: vec![2],
+                value: vec![2],
+            },
+        ])?;
+
+        assert!(!store.key_exist_cf("a", vec![1])?);
+        assert_eq!(store.get_cf("b", vec![2])?, Some(vec![2]));
+        Ok(())
+    }
+}
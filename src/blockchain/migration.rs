@@ -0,0 +1,184 @@
+use async_std::sync::Arc;
+
+use log::warn;
+
+use super::kvstore::{BatchOp, IteratorMode, KvStore};
+use super::rocks::{columns, Column, RocksColumn};
+use crate::serial::serialize;
+use crate::{Error, Result};
+
+/// Version of the database layout written by this code. Bump it whenever
+/// the layout or the transaction encoding of the slabs changes. Slabs of
+/// older versions can not be converted, so `migrate` empties the database
+/// to be synced again.
+pub const SCHEMA_VERSION: u32 = 2;
+
+// Key of the schema version in the metadata column
+const VERSION_KEY: u8 = 0;
+
+// Every column but the metadata
+const DATA_COLUMNS: [&str; 7] = [
+    columns::Slabs::NAME,
+    columns::Nullifiers::NAME,
+    columns::Coins::NAME,
+    columns::MerkleRoots::NAME,
+    columns::Tree::NAME,
+    columns::Undo::NAME,
+    columns::Snapshots::NAME,
+];
+
+/// Read the schema version of the database and reset it if it is older
+/// than `SCHEMA_VERSION`. The reset is committed together with the new
+/// version, so an interrupted one runs again on the next start.
+pub fn migrate<K: KvStore>(store: Arc<K>) -> Result<()> {
+    let metadata = RocksColumn::<columns::Metadata, K>::new(store.clone());
+
+    let version = match metadata.get_value_deserialized::<u32>(VERSION_KEY)? {
+        Some(version) => version,
+        // Databases created before the version marker existed
+        None if has_legacy_data(&store)? => 0,
+        None => {
+            metadata.put(VERSION_KEY, SCHEMA_VERSION)?;
+            return Ok(());
+        }
+    };
+
+    if version > SCHEMA_VERSION {
+        return Err(Error::UnsupportedSchemaVersion(version, SCHEMA_VERSION));
+    }
+
+    if version < SCHEMA_VERSION {
+        reset(&store, version)?;
+    }
+
+    Ok(())
+}
+
+/// Fail unless the database was written with `SCHEMA_VERSION`, for tools
+/// which must not change the database by migrating it.
+pub fn check_version<K: KvStore>(store: &Arc<K>) -> Result<()> {
+    let metadata = RocksColumn::<columns::Metadata, K>::new(store.clone());
+
+    match metadata.get_value_deserialized::<u32>(VERSION_KEY)? {
+        Some(version) if version == SCHEMA_VERSION => Ok(()),
+        Some(version) => Err(Error::UnsupportedSchemaVersion(version, SCHEMA_VERSION)),
+        None if has_legacy_data(store)? => Err(Error::UnsupportedSchemaVersion(0, SCHEMA_VERSION)),
+        None => Ok(()),
+    }
+}
+
+fn has_legacy_data<K: KvStore>(store: &Arc<K>) -> Result<bool> {
+    for column in [
+        columns::Slabs::NAME,
+        columns::Nullifiers::NAME,
+        columns::MerkleRoots::NAME,
+    ] {
+        let mut iter = store.iterator(column, IteratorMode::Start)?;
+        if iter.next().is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Version 0 stored slabs without headers, and the transactions of both
+// older versions are in an encoding this code can not read. The slabs are
+// dropped along with everything the state derived from them. A gateway
+// starts a new chain and clients sync it from the first slab. Wallets
+// synced before hold coins of the old slabs and must be restored.
+fn reset<K: KvStore>(store: &Arc<K>, version: u32) -> Result<()> {
+    warn!(
+        target: "MIGRATION",
+        "Database version {} is older than {}, its slabs can not be read. \
+         Drop them to sync again.",
+        version, SCHEMA_VERSION
+    );
+
+    let mut ops = vec![];
+    for column in DATA_COLUMNS {
+        for (key, _) in store.iterator(column, IteratorMode::Start)? {
+            ops.push(BatchOp::Delete {
+                column,
+                key: key.into_vec(),
+            });
+        }
+    }
+    ops.push(BatchOp::Put {
+        column: columns::Metadata::NAME,
+        key: serialize(&VERSION_KEY),
+        value: serialize(&SCHEMA_VERSION),
+    });
+
+    store.write(ops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{MemStore, Slab, SlabStore};
+    use crate::crypto::{merkle::CommitmentTree, merkle_node::MerkleNode};
+
+    #[test]
+    fn test_migrate_resets_v0() -> Result<()> {
+        let store = MemStore::new();
+        for index in 1..=3u64 {
+            let legacy = (index, vec![index as u8]);
+            store.put_cf(columns::Slabs::NAME, serialize(&index), serialize(&legacy))?;
+        }
+
+        migrate(store.clone())?;
+
+        let metadata = RocksColumn::<columns::Metadata, MemStore>::new(store.clone());
+        let version = metadata.get_value_deserialized::<u32>(VERSION_KEY)?;
+        assert_eq!(version, Some(SCHEMA_VERSION));
+        assert!(!has_legacy_data(&store)?);
+
+        // Running it again leaves the database as it is
+        migrate(store.clone())?;
+        check_version(&store)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_resets_v1() -> Result<()> {
+        let store = MemStore::new();
+        let metadata = RocksColumn::<columns::Metadata, MemStore>::new(store.clone());
+        metadata.put(VERSION_KEY, 1u32)?;
+        assert!(check_version(&store).is_err());
+
+        let slabstore = SlabStore::new(RocksColumn::new(store.clone()))?;
+        let empty_root = CommitmentTree::<MerkleNode>::empty().root();
+        slabstore.put(Slab::new(1, [0u8; 32], empty_root, vec![1]))?;
+        let undo = RocksColumn::<columns::Undo, MemStore>::new(store.clone());
+        undo.put(1u64, vec![1u8])?;
+
+        migrate(store.clone())?;
+
+        assert_eq!(slabstore.get_last_index()?, 0);
+        assert!(undo.get_value_deserialized::<Vec<u8>>(1u64)?.is_none());
+        let version = metadata.get_value_deserialized::<u32>(VERSION_KEY)?;
+        assert_eq!(version, Some(SCHEMA_VERSION));
+        check_version(&store)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_newer_version() -> Result<()> {
+        let store = MemStore::new();
+        let metadata = RocksColumn::<columns::Metadata, MemStore>::new(store.clone());
+        metadata.put(VERSION_KEY, SCHEMA_VERSION + 1)?;
+
+        match migrate(store.clone()) {
+            Err(Error::UnsupportedSchemaVersion(found, supported)) => {
+                assert_eq!(found, SCHEMA_VERSION + 1);
+                assert_eq!(supported, SCHEMA_VERSION);
+            }
+            _ => panic!("Opened a database with a newer schema version"),
+        }
+        assert!(check_version(&store).is_err());
+
+        Ok(())
+    }
+}
@@ -1,7 +1,15 @@
+pub mod dbcheck;
+pub mod kvstore;
+pub mod migration;
 pub mod rocks;
 pub mod slab;
 pub mod slabstore;
+pub mod snapshot;
+pub mod undo;
 
-pub use rocks::{Rocks, RocksColumn};
+pub use kvstore::{KvStore, MemStore};
+pub use rocks::{Rocks, RocksColumn, WriteBatch};
 pub use slab::Slab;
-pub use slabstore::SlabStore;
+pub use slabstore::{SlabIterator, SlabStore};
+pub use snapshot::Snapshot;
+pub use undo::UndoRecord;
@@ -4,14 +4,11 @@ use std::path::Path;
 
 use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, Options, DB};
 
+use super::kvstore::{BatchOp, IteratorMode, KvIterator, KvStore};
+use super::migration;
 use crate::serial::{deserialize, serialize, Decodable, Encodable};
 use crate::{Error, Result};
 
-pub enum IteratorMode {
-    Start,
-    End,
-}
-
 pub trait Column {
     const NAME: &'static str;
 }
@@ -19,7 +16,12 @@ pub trait Column {
 pub mod columns {
     pub struct Slabs;
     pub struct Nullifiers;
+    pub struct Coins;
     pub struct MerkleRoots;
+    pub struct Tree;
+    pub struct Undo;
+    pub struct Snapshots;
+    pub struct Metadata;
 }
 
 impl Column for columns::Slabs {
@@ -30,36 +32,76 @@ impl Column for columns::Nullifiers {
     const NAME: &'static str = "nullifiers";
 }
 
+impl Column for columns::Coins {
+    const NAME: &'static str = "coins";
+}
+
 impl Column for columns::MerkleRoots {
     const NAME: &'static str = "merkleroots";
 }
 
+impl Column for columns::Tree {
+    const NAME: &'static str = "tree";
+}
+
+impl Column for columns::Undo {
+    const NAME: &'static str = "undo";
+}
+
+impl Column for columns::Snapshots {
+    const NAME: &'static str = "snapshots";
+}
+
+impl Column for columns::Metadata {
+    const NAME: &'static str = "metadata";
+}
+
 pub struct Rocks {
     db: DB,
 }
 
 impl Rocks {
     pub fn new(path: &Path) -> Result<Arc<Self>> {
+        let rocks = Self::open_with(path, true)?;
+
+        // an older database is emptied before anything reads it
+        migration::migrate(rocks.clone())?;
+
+        Ok(rocks)
+    }
+
+    /// Open an existing database as it is, without creating anything or
+    /// migrating it. Fails if it was written with another schema version.
+    pub fn open(path: &Path) -> Result<Arc<Self>> {
+        let rocks = Self::open_with(path, false)?;
+        migration::check_version(&rocks)?;
+        Ok(rocks)
+    }
+
+    fn open_with(path: &Path, create: bool) -> Result<Arc<Self>> {
         // column family options
         let cf_opts = Options::default();
 
-        // default column family
-        let default_cf =
-            ColumnFamilyDescriptor::new(rocksdb::DEFAULT_COLUMN_FAMILY_NAME, cf_opts.clone());
-        // slabs column family
-        let slab_cf = ColumnFamilyDescriptor::new(columns::Slabs::NAME, cf_opts.clone());
-        // nullifiers column family
-        let nullifiers_cf = ColumnFamilyDescriptor::new(columns::Nullifiers::NAME, cf_opts.clone());
-        // merkleroots column family
-        let merkleroots_cf = ColumnFamilyDescriptor::new(columns::MerkleRoots::NAME, cf_opts);
-
         // column families
-        let cfs = vec![default_cf, slab_cf, nullifiers_cf, merkleroots_cf];
+        let cfs = [
+            rocksdb::DEFAULT_COLUMN_FAMILY_NAME,
+            columns::Slabs::NAME,
+            columns::Nullifiers::NAME,
+            columns::Coins::NAME,
+            columns::MerkleRoots::NAME,
+            columns::Tree::NAME,
+            columns::Undo::NAME,
+            columns::Snapshots::NAME,
+            columns::Metadata::NAME,
+        ]
+        .iter()
+        .map(|name| ColumnFamilyDescriptor::new(*name, cf_opts.clone()))
+        .collect::<Vec<_>>();
 
         // database options
         let mut opt = Options::default();
-        opt.create_if_missing(true);
-        opt.create_missing_column_families(true);
+        opt.create_if_missing(create);
+        opt.create_missing_column_families(create);
 
         // open database with following options and cf
         let db = DB::open_cf_descriptors(&opt, path, cfs)?;
@@ -71,72 +113,158 @@ impl Rocks {
     where
         C: Column,
     {
+        self.cf_handle_by_name(C::NAME)
+    }
+
+    fn cf_handle_by_name(&self, name: &str) -> Result<&ColumnFamily> {
         self.db
-            .cf_handle(C::NAME)
+            .cf_handle(name)
             .ok_or_else(|| Error::RocksdbError("unknown column".to_string()))
     }
 
-    pub fn put_cf(&self, cf: &ColumnFamily, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+    pub fn destroy(path: &Path) -> Result<()> {
+        DB::destroy(&Options::default(), path)?;
+        Ok(())
+    }
+}
+
+impl KvStore for Rocks {
+    fn put_cf(&self, column: &'static str, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let cf = self.cf_handle_by_name(column)?;
         self.db.put_cf(cf, key, value)?;
         Ok(())
     }
 
-    pub fn get_cf(&self, cf: &ColumnFamily, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+    fn get_cf(&self, column: &'static str, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let cf = self.cf_handle_by_name(column)?;
         let val = self.db.get_cf(cf, key)?;
         Ok(val)
     }
 
-    pub fn key_exist_cf(&self, cf: &ColumnFamily, key: Vec<u8>) -> Result<bool> {
-        let val = self.db.get_cf(cf, key)?;
-        Ok(val.is_some())
+    fn delete_cf(&self, column: &'static str, key: Vec<u8>) -> Result<()> {
+        let cf = self.cf_handle_by_name(column)?;
+        self.db.delete_cf(cf, key)?;
+        Ok(())
     }
 
-    pub fn iterator(&self, cf: &ColumnFamily, iterator_mode: IteratorMode) -> rocksdb::DBIterator {
-        let iterator_mode = match iterator_mode {
-            IteratorMode::Start => rocksdb::IteratorMode::Start,
-            IteratorMode::End => rocksdb::IteratorMode::End,
+    fn write(&self, batch: Vec<BatchOp>) -> Result<()> {
+        let mut write_batch = rocksdb::WriteBatch::default();
+        for op in batch {
+            match op {
+                BatchOp::Put { column, key, value } => {
+                    write_batch.put_cf(self.cf_handle_by_name(column)?, key, value)
+                }
+                BatchOp::Delete { column, key } => {
+                    write_batch.delete_cf(self.cf_handle_by_name(column)?, key)
+                }
+            }
+        }
+        self.db.write(write_batch)?;
+        Ok(())
+    }
+
+    fn iterator(
+        &self,
+        column: &'static str,
+        iterator_mode: IteratorMode,
+    ) -> Result<KvIterator<'_>> {
+        let cf = self.cf_handle_by_name(column)?;
+        let iter = match iterator_mode {
+            IteratorMode::Start => self.db.iterator_cf(cf, rocksdb::IteratorMode::Start),
+            IteratorMode::End => self.db.iterator_cf(cf, rocksdb::IteratorMode::End),
+            IteratorMode::From(key) => self.db.iterator_cf(
+                cf,
+                rocksdb::IteratorMode::From(&key, rocksdb::Direction::Forward),
+            ),
         };
-        self.db.iterator_cf(cf, iterator_mode)
+        Ok(Box::new(iter))
     }
+}
 
-    pub fn destroy(path: &Path) -> Result<()> {
-        DB::destroy(&Options::default(), path)?;
+/// A set of writes across any number of columns which are committed
+/// to the database atomically: either all of them land or none do.
+pub struct WriteBatch<K: KvStore = Rocks> {
+    store: Arc<K>,
+    ops: Vec<BatchOp>,
+}
+
+impl<K: KvStore> WriteBatch<K> {
+    pub fn new(store: Arc<K>) -> Self {
+        Self { store, ops: vec![] }
+    }
+
+    pub fn put<C: Column>(&mut self, key: impl Encodable, value: impl Encodable) -> Result<()> {
+        self.ops.push(BatchOp::Put {
+            column: C::NAME,
+            key: serialize(&key),
+            value: serialize(&value),
+        });
         Ok(())
     }
+
+    pub fn delete<C: Column>(&mut self, key: impl Encodable) -> Result<()> {
+        self.ops.push(BatchOp::Delete {
+            column: C::NAME,
+            key: serialize(&key),
+        });
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Write every queued operation to the database in a single commit.
+    pub fn commit(self) -> Result<()> {
+        self.store.write(self.ops)
+    }
 }
 
-pub struct RocksColumn<T: Column> {
-    rocks: Arc<Rocks>,
+pub struct RocksColumn<T: Column, K: KvStore = Rocks> {
+    store: Arc<K>,
     column: PhantomData<T>,
 }
 
-impl<T: Column> RocksColumn<T> {
-    pub fn new(rocks: Arc<Rocks>) -> RocksColumn<T> {
+impl<T: Column, K: KvStore> RocksColumn<T, K> {
+    pub fn new(store: Arc<K>) -> RocksColumn<T, K> {
         RocksColumn {
-            rocks,
+            store,
             column: PhantomData,
         }
     }
-    fn cf_handle(&self) -> Result<&ColumnFamily> {
-        self.rocks.cf_handle::<T>()
+
+    /// Start a new batch on the database this column belongs to.
+    pub fn batch(&self) -> WriteBatch<K> {
+        WriteBatch::new(self.store.clone())
+    }
+
+    pub fn put_batch(
+        &self,
+        batch: &mut WriteBatch<K>,
+        key: impl Encodable,
+        value: impl Encodable,
+    ) -> Result<()> {
+        batch.put::<T>(key, value)
     }
 
     pub fn put(&self, key: impl Encodable, value: impl Encodable) -> Result<()> {
         let key = serialize(&key);
         let value = serialize(&value);
-        let cf = self.cf_handle()?;
-        self.rocks.put_cf(cf, key, value)?;
+        self.store.put_cf(T::NAME, key, value)?;
         Ok(())
     }
 
     pub fn get(&self, key: impl Encodable) -> Result<Option<Vec<u8>>> {
         let key = serialize(&key);
-        let cf = self.cf_handle()?;
-        let val = self.rocks.get_cf(cf, key)?;
+        let val = self.store.get_cf(T::NAME, key)?;
         Ok(val)
     }
 
-    pub fn get_value_deserialized<D: Decodable>(&self, key: Vec<u8>) -> Result<Option<D>> {
+    pub fn get_value_deserialized<D: Decodable>(&self, key: impl Encodable) -> Result<Option<D>> {
         let value = self.get(key)?;
         match value {
             Some(v) => {
@@ -147,16 +275,23 @@ impl<T: Column> RocksColumn<T> {
         }
     }
 
+    pub fn delete(&self, key: impl Encodable) -> Result<()> {
+        let key = serialize(&key);
+        self.store.delete_cf(T::NAME, key)?;
+        Ok(())
+    }
+
+    pub fn delete_batch(&self, batch: &mut WriteBatch<K>, key: impl Encodable) -> Result<()> {
+        batch.delete::<T>(key)
+    }
+
     pub fn key_exist(&self, key: impl Encodable) -> Result<bool> {
         let key = serialize(&key);
-        let cf = self.cf_handle()?;
-        let val = self.rocks.key_exist_cf(cf, key)?;
+        let val = self.store.key_exist_cf(T::NAME, key)?;
         Ok(val)
     }
 
-    pub fn iterator(&self, iterator_mode: IteratorMode) -> Result<rocksdb::DBIterator> {
-        let cf = self.cf_handle()?;
-        let iter = self.rocks.iterator(cf, iterator_mode);
-        Ok(iter)
+    pub fn iterator(&self, iterator_mode: IteratorMode) -> Result<KvIterator<'_>> {
+        self.store.iterator(T::NAME, iterator_mode)
     }
 }
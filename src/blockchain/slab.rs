@@ -1,35 +1,132 @@
-use crate::serial::{Decodable, Encodable};
+use std::io;
+
+use blake2b_simd::Params as Blake2bParams;
+
+use crate::crypto::merkle_node::MerkleNode;
+use crate::impl_vec;
+use crate::serial::{serialize, Decodable, Encodable, VarInt};
 use crate::Result;
 
+pub const SLAB_HASH_PERSONALIZATION: &[u8; 16] = b"DarkFi_SlabHash_";
+pub const PAYLOAD_HASH_PERSONALIZATION: &[u8; 16] = b"DarkFi_SlabData_";
+
+fn blake2b_256(persona: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(
+        Blake2bParams::new()
+            .hash_length(32)
+            .personal(persona)
+            .hash(data)
+            .as_bytes(),
+    );
+    hash
+}
+
+/// Links a slab to the one before it and commits to its contents.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SlabHeader {
+    /// Hash of the previous slab, all zeros for the first one
+    pub prev_hash: [u8; 32],
+    /// Hash of the slab's payload
+    pub payload_hash: [u8; 32],
+    /// Root of the commitment tree once this slab's coins are added
+    pub merkle_root: MerkleNode,
+}
+
 #[derive(Clone, Debug)]
 pub struct Slab {
     index: u64,
+    header: SlabHeader,
     payload: Vec<u8>,
 }
 
 impl Slab {
-    pub fn new(payload: Vec<u8>) -> Self {
-        let index = 0;
-        Slab { index, payload }
-    }
-
-    pub fn set_index(&mut self, index: u64) {
-        self.index = index;
+    pub fn new(index: u64, prev_hash: [u8; 32], merkle_root: MerkleNode, payload: Vec<u8>) -> Self {
+        let header = SlabHeader {
+            prev_hash,
+            payload_hash: blake2b_256(PAYLOAD_HASH_PERSONALIZATION, &payload),
+            merkle_root,
+        };
+        Slab {
+            index,
+            header,
+            payload,
+        }
     }
 
     pub fn get_index(&self) -> u64 {
         self.index
     }
 
+    pub fn get_header(&self) -> &SlabHeader {
+        &self.header
+    }
+
     pub fn get_payload(&self) -> Vec<u8> {
         self.payload.clone()
     }
+
+    /// Hash of the slab index and header. The header commits to the payload,
+    /// so this identifies the whole slab and everything before it.
+    pub fn hash(&self) -> [u8; 32] {
+        let data = serialize(&(self.index, self.header.clone()));
+        blake2b_256(SLAB_HASH_PERSONALIZATION, &data)
+    }
+
+    /// Check the payload matches the hash in the header.
+    pub fn verify_payload(&self) -> bool {
+        blake2b_256(PAYLOAD_HASH_PERSONALIZATION, &self.payload) == self.header.payload_hash
+    }
+
+    /// Copy of this slab without its payload. The header still commits to
+    /// the payload, so the copy keeps the same hash.
+    pub fn pruned(&self) -> Slab {
+        Slab {
+            index: self.index,
+            header: self.header.clone(),
+            payload: vec![],
+        }
+    }
+
+    pub fn is_pruned(&self) -> bool {
+        self.payload.is_empty()
+    }
+
+    /// Check this slab directly follows `prev`, or is the first slab if
+    /// `prev` is `None`.
+    pub fn extends(&self, prev: Option<&Slab>) -> bool {
+        match prev {
+            Some(prev) => self.index == prev.index + 1 && self.header.prev_hash == prev.hash(),
+            None => self.index == 1 && self.header.prev_hash == [0u8; 32],
+        }
+    }
+}
+
+impl Encodable for SlabHeader {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.prev_hash.encode(&mut s)?;
+        len += self.payload_hash.encode(&mut s)?;
+        len += self.merkle_root.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for SlabHeader {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            prev_hash: Decodable::decode(&mut d)?,
+            payload_hash: Decodable::decode(&mut d)?,
+            merkle_root: Decodable::decode(&mut d)?,
+        })
+    }
 }
 
 impl Encodable for Slab {
     fn encode<S: std::io::Write>(&self, mut s: S) -> Result<usize> {
         let mut len = 0;
         len += self.index.encode(&mut s)?;
+        len += self.header.encode(&mut s)?;
         len += self.payload.encode(&mut s)?;
         Ok(len)
     }
@@ -39,7 +136,10 @@ impl Decodable for Slab {
     fn decode<D: std::io::Read>(mut d: D) -> Result<Self> {
         Ok(Self {
             index: Decodable::decode(&mut d)?,
+            header: Decodable::decode(&mut d)?,
             payload: Decodable::decode(&mut d)?,
         })
     }
 }
+
+impl_vec!(Slab);
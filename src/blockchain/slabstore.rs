@@ -2,59 +2,252 @@ use std::sync::Arc;
 
 use log::debug;
 
-use super::rocks::{columns, IteratorMode, RocksColumn};
+use super::kvstore::{IteratorMode, KvIterator, KvStore};
+use super::rocks::{columns, Rocks, RocksColumn, WriteBatch};
 use super::slab::Slab;
 use crate::serial::{deserialize, serialize};
-use crate::Result;
+use crate::{Error, Result};
 
-pub struct SlabStore {
-    rocks: RocksColumn<columns::Slabs>,
+pub struct SlabStore<K: KvStore = Rocks> {
+    rocks: RocksColumn<columns::Slabs, K>,
 }
 
-impl SlabStore {
-    pub fn new(rocks: RocksColumn<columns::Slabs>) -> Result<Arc<Self>> {
+impl<K: KvStore> SlabStore<K> {
+    pub fn new(rocks: RocksColumn<columns::Slabs, K>) -> Result<Arc<Self>> {
         Ok(Arc::new(SlabStore { rocks }))
     }
 
+    // Indexes are stored big-endian so that the byte order RocksDB sorts keys
+    // by is also the numeric order of the slabs.
+    pub(super) fn key(index: u64) -> [u8; 8] {
+        index.to_be_bytes()
+    }
+
+    // None if the key was not written by `key`
+    pub(super) fn index_from_key(key: &[u8]) -> Option<u64> {
+        let bytes: [u8; 8] = key.try_into().ok()?;
+        Some(u64::from_be_bytes(bytes))
+    }
+
     pub fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         debug!(target: "SLABSTORE", "get value");
         let key: u64 = deserialize(&key)?;
-        let value = self.rocks.get(key)?;
+        let value = self.rocks.get(Self::key(key))?;
         Ok(value)
     }
 
+    /// Store the slab if it extends the last stored one: its index follows
+    /// on, its header links to the last slab's hash and its payload matches.
     pub fn put(&self, slab: Slab) -> Result<Option<u64>> {
         debug!(target: "SLABSTORE", "Put slab");
+        if !self.is_next(&slab)? {
+            return Ok(None);
+        }
+
+        let key = slab.get_index();
+        self.rocks.put(Self::key(key), slab)?;
+        Ok(Some(key))
+    }
+
+    /// Queue the slab into the given batch instead of writing it straight away,
+    /// so it is only stored together with the rest of the state it produced.
+    pub fn put_batch(&self, batch: &mut WriteBatch<K>, slab: Slab) -> Result<Option<u64>> {
+        debug!(target: "SLABSTORE", "Put slab to batch");
+        if !self.is_next(&slab)? {
+            return Ok(None);
+        }
+
+        let key = slab.get_index();
+        self.rocks.put_batch(batch, Self::key(key), slab)?;
+        Ok(Some(key))
+    }
+
+    /// Store a slab whose predecessors are not kept, such as the last slab
+    /// of a snapshot. Only allowed while the store is empty.
+    pub fn put_base_batch(&self, batch: &mut WriteBatch<K>, slab: Slab) -> Result<Option<u64>> {
+        debug!(target: "SLABSTORE", "Put base slab to batch");
+        if self.get_last_index()? != 0 {
+            return Ok(None);
+        }
+
+        let key = slab.get_index();
+        self.rocks.put_batch(batch, Self::key(key), slab)?;
+        Ok(Some(key))
+    }
+
+    fn is_next(&self, slab: &Slab) -> Result<bool> {
+        let last_slab = self.get_last_slab()?;
+        Ok(slab.verify_payload() && slab.extends(last_slab.as_ref()))
+    }
+
+    /// Remove every slab after `index`, so that `index` becomes the last one.
+    /// Returns the indexes of the removed slabs, newest first.
+    pub fn truncate_to(&self, index: u64) -> Result<Vec<u64>> {
+        let mut batch = self.batch();
+        let removed = self.truncate_to_batch(&mut batch, index)?;
+        batch.commit()?;
+        Ok(removed)
+    }
+
+    pub fn truncate_to_batch(&self, batch: &mut WriteBatch<K>, index: u64) -> Result<Vec<u64>> {
+        debug!(target: "SLABSTORE", "Truncate to {}", index);
         let last_index = self.get_last_index()?;
-        let key = last_index + 1;
+        let mut removed = vec![];
+
+        for i in ((index + 1)..(last_index + 1)).rev() {
+            self.rocks.delete_batch(batch, Self::key(i))?;
+            removed.push(i);
+        }
+
+        Ok(removed)
+    }
 
-        if slab.get_index() == key {
-            self.rocks.put(key, slab)?;
-            Ok(Some(key))
-        } else {
-            Ok(None)
+    /// Drop the payloads of the slabs up to and including `index`, keeping
+    /// their headers so the chain can still be followed. Returns how many
+    /// slabs were pruned.
+    pub fn prune_to_batch(&self, batch: &mut WriteBatch<K>, index: u64) -> Result<u64> {
+        debug!(target: "SLABSTORE", "Prune to {}", index);
+        let mut pruned = 0;
+
+        for slab in self.iter_from(1)? {
+            let slab = slab?;
+            if slab.get_index() > index {
+                break;
+            }
+            if slab.is_pruned() {
+                continue;
+            }
+            self.rocks
+                .put_batch(batch, Self::key(slab.get_index()), slab.pruned())?;
+            pruned += 1;
         }
+
+        Ok(pruned)
+    }
+
+    pub fn batch(&self) -> WriteBatch<K> {
+        self.rocks.batch()
     }
 
     pub fn get_value_deserialized(&self, key: Vec<u8>) -> Result<Option<Slab>> {
-        self.rocks.get_value_deserialized::<Slab>(key)
+        let key: u64 = deserialize(&key)?;
+        self.rocks.get_value_deserialized::<Slab>(Self::key(key))
+    }
+
+    /// Return the stored slabs with indexes in `start..=end`, stopping early
+    /// at the first missing index.
+    pub fn get_range(&self, start: u64, end: u64) -> Result<Vec<Slab>> {
+        debug!(target: "SLABSTORE", "Get range {} to {}", start, end);
+        let mut slabs = vec![];
+
+        if start > end {
+            return Ok(slabs);
+        }
+
+        for slab in self.iter_from(start)? {
+            let slab = slab?;
+            if slab.get_index() != start + slabs.len() as u64 {
+                break;
+            }
+            slabs.push(slab);
+            if slabs.len() as u64 > end - start {
+                break;
+            }
+        }
+
+        Ok(slabs)
+    }
+
+    /// Lazily iterate over the stored slabs, starting from `index`.
+    pub fn iter_from(&self, index: u64) -> Result<SlabIterator<'_>> {
+        let iter = self
+            .rocks
+            .iterator(IteratorMode::From(Self::key(index).to_vec()))?;
+        Ok(SlabIterator { iter })
+    }
+
+    pub fn get_last_slab(&self) -> Result<Option<Slab>> {
+        debug!(target: "SLABSTORE", "Get last slab");
+        let last_slab = self.rocks.iterator(IteratorMode::End)?.next();
+        match last_slab {
+            Some((_, slab)) => Ok(Some(deserialize(&slab)?)),
+            None => Ok(None),
+        }
     }
 
     pub fn get_last_index(&self) -> Result<u64> {
         debug!(target: "SLABSTORE", "Get last index");
         let last_index = self.rocks.iterator(IteratorMode::End)?.next();
         match last_index {
-            Some((index, _)) => Ok(deserialize(&index)?),
+            Some((key, _)) => Self::index_from_key(&key)
+                .ok_or_else(|| Error::SlabsStore(format!("Bad slab key {}", hex::encode(key)))),
             None => Ok(0),
         }
     }
 
     pub fn get_last_index_as_bytes(&self) -> Result<Vec<u8>> {
         debug!(target: "SLABSTORE", "Get last index as bytes");
-        let last_index = self.rocks.iterator(IteratorMode::End)?.next();
-        match last_index {
-            Some((index, _)) => Ok(index.to_vec()),
-            None => Ok(serialize::<u64>(&0)),
-        }
+        let last_index = self.get_last_index()?;
+        Ok(serialize(&last_index))
+    }
+}
+
+pub struct SlabIterator<'a> {
+    iter: KvIterator<'a>,
+}
+
+impl Iterator for SlabIterator<'_> {
+    type Item = Result<Slab>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|(_, value)| deserialize(&value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::MemStore;
+    use crate::crypto::merkle_node::MerkleNode;
+
+    fn next_slab(prev: Option<&Slab>, payload: Vec<u8>) -> Slab {
+        let (index, prev_hash) = match prev {
+            Some(prev) => (prev.get_index() + 1, prev.hash()),
+            None => (1, [0u8; 32]),
+        };
+        Slab::new(index, prev_hash, MerkleNode::new([0u8; 32]), payload)
+    }
+
+    #[test]
+    fn test_slabstore_chain() -> Result<()> {
+        let slabstore = SlabStore::new(RocksColumn::new(MemStore::new()))?;
+
+        let first = next_slab(None, vec![1]);
+        let second = next_slab(Some(&first), vec![2]);
+        let third = next_slab(Some(&second), vec![3]);
+
+        // Slabs have to be added in order
+        assert_eq!(slabstore.put(second.clone())?, None);
+        assert_eq!(slabstore.put(first.clone())?, Some(1));
+        assert_eq!(slabstore.put(second.clone())?, Some(2));
+        assert_eq!(slabstore.put(third)?, Some(3));
+
+        // A slab which does not link to the last one is rejected
+        let fork = next_slab(Some(&second), vec![4]);
+        assert_eq!(slabstore.put(fork)?, None);
+
+        assert_eq!(slabstore.get_last_index()?, 3);
+        let payloads: Vec<Vec<u8>> = slabstore
+            .get_range(2, 10)?
+            .iter()
+            .map(|s| s.get_payload())
+            .collect();
+        assert_eq!(payloads, vec![vec![2], vec![3]]);
+
+        assert_eq!(slabstore.truncate_to(1)?, vec![3, 2]);
+        assert_eq!(slabstore.get_last_index()?, 1);
+        assert_eq!(slabstore.put(second)?, Some(2));
+
+        Ok(())
     }
 }
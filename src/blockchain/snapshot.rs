@@ -0,0 +1,263 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+use log::debug;
+
+use super::kvstore::KvStore;
+use super::slab::Slab;
+use super::slabstore::SlabStore;
+use crate::crypto::{merkle::CommitmentTree, merkle_node::MerkleNode, nullifier::Nullifier};
+use crate::serial::{Decodable, Encodable};
+use crate::state::{verified_state_transition, StateQuery, StateUpdate};
+use crate::tx::Transaction;
+use crate::{Error, Result};
+
+/// The state after applying every slab up to some index, so clients can
+/// start from there instead of replaying the whole chain.
+pub struct Snapshot {
+    /// The last slab the snapshot covers, without its payload
+    pub slab: Slab,
+    /// Frontier of the commitment tree after that slab
+    pub tree: CommitmentTree<MerkleNode>,
+    /// Every nullifier revealed so far, with the slab that revealed it
+    pub nullifiers: Vec<(Nullifier, u64)>,
+    /// Every merkle root the tree has had so far
+    pub merkle_roots: Vec<MerkleNode>,
+}
+
+impl Snapshot {
+    // Key of the single latest snapshot in the snapshots column
+    pub const KEY: u8 = 0;
+
+    pub fn get_index(&self) -> u64 {
+        self.slab.get_index()
+    }
+
+    // The state before the first slab
+    pub(super) fn empty() -> Self {
+        let tree = CommitmentTree::empty();
+        Self {
+            slab: Slab::new(0, [0u8; 32], tree.root(), vec![]),
+            tree,
+            nullifiers: vec![],
+            merkle_roots: vec![],
+        }
+    }
+
+    /// Build a snapshot at `index` by replaying the stored slabs on top of
+    /// `base`, or from the first slab if there is no base. Gateways take
+    /// their snapshots from their verified state with `State::snapshot`;
+    /// this is for checking a database on its own.
+    ///
+    /// Slabs are checked against the state before they are replayed, the
+    /// same as clients check them, and left out when the check fails or
+    /// their coins do not lead to the merkle root in the header. Deposits
+    /// are only valid when signed by one of `cashier_public_keys`. Proofs
+    /// and signatures are not checked here.
+    pub fn build<K: KvStore>(
+        slabstore: &SlabStore<K>,
+        base: Option<Snapshot>,
+        index: u64,
+        cashier_public_keys: &[jubjub::SubgroupPoint],
+    ) -> Result<Self> {
+        let (start, mut replay) = match base {
+            Some(base) if base.get_index() >= index => {
+                return Err(Error::SlabsStore(format!(
+                    "There is already a snapshot at slab {}",
+                    base.get_index()
+                )))
+            }
+            Some(base) => (base.get_index() + 1, Replay::new(base, cashier_public_keys)),
+            None => (1, Replay::new(Self::empty(), cashier_public_keys)),
+        };
+
+        let mut last_slab: Option<Slab> = None;
+
+        for (expected, slab) in (start..).zip(slabstore.iter_from(start)?) {
+            let slab = slab?;
+            if slab.get_index() > index {
+                break;
+            }
+
+            if slab.get_index() != expected || slab.is_pruned() {
+                return Err(Error::SlabsStore(format!(
+                    "Slab {} is missing or pruned",
+                    expected
+                )));
+            }
+
+            match Self::check(&replay, &slab) {
+                Ok(update) => replay.apply(update, slab.get_index()),
+                Err(e) => debug!(target: "SNAPSHOT", "Skip slab {}: {}", slab.get_index(), e),
+            }
+            replay.height = slab.get_index();
+
+            last_slab = Some(slab);
+        }
+
+        match last_slab {
+            Some(slab) if slab.get_index() == index => Ok(Self {
+                slab: slab.pruned(),
+                tree: replay.tree.clone(),
+                nullifiers: std::mem::take(&mut replay.nullifiers),
+                merkle_roots: std::mem::take(&mut replay.merkle_roots),
+            }),
+            _ => Err(Error::SlabsStore(format!("Slab {} not found", index))),
+        }
+    }
+
+    // The checks a client makes before applying the slab, short of the
+    // proofs and signatures
+    fn check(replay: &Replay, slab: &Slab) -> Result<StateUpdate> {
+        let tx = Transaction::decode(&slab.get_payload()[..])?;
+        let update = verified_state_transition(replay, tx)?;
+
+        let mut tree = replay.tree.clone();
+        for coin in update.coins.iter() {
+            tree.append(MerkleNode::from_coin(coin))?;
+        }
+        if tree.root() != slab.get_header().merkle_root {
+            return Err(Error::SlabsStore("Merkle root mismatch".into()));
+        }
+
+        Ok(update)
+    }
+
+    /// Check the tree matches the merkle root committed to in the header.
+    pub fn verify(&self) -> bool {
+        self.tree.root() == self.slab.get_header().merkle_root
+    }
+}
+
+// The state while slabs are replayed into a snapshot
+struct Replay {
+    tree: CommitmentTree<MerkleNode>,
+    nullifiers: Vec<(Nullifier, u64)>,
+    merkle_roots: Vec<MerkleNode>,
+    // For lookups while the slabs are checked
+    revealed: HashMap<[u8; 32], u64>,
+    roots: HashSet<[u8; 32]>,
+    height: u64,
+    cashier_public_keys: Vec<jubjub::SubgroupPoint>,
+}
+
+impl Replay {
+    fn new(base: Snapshot, cashier_public_keys: &[jubjub::SubgroupPoint]) -> Self {
+        Self {
+            revealed: base
+                .nullifiers
+                .iter()
+                .map(|(nullifier, index)| (nullifier.repr, *index))
+                .collect(),
+            roots: base.merkle_roots.iter().map(|root| root.repr).collect(),
+            height: base.get_index(),
+            cashier_public_keys: cashier_public_keys.to_vec(),
+            tree: base.tree,
+            nullifiers: base.nullifiers,
+            merkle_roots: base.merkle_roots,
+        }
+    }
+
+    fn apply(&mut self, update: StateUpdate, index: u64) {
+        for coin in update.coins.iter() {
+            // The coins were appended to a copy of the tree in `check`
+            self.tree
+                .append(MerkleNode::from_coin(coin))
+                .expect("Append to merkle tree");
+            self.roots.insert(self.tree.root().repr);
+            self.merkle_roots.push(self.tree.root());
+        }
+        for nullifier in update.nullifiers {
+            self.revealed.insert(nullifier.repr, index);
+            self.nullifiers.push((nullifier, index));
+        }
+    }
+}
+
+impl StateQuery for Replay {
+    fn is_valid_cashier_public_key(&self, public: &jubjub::SubgroupPoint) -> bool {
+        self.cashier_public_keys.contains(public)
+    }
+
+    fn is_valid_merkle(&self, merkle: &MerkleNode) -> bool {
+        self.roots.contains(&merkle.repr)
+    }
+
+    fn nullifier_exists(&self, nullifier: &Nullifier) -> bool {
+        self.revealed.contains_key(&nullifier.repr)
+    }
+
+    fn nullifier_slab(&self, nullifier: &Nullifier) -> Option<u64> {
+        self.revealed.get(&nullifier.repr).copied()
+    }
+
+    fn height(&self) -> Result<u64> {
+        Ok(self.height)
+    }
+}
+
+impl Encodable for Snapshot {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.slab.encode(&mut s)?;
+        len += self.tree.encode(&mut s)?;
+        len += self.nullifiers.encode(&mut s)?;
+        len += self.merkle_roots.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for Snapshot {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            slab: Decodable::decode(&mut d)?,
+            tree: Decodable::decode(&mut d)?,
+            nullifiers: Decodable::decode(&mut d)?,
+            merkle_roots: Decodable::decode(&mut d)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{MemStore, RocksColumn};
+
+    #[test]
+    fn test_snapshot_and_prune() -> Result<()> {
+        let slabstore = SlabStore::new(RocksColumn::new(MemStore::new()))?;
+        let empty_root = CommitmentTree::<MerkleNode>::empty().root();
+
+        // Payloads which are not transactions are skipped, like clients do
+        let mut prev: Option<Slab> = None;
+        for i in 1..=3u8 {
+            let (index, prev_hash) = match &prev {
+                Some(prev) => (prev.get_index() + 1, prev.hash()),
+                None => (1, [0u8; 32]),
+            };
+            let slab = Slab::new(index, prev_hash, empty_root, vec![i]);
+            slabstore.put(slab.clone())?;
+            prev = Some(slab);
+        }
+
+        let snapshot = Snapshot::build(&slabstore, None, 2, &[])?;
+        assert_eq!(snapshot.get_index(), 2);
+        assert!(snapshot.verify());
+        assert!(snapshot.slab.is_pruned());
+
+        let mut batch = slabstore.batch();
+        assert_eq!(slabstore.prune_to_batch(&mut batch, 2)?, 2);
+        batch.commit()?;
+
+        // Pruned slabs keep their hash, so new slabs still link to them
+        let second = slabstore.get_range(2, 2)?.remove(0);
+        assert!(second.is_pruned());
+        assert_eq!(second.hash(), snapshot.slab.hash());
+
+        assert!(Snapshot::build(&slabstore, None, 3, &[]).is_err());
+        let snapshot = Snapshot::build(&slabstore, Some(snapshot), 3, &[])?;
+        assert_eq!(snapshot.get_index(), 3);
+
+        Ok(())
+    }
+}
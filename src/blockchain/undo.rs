@@ -0,0 +1,42 @@
+use std::io;
+
+use crate::crypto::{
+    coin::Coin, merkle::CommitmentTree, merkle_node::MerkleNode, nullifier::Nullifier,
+};
+use crate::serial::{Decodable, Encodable};
+use crate::Result;
+
+/// Everything needed to revert the state changes made by applying one slab.
+#[derive(Clone)]
+pub struct UndoRecord {
+    /// Nullifiers revealed by the slab
+    pub nullifiers: Vec<Nullifier>,
+    /// Coins minted by the slab
+    pub coins: Vec<Coin>,
+    /// Merkle roots added while appending the slab's coins
+    pub merkle_roots: Vec<MerkleNode>,
+    /// The commitment tree as it was before the slab was applied
+    pub tree: CommitmentTree<MerkleNode>,
+}
+
+impl Encodable for UndoRecord {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.nullifiers.encode(&mut s)?;
+        len += self.coins.encode(&mut s)?;
+        len += self.merkle_roots.encode(&mut s)?;
+        len += self.tree.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for UndoRecord {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            nullifiers: Decodable::decode(&mut d)?,
+            coins: Decodable::decode(&mut d)?,
+            merkle_roots: Decodable::decode(&mut d)?,
+            tree: Decodable::decode(&mut d)?,
+        })
+    }
+}
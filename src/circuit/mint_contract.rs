@@ -18,7 +18,6 @@ pub struct MintContract {
     pub value: Option<u64>,
     pub token_id: Option<jubjub::Fr>,
     pub randomness_value: Option<jubjub::Fr>,
-    pub randomness_token: Option<jubjub::Fr>,
     pub serial: Option<jubjub::Fr>,
     pub randomness_coin: Option<jubjub::Fr>,
     pub public: Option<jubjub::SubgroupPoint>,
@@ -46,12 +45,6 @@ impl Circuit<bls12_381::Scalar> for MintContract {
             self.randomness_value,
         )?;
 
-        // Line 23: fr_as_binary_le randomness_token param:randomness_token
-        let randomness_token = boolean::field_into_boolean_vec_le(
-            cs.namespace(|| "Line 23: fr_as_binary_le randomness_token param:randomness_token"),
-            self.randomness_token,
-        )?;
-
         // Line 24: fr_as_binary_le serial param:serial
         let serial = boolean::field_into_boolean_vec_le(
             cs.namespace(|| "Line 24: fr_as_binary_le serial param:serial"),
@@ -73,10 +66,16 @@ impl Circuit<bls12_381::Scalar> for MintContract {
         // Line 28: assert_not_small_order public
         public.assert_not_small_order(cs.namespace(|| "Line 28: assert_not_small_order public"))?;
 
-        // Line 33: ec_mul_const vcv value G_VCV
-        let vcv = ecc::fixed_base_multiplication(
-            cs.namespace(|| "Line 33: ec_mul_const vcv value G_VCV"),
-            &zcash_proofs::constants::VALUE_COMMITMENT_VALUE_GENERATOR,
+        // Line 33: ec_mul_const value_base token_id VALUE_BASE
+        let value_base = ecc::fixed_base_multiplication(
+            cs.namespace(|| "Line 33: ec_mul_const value_base token_id VALUE_BASE"),
+            &crate::crypto::util::VALUE_BASE_GENERATOR,
+            &token_id,
+        )?;
+
+        // Line 34: ec_mul vcv value value_base
+        let vcv = value_base.mul(
+            cs.namespace(|| "Line 34: ec_mul vcv value value_base"),
             &value,
         )?;
 
@@ -93,26 +92,6 @@ impl Circuit<bls12_381::Scalar> for MintContract {
         // Line 37: emit_ec cv
         cv.inputize(cs.namespace(|| "Line 37: emit_ec cv"))?;
 
-        // Line 42: ec_mul_const vca token_id G_VCV
-        let vca = ecc::fixed_base_multiplication(
-            cs.namespace(|| "Line 42: ec_mul_const vca token_id G_VCV"),
-            &zcash_proofs::constants::VALUE_COMMITMENT_VALUE_GENERATOR,
-            &token_id,
-        )?;
-
-        // Line 43: ec_mul_const rca randomness_token G_VCR
-        let rca = ecc::fixed_base_multiplication(
-            cs.namespace(|| "Line 43: ec_mul_const rca randomness_token G_VCR"),
-            &zcash_proofs::constants::VALUE_COMMITMENT_RANDOMNESS_GENERATOR,
-            &randomness_token,
-        )?;
-
-        // Line 44: ec_add ca vca rca
-        let ca = vca.add(cs.namespace(|| "Line 44: ec_add ca vca rca"), &rca)?;
-
-        // Line 46: emit_ec ca
-        ca.inputize(cs.namespace(|| "Line 46: emit_ec ca"))?;
-
         // Line 53: alloc_binary preimage
         let mut preimage = vec![];
 
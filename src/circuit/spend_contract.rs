@@ -19,7 +19,6 @@ pub struct SpendContract {
     pub value: Option<u64>,
     pub token_id: Option<jubjub::Fr>,
     pub randomness_value: Option<jubjub::Fr>,
-    pub randomness_token: Option<jubjub::Fr>,
     pub serial: Option<jubjub::Fr>,
     pub randomness_coin: Option<jubjub::Fr>,
     pub secret: Option<jubjub::Fr>,
@@ -50,16 +49,16 @@ impl Circuit<bls12_381::Scalar> for SpendContract {
             self.randomness_value,
         )?;
 
-        // Line 41: fr_as_binary_le randomness_token param:randomness_token
-        let randomness_token = boolean::field_into_boolean_vec_le(
-            cs.namespace(|| "Line 41: fr_as_binary_le randomness_token param:randomness_token"),
-            self.randomness_token,
+        // Line 45: ec_mul_const value_base token_id VALUE_BASE
+        let value_base = ecc::fixed_base_multiplication(
+            cs.namespace(|| "Line 45: ec_mul_const value_base token_id VALUE_BASE"),
+            &crate::crypto::util::VALUE_BASE_GENERATOR,
+            &token_id,
         )?;
 
-        // Line 46: ec_mul_const vcv value G_VCV
-        let vcv = ecc::fixed_base_multiplication(
-            cs.namespace(|| "Line 46: ec_mul_const vcv value G_VCV"),
-            &zcash_proofs::constants::VALUE_COMMITMENT_VALUE_GENERATOR,
+        // Line 46: ec_mul vcv value value_base
+        let vcv = value_base.mul(
+            cs.namespace(|| "Line 46: ec_mul vcv value value_base"),
             &value,
         )?;
 
@@ -76,26 +75,6 @@ impl Circuit<bls12_381::Scalar> for SpendContract {
         // Line 50: emit_ec cv
         cv.inputize(cs.namespace(|| "Line 50: emit_ec cv"))?;
 
-        // Line 46: ec_mul_const vca token_id G_VCV
-        let vca = ecc::fixed_base_multiplication(
-            cs.namespace(|| "Line 46: ec_mul_const vca token_id G_VCV"),
-            &zcash_proofs::constants::VALUE_COMMITMENT_VALUE_GENERATOR,
-            &token_id,
-        )?;
-
-        // Line 47: ec_mul_const rca randomness_token G_VCR
-        let rca = ecc::fixed_base_multiplication(
-            cs.namespace(|| "Line 47: ec_mul_const rca randomness_token G_VCR"),
-            &zcash_proofs::constants::VALUE_COMMITMENT_RANDOMNESS_GENERATOR,
-            &randomness_token,
-        )?;
-
-        // Line 48: ec_add ca vca rca
-        let ca = vca.add(cs.namespace(|| "Line 48: ec_add ca vca rca"), &rca)?;
-
-        // Line 50: emit_ec ca
-        ca.inputize(cs.namespace(|| "Line 50: emit_ec ca"))?;
-
         // Line 54: fr_as_binary_le serial param:serial
         let serial = boolean::field_into_boolean_vec_le(
             cs.namespace(|| "Line 54: fr_as_binary_le serial param:serial"),
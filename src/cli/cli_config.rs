@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     fs,
     marker::PhantomData,
     net::SocketAddr,
@@ -84,6 +85,9 @@ pub struct DarkfidConfig {
     pub wallet_path: String,
     /// The wallet password
     pub wallet_password: String,
+    /// Fee paid by every transfer, in units of the token sent
+    #[serde(default)]
+    pub fee: u64,
     /// The configured cashiers to use
     pub cashiers: Vec<Cashier>,
 }
@@ -103,6 +107,31 @@ pub struct GatewaydConfig {
     pub tls_identity_password: String,
     /// Path to the database
     pub database_path: String,
+    /// Lowest fee accepted for a transaction that spends coins, for each
+    /// token it may be paid in, keyed by the token ID in base58. With none
+    /// set, transactions pay no fee.
+    #[serde(default)]
+    pub min_fees: HashMap<String, u64>,
+    /// Path to mint.params, used to verify transactions
+    #[serde(default = "default_mint_params_path")]
+    pub mint_params_path: String,
+    /// Path to spend.params, used to verify transactions
+    #[serde(default = "default_spend_params_path")]
+    pub spend_params_path: String,
+    /// Public keys of the cashiers allowed to mint coins, in base58. With
+    /// none set, every deposit is refused.
+    #[serde(default)]
+    pub cashier_public_keys: Vec<String>,
+}
+
+// Where the example configs put the trusted setup parameters, so configs
+// written before gatewayd verified transactions still load
+fn default_mint_params_path() -> String {
+    "/usr/local/share/darkfi/mint.params".into()
+}
+
+fn default_spend_params_path() -> String {
+    "/usr/local/share/darkfi/spend.params".into()
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -1,28 +1,37 @@
 use async_executor::Executor;
 use async_std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 
-use bellman::groth16;
 use bls12_381::Bls12;
 use log::{debug, info, warn};
 use url::Url;
 use blake2s_simd::Params as Blake2sParams;
+use easy_parallel::Parallel;
 
 use crate::{
-    blockchain::{rocks::columns, Rocks, RocksColumn, Slab},
+    blockchain::{
+        kvstore::IteratorMode, rocks::columns, KvStore, Rocks, RocksColumn, Slab, SlabStore,
+        Snapshot, UndoRecord,
+    },
     crypto::{
         coin::Coin,
+        keys::{Address, OutgoingViewingKey, ViewingKey},
         merkle::{CommitmentTree, IncrementalWitness},
         merkle_node::MerkleNode,
-        note::{EncryptedNote, Note},
+        note::{EncryptedNote, Memo, Note},
         nullifier::Nullifier,
-        OwnCoin,
+        OwnCoin, PreparedKey,
     },
-    serial::{serialize, Decodable, Encodable},
+    serial::{deserialize, serialize, Decodable},
     service::{GatewayClient, GatewaySlabsSubscriber},
-    state::{state_transition, ProgramState, StateUpdate},
+    state::{
+        state_transition, verified_state_transition, ProgramState, StateQuery, StateUpdate,
+        VerifyResult,
+    },
     tx,
-    wallet::{walletdb::Balances, CashierDbPtr, Keypair, WalletPtr},
-    Result,
+    wallet::{walletdb::Balances, CashierDbPtr, Keypair, SentTransaction, ViewedNote, WalletPtr},
+    Error, Result,
 };
 
 #[derive(Debug)]
@@ -37,15 +46,77 @@ pub enum ClientFailed {
     EmptyPassword,
     WalletInitialized,
     KeyExists,
+    NoSeed,
+    InvalidMnemonic(String),
     ClientError(String),
     VerifyError(String),
 }
 
+/// A transfer which was built and verified, but not sent
+pub struct DryRun {
+    pub tx_id: [u8; 32],
+    pub fee: u64,
+    // The coins the transfer would spend
+    pub inputs: Vec<OwnCoin>,
+    // What comes back to the sender, if the inputs are worth more than
+    // the amount and fee
+    pub change: Option<u64>,
+    // Whether the state would accept the transaction
+    pub verified: VerifyResult<()>,
+}
+
+/// A transaction built by the client, to be sent to the gateway without
+/// holding the lock on the client
+pub struct Submission {
+    pub tx_id: [u8; 32],
+    // The encoded transaction
+    data: Vec<u8>,
+    // The coins it spends, marked as spent once the gateway accepts it
+    coins: Vec<OwnCoin>,
+    sent: SentTransaction,
+    gateway_addr: SocketAddr,
+    wallet: WalletPtr,
+    spending: Arc<Mutex<Vec<Nullifier>>>,
+}
+
+impl Submission {
+    /// Send the transaction to the gateway and wait until it is in a slab.
+    /// Returns the index of the slab.
+    pub async fn submit(self) -> Result<u64> {
+        let result = GatewayClient::submit_tx(self.gateway_addr, self.tx_id, self.data).await;
+
+        // Accepted or not, the coins are no longer waiting on the gateway
+        self.spending.lock().await.retain(|nullifier| {
+            !self
+                .coins
+                .iter()
+                .any(|own_coin| own_coin.nullifier == *nullifier)
+        });
+
+        let index = result?;
+
+        for own_coin in self.coins.iter() {
+            self.wallet.confirm_spend_coin(&own_coin.coin)?;
+        }
+        self.wallet.put_transaction(&self.sent)?;
+
+        Ok(index)
+    }
+}
+
+// Most slabs received from the gateway that are verified together
+const VERIFY_BATCH_SIZE: usize = 64;
+
 pub struct Client {
     mint_params: bellman::groth16::Parameters<Bls12>,
     spend_params: bellman::groth16::Parameters<Bls12>,
-    gateway: GatewayClient,
+    gateway: Arc<Mutex<GatewayClient>>,
     wallet: WalletPtr,
+    // Paid by every transaction that spends coins, in the token sent
+    fee: u64,
+    // Nullifiers of the coins spent by transactions the gateway has not
+    // answered yet, so they are not selected again
+    spending: Arc<Mutex<Vec<Nullifier>>>,
     pub main_keypair: Keypair,
 }
 
@@ -56,6 +127,7 @@ impl Client {
         wallet: WalletPtr,
         mint_params: bellman::groth16::Parameters<Bls12>,
         spend_params: bellman::groth16::Parameters<Bls12>,
+        fee: u64,
     ) -> Result<Self> {
         wallet.init_db().await?;
 
@@ -66,8 +138,8 @@ impl Client {
         let main_keypair = wallet.get_keypairs()?[0].clone();
 
         info!(
-            target: "CLIENT", "Main Keypair: {}",
-            bs58::encode(&serialize(&main_keypair.public)).into_string()
+            target: "CLIENT", "Main Address: {}",
+            bs58::encode(&serialize(&main_keypair.address())).into_string()
         );
 
         let slabstore = RocksColumn::<columns::Slabs>::new(rocks.clone());
@@ -80,79 +152,188 @@ impl Client {
             mint_params,
             spend_params,
             wallet,
-            gateway,
+            gateway: Arc::new(Mutex::new(gateway)),
+            fee,
+            spending: Arc::new(Mutex::new(vec![])),
             main_keypair,
         })
     }
 
-    pub async fn start(&mut self) -> Result<()> {
-        self.gateway.start().await?;
+    /// Connect to the gateway and sync the slabstore. A restored wallet
+    /// asks for a `full_replay`, as its coins may be in any slab.
+    pub async fn start(&mut self, state: Arc<Mutex<State>>, full_replay: bool) -> Result<()> {
+        let mut gateway = self.gateway.lock().await;
+        gateway.start().await?;
+
+        // A new client starts from the gateway's snapshot rather than
+        // replaying every slab from the first one
+        let is_empty = state.lock().await.slabstore.get_last_index()? == 0;
+        if is_empty {
+            if let Some(snapshot) = gateway.get_snapshot().await? {
+                if full_replay {
+                    return Err(Error::SlabsStore(format!(
+                        "Gateway has pruned the slabs up to {}, coins received before them \
+                         can not be found. Start without --restore to sync from the snapshot.",
+                        snapshot.get_index()
+                    )));
+                }
+                info!(target: "CLIENT", "Start from snapshot at slab {}", snapshot.get_index());
+                state.lock().await.load_snapshot(snapshot)?;
+                gateway.reload_tip().await?;
+            }
+        }
+
+        gateway.sync().await?;
         Ok(())
     }
 
     pub async fn transfer(
         &mut self,
         token_id: jubjub::Fr,
-        pub_key: jubjub::SubgroupPoint,
+        address: Address,
         amount: u64,
+        memo: Memo,
         state: Arc<Mutex<State>>,
-    ) -> ClientResult<()> {
+    ) -> ClientResult<Submission> {
         debug!(target: "CLIENT", "Start transfer {}", amount);
 
         let token_id_exists = self.wallet.token_id_exists(&token_id)?;
 
-        if token_id_exists {
-            self.send(pub_key, amount, token_id, false, state).await?;
-        } else {
+        if !token_id_exists {
             return Err(ClientFailed::NotEnoughValue(amount));
         }
 
+        let submission = self
+            .send(address, amount, token_id, memo, false, state)
+            .await?;
+
         debug!(target: "CLIENT", "End transfer {}", amount);
 
-        Ok(())
+        Ok(submission)
     }
 
+    /// Build a payment and check it against the state. It is sent to the
+    /// gateway with `Submission::submit`, after the lock on the client is
+    /// released.
     pub async fn send(
         &mut self,
-        pub_key: jubjub::SubgroupPoint,
+        address: Address,
         amount: u64,
         token_id: jubjub::Fr,
+        memo: Memo,
         clear_input: bool,
         state: Arc<Mutex<State>>,
-    ) -> ClientResult<()> {
+    ) -> ClientResult<Submission> {
         debug!(target: "CLIENT", "Start send {}", amount);
 
         if amount == 0 {
             return Err(ClientFailed::InvalidAmount(amount as u64));
         }
 
-        let coins = self
-            .build_slab_from_tx(pub_key, amount, token_id, clear_input, state)
-            .await?;
+        let mut spending = self.spending.lock().await;
 
-        for coin in coins.iter() {
-            self.wallet.confirm_spend_coin(coin)?;
-        }
+        let (tx, inputs, _) = self.build_tx(
+            address,
+            amount,
+            token_id,
+            memo.clone(),
+            clear_input,
+            &spending,
+        )?;
+        let tx_id = tx.id();
+        let data = serialize(&tx);
+        // The output to the recipient is built last, after the change
+        let coin = match tx.outputs.last() {
+            Some(output) => Coin::new(output.revealed.coin),
+            None => {
+                return Err(ClientFailed::ClientError(
+                    "Transaction has no outputs".into(),
+                ))
+            }
+        };
+
+        // The gateway checks it again against its own state
+        state_transition(&*state.lock().await, tx)?;
+
+        spending.extend(inputs.iter().map(|own_coin| own_coin.nullifier.clone()));
+
+        let submission = Submission {
+            tx_id,
+            data,
+            coins: inputs,
+            sent: SentTransaction {
+                tx_id,
+                coin,
+                token_id,
+                value: amount,
+                recipient: address,
+                memo,
+            },
+            gateway_addr: self.gateway.lock().await.addr(),
+            wallet: self.wallet.clone(),
+            spending: self.spending.clone(),
+        };
 
         debug!(target: "CLIENT", "End send {}", amount);
 
-        Ok(())
+        Ok(submission)
     }
 
-    async fn build_slab_from_tx(
-        &mut self,
-        pub_key: jubjub::SubgroupPoint,
-        value: u64,
+    /// Build and verify a transfer without sending it, to see what it
+    /// would spend and whether the state would accept it.
+    pub async fn dry_run_transfer(
+        &self,
         token_id: jubjub::Fr,
-        clear_input: bool,
+        address: Address,
+        amount: u64,
+        memo: Memo,
         state: Arc<Mutex<State>>,
-    ) -> ClientResult<Vec<Coin>> {
-        debug!(target: "CLIENT", "Start build slab from tx");
+    ) -> ClientResult<DryRun> {
+        debug!(target: "CLIENT", "Start dry run transfer {}", amount);
 
+        if !self.wallet.token_id_exists(&token_id)? {
+            return Err(ClientFailed::NotEnoughValue(amount));
+        }
+
+        if amount == 0 {
+            return Err(ClientFailed::InvalidAmount(amount));
+        }
+
+        let spending = self.spending.lock().await;
+        let (tx, inputs, change) =
+            self.build_tx(address, amount, token_id, memo, false, &spending)?;
+
+        let dry_run = DryRun {
+            tx_id: tx.id(),
+            fee: tx.fee.value,
+            inputs,
+            change,
+            verified: state_transition(&*state.lock().await, tx).map(|_| ()),
+        };
+
+        debug!(target: "CLIENT", "End dry run transfer {}", amount);
+
+        Ok(dry_run)
+    }
+
+    // Select the coins to spend, leaving out the ones in `spending`, and
+    // build the transaction. Returns it along with the coins it spends and
+    // the value of the change output, if any.
+    fn build_tx(
+        &self,
+        address: Address,
+        value: u64,
+        token_id: jubjub::Fr,
+        memo: Memo,
+        clear_input: bool,
+        spending: &[Nullifier],
+    ) -> ClientResult<(tx::Transaction, Vec<OwnCoin>, Option<u64>)> {
         let mut clear_inputs: Vec<tx::TransactionBuilderClearInputInfo> = vec![];
         let mut inputs: Vec<tx::TransactionBuilderInputInfo> = vec![];
         let mut outputs: Vec<tx::TransactionBuilderOutputInfo> = vec![];
-        let mut coins: Vec<Coin> = vec![];
+        let mut coins: Vec<OwnCoin> = vec![];
+        let mut change: Option<u64> = None;
+        let mut fee = tx::TransactionFee::none();
 
         if clear_input {
             let signature_secret = self.main_keypair.private;
@@ -165,14 +346,26 @@ impl Client {
         } else {
             debug!(target: "CLIENT", "Start build inputs");
 
+            fee = tx::TransactionFee {
+                value: self.fee,
+                token_id,
+            };
+            let total = value
+                .checked_add(fee.value)
+                .ok_or(ClientFailed::InvalidAmount(value))?;
+
             let mut inputs_value: u64 = 0;
 
             let own_coins = self.wallet.get_own_coins()?;
 
             for own_coin in own_coins.iter() {
-                if inputs_value >= value {
+                if inputs_value >= total {
                     break;
                 }
+                // Coins spent by a transaction sent to the gateway are taken
+                if own_coin.note.token_id != token_id || spending.contains(&own_coin.nullifier) {
+                    continue;
+                }
                 let witness = &own_coin.witness;
                 let merkle_path = witness.path().unwrap();
                 inputs_value += own_coin.note.value;
@@ -184,20 +377,22 @@ impl Client {
                 };
 
                 inputs.push(input);
-                coins.push(own_coin.coin.clone());
+                coins.push(own_coin.clone());
             }
 
-            if inputs_value < value {
+            if inputs_value < total {
                 return Err(ClientFailed::NotEnoughValue(inputs_value));
             }
 
-            if inputs_value > value {
-                let return_value: u64 = inputs_value - value;
+            if inputs_value > total {
+                let return_value: u64 = inputs_value - total;
+                change = Some(return_value);
 
                 outputs.push(tx::TransactionBuilderOutputInfo {
                     value: return_value,
                     token_id,
-                    public: self.main_keypair.public,
+                    address: self.main_keypair.address(),
+                    memo: Memo::default(),
                 });
             }
 
@@ -207,56 +402,49 @@ impl Client {
         outputs.push(tx::TransactionBuilderOutputInfo {
             value,
             token_id,
-            public: pub_key,
+            address,
+            memo,
         });
 
         let builder = tx::TransactionBuilder {
             clear_inputs,
             inputs,
             outputs,
+            fee,
+            valid_after: None,
+            expires_at: None,
+            ovk: Some(self.main_keypair.outgoing_viewing_key()),
         };
 
-        let tx: tx::Transaction;
-
-        let mut tx_data = vec![];
-        {
-            tx = builder.build(&self.mint_params, &self.spend_params);
-            tx.encode(&mut tx_data).expect("encode tx");
-        }
-
-        let slab = Slab::new(tx_data);
+        let tx = builder.build(&self.mint_params, &self.spend_params);
 
-        debug!(target: "CLIENT", "End build slab from tx");
-
-        // check if it's valid before send it to gateway
-        let state = state.lock().await;
-        state_transition(&state, tx)?;
-
-        self.gateway.put_slab(slab).await?;
-
-        Ok(coins)
+        Ok((tx, coins, change))
     }
 
     pub async fn connect_to_subscriber_from_cashier(
         &self,
         state: Arc<Mutex<State>>,
         cashier_wallet: CashierDbPtr,
-        notify: async_channel::Sender<(jubjub::SubgroupPoint, u64)>,
+        notify: async_channel::Sender<(Address, u64)>,
         executor: Arc<Executor<'_>>,
     ) -> Result<()> {
         // start subscribing
         debug!(target: "CLIENT", "Start subscriber for cashier");
-        let gateway_slabs_sub: GatewaySlabsSubscriber =
-            self.gateway.start_subscriber(executor.clone()).await?;
+        let gateway_slabs_sub: GatewaySlabsSubscriber = self
+            .gateway
+            .lock()
+            .await
+            .start_subscriber(executor.clone())
+            .await?;
 
         let secret_key = self.main_keypair.private;
         let wallet = self.wallet.clone();
 
         let task: smol::Task<Result<()>> = executor.spawn(async move {
             loop {
-                let slab = gateway_slabs_sub.recv().await?;
+                let slabs = Self::recv_slabs(&gateway_slabs_sub).await?;
 
-                debug!(target: "CLIENT", "Received new slab");
+                debug!(target: "CLIENT", "Received {} new slabs", slabs.len());
 
                 let mut secret_keys: Vec<jubjub::Fr> = vec![secret_key];
                 let mut withdraw_keys = cashier_wallet.get_withdraw_private_keys()?;
@@ -264,7 +452,7 @@ impl Client {
 
                 let update_state = Self::update_state(
                     secret_keys,
-                    &slab,
+                    slabs,
                     state.clone(),
                     wallet.clone(),
                     Some(notify.clone()),
@@ -290,21 +478,32 @@ impl Client {
     ) -> Result<()> {
         // start subscribing
         debug!(target: "CLIENT", "Start subscriber");
-        let gateway_slabs_sub: GatewaySlabsSubscriber =
-            self.gateway.start_subscriber(executor.clone()).await?;
+        let gateway_slabs_sub: GatewaySlabsSubscriber = self
+            .gateway
+            .lock()
+            .await
+            .start_subscriber(executor.clone())
+            .await?;
 
-        let secret_key = self.main_keypair.private;
         let wallet = self.wallet.clone();
 
         let task: smol::Task<Result<()>> = executor.spawn(async move {
             loop {
-                let slab = gateway_slabs_sub.recv().await?;
+                let slabs = Self::recv_slabs(&gateway_slabs_sub).await?;
 
-                debug!(target: "CLIENT", "Received new slab");
+                debug!(target: "CLIENT", "Received {} new slabs", slabs.len());
+
+                // Every account of the wallet, including ones added since
+                // the last slabs
+                let secret_keys = wallet
+                    .get_keypairs()?
+                    .iter()
+                    .map(|keypair| keypair.private)
+                    .collect();
 
                 let update_state = Self::update_state(
-                    vec![secret_key],
-                    &slab,
+                    secret_keys,
+                    slabs,
                     state.clone(),
                     wallet.clone(),
                     None,
@@ -323,28 +522,123 @@ impl Client {
         Ok(())
     }
 
+    // Wait for the next slab, then take the ones that have arrived since
+    // as well, so they are verified together
+    async fn recv_slabs(gateway_slabs_sub: &GatewaySlabsSubscriber) -> Result<Vec<Slab>> {
+        let mut slabs = vec![gateway_slabs_sub.recv().await?];
+        while slabs.len() < VERIFY_BATCH_SIZE {
+            match gateway_slabs_sub.try_recv() {
+                Ok(slab) => slabs.push(slab),
+                Err(_) => break,
+            }
+        }
+        Ok(slabs)
+    }
+
     async fn update_state(
         secret_keys: Vec<jubjub::Fr>,
-        slab: &Slab,
+        slabs: Vec<Slab>,
         state: Arc<Mutex<State>>,
         wallet: WalletPtr,
-        notify: Option<async_channel::Sender<(jubjub::SubgroupPoint, u64)>>,
+        notify: Option<async_channel::Sender<(Address, u64)>>,
     ) -> Result<()> {
-        debug!(target: "CLIENT", "Build tx from slab and update the state");
+        debug!(target: "CLIENT", "Build txs from slabs and update the state");
+
+        let (mint_pvk, spend_pvk) = {
+            let state = state.lock().await;
+            (state.mint_pvk.clone(), state.spend_pvk.clone())
+        };
 
-        let tx = tx::Transaction::decode(&slab.get_payload()[..])?;
+        // Proofs and signatures do not depend on the state, so they are
+        // checked on the thread pool without holding the state lock
+        let payloads: Vec<Vec<u8>> = slabs.iter().map(|slab| slab.get_payload()).collect();
+        let txs = smol::unblock(move || Self::verify_txs(&payloads, &mint_pvk, &spend_pvk)).await;
 
+        // What is left depends on the slabs before, so it is done in order
         let mut state = state.lock().await;
 
-        let update = state_transition(&state, tx)?;
+        for (slab, tx) in slabs.into_iter().zip(txs) {
+            // The same slab can arrive both from sync and from the subscriber
+            if slab.get_index() <= state.slabstore.get_last_index()? {
+                debug!(target: "CLIENT", "Slab {} is already applied", slab.get_index());
+                continue;
+            }
 
-        state
-            .apply(update, secret_keys.clone(), notify, wallet)
-            .await?;
+            let update = match tx.and_then(|tx| Self::verify_slab(&state, &slab, tx)) {
+                Ok(update) => update,
+                Err(e) => {
+                    // Keep the slab so the chain continues, but leave the state as it is
+                    warn!(target: "CLIENT", "Skip invalid slab {}: {}", slab.get_index(), e);
+                    state.skip(slab)?;
+                    continue;
+                }
+            };
+
+            state
+                .apply(
+                    update,
+                    slab,
+                    secret_keys.clone(),
+                    notify.clone(),
+                    Some(wallet.clone()),
+                )
+                .await?;
+        }
 
         Ok(())
     }
 
+    // Decode the transactions and run the checks which do not need the
+    // state, splitting the payloads between one thread per CPU. The proofs
+    // of each thread's share are checked in one batch.
+    fn verify_txs(
+        payloads: &[Vec<u8>],
+        mint_pvk: &PreparedKey,
+        spend_pvk: &PreparedKey,
+    ) -> Vec<Result<tx::Transaction>> {
+        let chunk_size = payloads.len() / num_cpus::get() + 1;
+
+        Parallel::new()
+            .each(payloads.chunks(chunk_size), |payloads| {
+                let decoded: Vec<Result<tx::Transaction>> = payloads
+                    .iter()
+                    .map(|payload| tx::Transaction::decode(&payload[..]))
+                    .collect();
+
+                let txs: Vec<&tx::Transaction> = decoded.iter().flatten().collect();
+                let mut proofs = tx::verify_proofs_batch(&txs, mint_pvk, spend_pvk).into_iter();
+
+                decoded
+                    .into_iter()
+                    .map(|tx| {
+                        let tx = tx?;
+                        proofs.next().expect("Proof result for every tx")?;
+                        tx.verify_without_proofs()?;
+                        Ok(tx)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .run()
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    // The checks against the state, for a transaction whose proofs and
+    // signatures have been verified already
+    fn verify_slab(state: &State, slab: &Slab, tx: tx::Transaction) -> Result<StateUpdate> {
+        let update = verified_state_transition(state, tx)?;
+
+        if state.root_after(&update.coins)? != slab.get_header().merkle_root {
+            return Err(Error::SlabsStore(format!(
+                "Merkle root in slab {} does not match its coins",
+                slab.get_index()
+            )));
+        }
+
+        Ok(update)
+    }
+
     pub async fn init_db(&self) -> Result<()> {
         self.wallet.init_db().await
     }
@@ -361,6 +655,26 @@ impl Client {
         self.wallet.key_gen()
     }
 
+    /// The mnemonic phrase of the wallet seed, to back up its keys
+    pub fn get_mnemonic(&self) -> Result<Option<String>> {
+        self.wallet.get_mnemonic()
+    }
+
+    /// Derive the next account from the wallet seed
+    pub fn new_account(&self) -> Result<Address> {
+        Ok(self.wallet.new_account()?.address())
+    }
+
+    /// The addresses of every account, the main one first
+    pub fn get_addresses(&self) -> Result<Vec<Address>> {
+        Ok(self
+            .wallet
+            .get_keypairs()?
+            .iter()
+            .map(Keypair::address)
+            .collect())
+    }
+
     pub fn get_balances(&self) -> Result<Balances> {
         self.wallet.get_balances()
     }
@@ -372,25 +686,57 @@ impl Client {
     pub fn get_token_id(&self) -> Result<Vec<jubjub::Fr>> {
         self.wallet.get_token_id()
     }
+
+    pub fn get_transactions(&self) -> Result<Vec<SentTransaction>> {
+        self.wallet.get_transactions()
+    }
+
+    pub fn get_received_notes(&self) -> Result<Vec<Note>> {
+        self.wallet.get_received_notes()
+    }
+
+    /// The viewing key of the main keypair. Whoever holds it sees the coins
+    /// sent to the main address, but can not spend them.
+    pub fn export_viewing_key(&self) -> ViewingKey {
+        self.main_keypair.viewing_key()
+    }
+
+    /// Watch the address of a viewing key. Only coins in slabs applied
+    /// after the import are found.
+    pub fn import_viewing_key(&self, view_key: &ViewingKey) -> Result<()> {
+        self.wallet.put_view_key(view_key)
+    }
+
+    pub fn get_viewed_notes(&self) -> Result<Vec<ViewedNote>> {
+        self.wallet.get_viewed_notes()
+    }
 }
 
-pub struct State {
+pub struct State<K: KvStore = Rocks> {
     // The entire merkle tree state
     pub tree: CommitmentTree<MerkleNode>,
     // List of all previous and the current merkle roots
     // This is the hashed value of all the children.
-    pub merkle_roots: RocksColumn<columns::MerkleRoots>,
-    // Nullifiers prevent double spending
-    pub nullifiers: RocksColumn<columns::Nullifiers>,
+    pub merkle_roots: RocksColumn<columns::MerkleRoots, K>,
+    // Nullifiers prevent double spending, mapped to the slab revealing them
+    pub nullifiers: RocksColumn<columns::Nullifiers, K>,
+    // Every coin mapped to the slab which minted it
+    pub coins: RocksColumn<columns::Coins, K>,
+    // Applied slabs, written together with the state they produce
+    pub slabstore: Arc<SlabStore<K>>,
+    // The merkle tree as of the last applied slab, so it survives restarts
+    pub saved_tree: RocksColumn<columns::Tree, K>,
+    // Per slab records of what applying it changed, used to roll back
+    pub undo: RocksColumn<columns::Undo, K>,
     // Mint verifying key used by ZK
-    pub mint_pvk: groth16::PreparedVerifyingKey<Bls12>,
+    pub mint_pvk: Arc<PreparedKey>,
     // Spend verifying key used by ZK
-    pub spend_pvk: groth16::PreparedVerifyingKey<Bls12>,
+    pub spend_pvk: Arc<PreparedKey>,
     // List of cashier public keys
     pub public_keys: Vec<jubjub::SubgroupPoint>,
 }
 
-impl ProgramState for State {
+impl<K: KvStore> StateQuery for State<K> {
     fn is_valid_cashier_public_key(&self, public: &jubjub::SubgroupPoint) -> bool {
         debug!(target: "CLIENT STATE", "Check if it is valid cashier public key");
         self.public_keys.contains(public)
@@ -414,44 +760,753 @@ impl ProgramState for State {
         false
     }
 
+    fn nullifier_slab(&self, nullifier: &Nullifier) -> Option<u64> {
+        self.get_nullifier_slab(nullifier).ok().flatten()
+    }
+
+    fn height(&self) -> Result<u64> {
+        self.slabstore.get_last_index()
+    }
+}
+
+impl<K: KvStore> ProgramState for State<K> {
     // load from disk
-    fn mint_pvk(&self) -> &groth16::PreparedVerifyingKey<Bls12> {
+    fn mint_pvk(&self) -> &PreparedKey {
         &self.mint_pvk
     }
 
-    fn spend_pvk(&self) -> &groth16::PreparedVerifyingKey<Bls12> {
+    fn spend_pvk(&self) -> &PreparedKey {
         &self.spend_pvk
     }
 }
 
-impl State {
+// A state whose slabstore holds slabs it has not applied yet, seen from
+// the slab being replayed
+struct Replaying<'a, K: KvStore> {
+    state: &'a State<K>,
+    height: u64,
+}
+
+impl<K: KvStore> StateQuery for Replaying<'_, K> {
+    fn is_valid_cashier_public_key(&self, public: &jubjub::SubgroupPoint) -> bool {
+        self.state.is_valid_cashier_public_key(public)
+    }
+
+    fn is_valid_merkle(&self, merkle_root: &MerkleNode) -> bool {
+        self.state.is_valid_merkle(merkle_root)
+    }
+
+    fn nullifier_exists(&self, nullifier: &Nullifier) -> bool {
+        self.state.nullifier_exists(nullifier)
+    }
+
+    fn nullifier_slab(&self, nullifier: &Nullifier) -> Option<u64> {
+        self.state.nullifier_slab(nullifier)
+    }
+
+    fn height(&self) -> Result<u64> {
+        Ok(self.height)
+    }
+}
+
+impl<K: KvStore> ProgramState for Replaying<'_, K> {
+    fn mint_pvk(&self) -> &PreparedKey {
+        &self.state.mint_pvk
+    }
+
+    fn spend_pvk(&self) -> &PreparedKey {
+        &self.state.spend_pvk
+    }
+}
+
+impl<K: KvStore> State<K> {
+    // Key of the single (last applied slab index, tree) entry in the tree column
+    pub const TREE_KEY: u8 = 0;
+    // Key of the last slab index the wallet was updated with, in the same column
+    pub const WALLET_KEY: u8 = 1;
+
+    /// A state kept in the columns of `store`, with an empty merkle tree.
+    /// `load_tree` picks up where the last run left it.
+    pub fn new(
+        store: Arc<K>,
+        mint_pvk: PreparedKey,
+        spend_pvk: PreparedKey,
+        public_keys: Vec<jubjub::SubgroupPoint>,
+    ) -> Result<Self> {
+        Ok(Self {
+            tree: CommitmentTree::empty(),
+            merkle_roots: RocksColumn::new(store.clone()),
+            nullifiers: RocksColumn::new(store.clone()),
+            coins: RocksColumn::new(store.clone()),
+            slabstore: SlabStore::new(RocksColumn::new(store.clone()))?,
+            saved_tree: RocksColumn::new(store.clone()),
+            undo: RocksColumn::new(store),
+            mint_pvk: Arc::new(mint_pvk),
+            spend_pvk: Arc::new(spend_pvk),
+            public_keys,
+        })
+    }
+
+    /// Reload the merkle tree saved with the last applied slab and replay
+    /// any stored slabs it has not seen yet. Returns the last applied index.
+    pub fn load_tree(&mut self) -> Result<u64> {
+        debug!(target: "CLIENT STATE", "Load merkle tree");
+
+        let (mut last_applied, tree) = self
+            .saved_tree
+            .get_value_deserialized::<(u64, CommitmentTree<MerkleNode>)>(Self::TREE_KEY)?
+            .unwrap_or((0, CommitmentTree::empty()));
+
+        self.tree = tree;
+
+        let last_index = self.slabstore.get_last_index()?;
+
+        if last_applied < last_index {
+            info!(
+                target: "CLIENT STATE",
+                "Replay slabs {} to {}", last_applied + 1, last_index
+            );
+        }
+
+        for index in (last_applied + 1)..(last_index + 1) {
+            match self.slabstore.get_value_deserialized(serialize(&index))? {
+                Some(slab) => self.replay(&slab)?,
+                None => break,
+            }
+            last_applied = index;
+        }
+
+        Ok(last_applied)
+    }
+
+    // Slabs in the store which the saved tree has not seen yet are checked
+    // again before they are replayed, as slabs from the gateway are. An
+    // invalid slab stays in the store so the chain continues, but leaves the
+    // state as it is. The wallet already holds the coins and witnesses from
+    // these slabs.
+    fn replay(&mut self, slab: &Slab) -> Result<()> {
+        let index = slab.get_index();
+
+        let update = match self.check_replayed(slab) {
+            Ok(update) => Some(update),
+            Err(reason) => {
+                warn!(target: "CLIENT STATE", "Skip invalid slab {}: {}", index, reason);
+                None
+            }
+        };
+
+        let mut batch = self.slabstore.batch();
+        let mut undo = UndoRecord {
+            nullifiers: vec![],
+            coins: vec![],
+            merkle_roots: vec![],
+            tree: self.tree.clone(),
+        };
+
+        if let Some(update) = update {
+            for nullifier in update.nullifiers {
+                self.nullifiers
+                    .put_batch(&mut batch, nullifier.clone(), index)?;
+                undo.nullifiers.push(nullifier);
+            }
+
+            for coin in update.coins {
+                self.coins.put_batch(&mut batch, coin.clone(), index)?;
+                let node = MerkleNode::from_coin(&coin);
+                undo.coins.push(coin);
+                self.tree.append(node)?;
+                self.merkle_roots
+                    .put_batch(&mut batch, self.tree.root(), vec![] as Vec<u8>)?;
+                undo.merkle_roots.push(self.tree.root());
+            }
+        }
+
+        self.undo.put_batch(&mut batch, index, undo)?;
+        self.saved_tree
+            .put_batch(&mut batch, Self::TREE_KEY, (index, self.tree.clone()))?;
+
+        batch.commit()
+    }
+
+    // The full checks of a stored slab, against the state as of the slab
+    // before it
+    fn check_replayed(&self, slab: &Slab) -> std::result::Result<StateUpdate, String> {
+        let tx = tx::Transaction::decode(&slab.get_payload()[..]).map_err(|e| e.to_string())?;
+
+        let replaying = Replaying {
+            state: self,
+            height: slab.get_index() - 1,
+        };
+        let update = state_transition(&replaying, tx).map_err(|e| e.to_string())?;
+
+        let root = self.root_after(&update.coins).map_err(|e| e.to_string())?;
+        if root != slab.get_header().merkle_root {
+            return Err("Merkle root does not match the slab's coins".into());
+        }
+
+        Ok(update)
+    }
+
+    /// Start an empty state from a snapshot. Slabs before the snapshot can
+    /// not be rolled back, and the wallet only learns about coins received
+    /// after it.
+    pub fn load_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
+        let index = snapshot.get_index();
+
+        if !snapshot.verify() {
+            return Err(Error::SlabsStore(format!(
+                "Snapshot tree does not match the merkle root of slab {}",
+                index
+            )));
+        }
+
+        let mut batch = self.slabstore.batch();
+
+        if self
+            .slabstore
+            .put_base_batch(&mut batch, snapshot.slab)?
+            .is_none()
+        {
+            return Err(Error::SlabsStore(
+                "Can only load a snapshot into an empty slabstore".into(),
+            ));
+        }
+
+        for (nullifier, slab_index) in snapshot.nullifiers {
+            self.nullifiers
+                .put_batch(&mut batch, nullifier, slab_index)?;
+        }
+        for root in snapshot.merkle_roots {
+            self.merkle_roots
+                .put_batch(&mut batch, root, vec![] as Vec<u8>)?;
+        }
+        self.saved_tree
+            .put_batch(&mut batch, Self::TREE_KEY, (index, snapshot.tree.clone()))?;
+
+        batch.commit()?;
+        self.tree = snapshot.tree;
+
+        Ok(())
+    }
+
+    /// Take a snapshot of the state as it was after slab `index`. It is read
+    /// from the state, so it only holds what the slabs this state verified
+    /// and applied have added. The undo records of the slabs after `index`
+    /// take back what they changed.
+    pub fn snapshot(&self, index: u64) -> Result<Snapshot> {
+        let height = self.slabstore.get_last_index()?;
+        if index > height {
+            return Err(Error::SlabsStore(format!(
+                "Slab {} has not been applied",
+                index
+            )));
+        }
+
+        let slab = match self.slabstore.get_value_deserialized(serialize(&index))? {
+            Some(slab) => slab.pruned(),
+            None => return Err(Error::SlabsStore(format!("Slab {} not found", index))),
+        };
+
+        let mut tree = self.tree.clone();
+        let mut later_roots = HashSet::new();
+
+        // Newest first, so the last tree is the one right after `index`
+        for i in ((index + 1)..=height).rev() {
+            let undo = self.get_undo(i)?;
+            later_roots.extend(undo.merkle_roots.iter().map(|root| root.repr));
+            tree = undo.tree;
+        }
+
+        let mut nullifiers = vec![];
+        for (key, value) in self.nullifiers.iterator(IteratorMode::Start)? {
+            let slab_index: u64 = deserialize(&value)?;
+            if slab_index <= index {
+                nullifiers.push((deserialize(&key)?, slab_index));
+            }
+        }
+
+        let mut merkle_roots = vec![];
+        for (key, _) in self.merkle_roots.iterator(IteratorMode::Start)? {
+            let root: MerkleNode = deserialize(&key)?;
+            if !later_roots.contains(&root.repr) {
+                merkle_roots.push(root);
+            }
+        }
+
+        Ok(Snapshot {
+            slab,
+            tree,
+            nullifiers,
+            merkle_roots,
+        })
+    }
+
+    /// Index of the slab which revealed the nullifier, i.e. spent its coin.
+    pub fn get_nullifier_slab(&self, nullifier: &Nullifier) -> Result<Option<u64>> {
+        self.nullifiers.get_value_deserialized(nullifier.clone())
+    }
+
+    /// Index of the slab which minted the coin.
+    pub fn get_coin_slab(&self, coin: &Coin) -> Result<Option<u64>> {
+        self.coins.get_value_deserialized(coin.clone())
+    }
+
+    /// Root of the merkle tree once the given coins are added to it.
+    pub fn root_after(&self, coins: &[Coin]) -> Result<MerkleNode> {
+        let mut tree = self.tree.clone();
+        for coin in coins.iter() {
+            tree.append(MerkleNode::from_coin(coin))?;
+        }
+        Ok(tree.root())
+    }
+
+    /// Build the chain of slabs that extends the last applied one, one slab
+    /// for each payload, given the coins its transaction creates. The headers
+    /// only hold until another slab is applied, so the slabs should be
+    /// applied before the state lock is released.
+    pub fn next_slabs(&self, txs: Vec<(Vec<u8>, &[Coin])>) -> Result<Vec<Slab>> {
+        let (mut index, mut prev_hash) = match self.slabstore.get_last_slab()? {
+            Some(slab) => (slab.get_index() + 1, slab.hash()),
+            None => (1, [0u8; 32]),
+        };
+
+        let mut tree = self.tree.clone();
+        let mut slabs = vec![];
+
+        for (payload, coins) in txs {
+            for coin in coins.iter() {
+                tree.append(MerkleNode::from_coin(coin))?;
+            }
+
+            let slab = Slab::new(index, prev_hash, tree.root(), payload);
+            index += 1;
+            prev_hash = slab.hash();
+            slabs.push(slab);
+        }
+
+        Ok(slabs)
+    }
+
+    /// Store a slab whose transaction is invalid without applying it, so
+    /// the slabs after it can still be applied.
+    pub fn skip(&mut self, slab: Slab) -> Result<()> {
+        let mut batch = self.slabstore.batch();
+
+        let index = slab.get_index();
+        if self.slabstore.put_batch(&mut batch, slab)?.is_none() {
+            return Err(Error::SlabsStore(format!(
+                "Slab {} does not follow the last applied slab",
+                index
+            )));
+        }
+
+        let undo = UndoRecord {
+            nullifiers: vec![],
+            coins: vec![],
+            merkle_roots: vec![],
+            tree: self.tree.clone(),
+        };
+
+        self.undo.put_batch(&mut batch, index, undo)?;
+        self.saved_tree
+            .put_batch(&mut batch, Self::TREE_KEY, (index, self.tree.clone()))?;
+
+        batch.commit()
+    }
+
+    fn get_undo(&self, index: u64) -> Result<UndoRecord> {
+        match self.undo.get_value_deserialized(index)? {
+            Some(undo) => Ok(undo),
+            None => Err(Error::SlabsStore(format!(
+                "Missing undo record for slab {}",
+                index
+            ))),
+        }
+    }
+
+    // The last slab the wallet has been updated with, if it is kept
+    fn stored_wallet_index(&self) -> Result<Option<u64>> {
+        self.saved_tree.get_value_deserialized(Self::WALLET_KEY)
+    }
+
+    // Wallets from before the index was kept are in step with the state
+    fn wallet_index(&self) -> Result<u64> {
+        match self.stored_wallet_index()? {
+            Some(index) => Ok(index),
+            None => self.slabstore.get_last_index(),
+        }
+    }
+
+    // The slab a snapshot was loaded at, or 0. The slabs up to it have no
+    // undo records, and the coins they minted are not in the state.
+    fn snapshot_index(&self) -> Result<u64> {
+        match self.slabstore.iter_from(0)?.next() {
+            Some(slab) => {
+                let slab = slab?;
+                Ok(if slab.is_pruned() {
+                    slab.get_index()
+                } else {
+                    0
+                })
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Undo every applied slab after `index`, removing the slabs along with
+    /// the nullifiers and merkle roots they added and rewinding the tree.
+    /// With a wallet, the coins minted by those slabs are forgotten, the
+    /// coins they spent are unspent and every witness is rebuilt for the
+    /// rewound tree.
+    ///
+    /// The state is committed first, keeping the undo records the wallet
+    /// needs until the wallet is rolled back as well. A wallet left behind
+    /// by a failure is rolled back by the next rollback or applied slab.
+    pub fn rollback(&mut self, index: u64, wallet: Option<WalletPtr>) -> Result<()> {
+        let base = self.snapshot_index()?;
+        if index < base {
+            return Err(Error::SlabsStore(format!(
+                "Can not roll back past the snapshot at slab {}",
+                base
+            )));
+        }
+
+        // Refuse before anything is changed if the witnesses can't be rebuilt
+        if let Some(wallet) = &wallet {
+            self.first_own_coin_slab(index, wallet)?;
+        }
+
+        let wallet_index = match (&wallet, self.stored_wallet_index()?) {
+            (_, Some(wallet_index)) => Some(wallet_index),
+            (Some(_), None) => Some(self.wallet_index()?),
+            (None, None) => None,
+        };
+
+        let mut batch = self.slabstore.batch();
+        let mut tree = None;
+
+        let removed = self.slabstore.truncate_to_batch(&mut batch, index)?;
+
+        for i in removed.iter() {
+            let undo = self.get_undo(*i)?;
+
+            for nullifier in undo.nullifiers {
+                self.nullifiers.delete_batch(&mut batch, nullifier)?;
+            }
+            for coin in undo.coins {
+                self.coins.delete_batch(&mut batch, coin)?;
+            }
+            for root in undo.merkle_roots {
+                self.merkle_roots.delete_batch(&mut batch, root)?;
+            }
+            // The wallet rolls back the slabs it has seen with their undo records
+            let kept = matches!(wallet_index, Some(wallet_index) if *i <= wallet_index);
+            if !kept {
+                self.undo.delete_batch(&mut batch, *i)?;
+            }
+
+            // Slabs are removed newest first, so the last tree is the oldest
+            tree = Some(undo.tree);
+        }
+
+        if let Some(tree) = tree {
+            if let Some(wallet_index) = wallet_index {
+                self.saved_tree
+                    .put_batch(&mut batch, Self::WALLET_KEY, wallet_index)?;
+            }
+            self.saved_tree
+                .put_batch(&mut batch, Self::TREE_KEY, (index, tree.clone()))?;
+            batch.commit()?;
+            self.tree = tree;
+            info!(target: "CLIENT STATE", "Rolled back {} slabs to {}", removed.len(), index);
+        }
+
+        if let Some(wallet) = &wallet {
+            self.rollback_wallet(wallet)?;
+        }
+
+        Ok(())
+    }
+
+    // Roll the wallet back to the state from the undo records `rollback`
+    // kept. Coins can be unspent and forgotten again and the witnesses are
+    // rebuilt from scratch, so this can be run again after a failure.
+    fn rollback_wallet(&self, wallet: &WalletPtr) -> Result<()> {
+        let index = self.slabstore.get_last_index()?;
+        let wallet_index = self.wallet_index()?;
+        if wallet_index <= index {
+            return Ok(());
+        }
+
+        let mut batch = self.slabstore.batch();
+
+        for i in (index + 1)..=wallet_index {
+            let undo = self.get_undo(i)?;
+            for nullifier in undo.nullifiers.iter() {
+                wallet.unspend_coin(nullifier)?;
+            }
+            for coin in undo.coins.iter() {
+                wallet.forget_coin(coin)?;
+            }
+            self.undo.delete_batch(&mut batch, i)?;
+        }
+
+        self.rebuild_witnesses(index, wallet)?;
+
+        self.saved_tree
+            .put_batch(&mut batch, Self::WALLET_KEY, index)?;
+        batch.commit()?;
+        info!(target: "CLIENT STATE", "Rolled back the wallet to slab {}", index);
+
+        Ok(())
+    }
+
+    /// Bring the wallet in step with the state, after a run which stopped
+    /// between committing a slab or a rollback and updating the wallet.
+    /// The slabs the wallet missed are read from the store.
+    pub async fn sync_wallet(&self, secret_keys: &[jubjub::Fr], wallet: &WalletPtr) -> Result<()> {
+        self.rollback_wallet(wallet)?;
+
+        let index = self.slabstore.get_last_index()?;
+        let wallet_index = self.wallet_index()?;
+        if wallet_index >= index {
+            return Ok(());
+        }
+
+        info!(
+            target: "CLIENT STATE",
+            "Update the wallet with slabs {} to {}", wallet_index + 1, index
+        );
+
+        // A slab the wallet had started on may have been appended to some
+        // witnesses already, so they are rebuilt at the end
+        let mut rebuild = false;
+
+        for i in (wallet_index + 1)..=index {
+            let undo = self.get_undo(i)?;
+            if undo.coins.is_empty() {
+                continue;
+            }
+
+            let slab: Slab = match self.slabstore.get_value_deserialized(serialize(&i))? {
+                Some(slab) => slab,
+                None => return Err(Error::SlabsStore(format!("Missing slab {}", i))),
+            };
+            let tx = tx::Transaction::decode(&slab.get_payload()[..])?;
+
+            let update = StateUpdate {
+                tx_id: tx.id(),
+                nullifiers: undo.nullifiers,
+                coins: undo.coins,
+                enc_notes: tx
+                    .outputs
+                    .into_iter()
+                    .map(|output| output.enc_note)
+                    .collect(),
+            };
+            Self::update_wallet(undo.tree, &update, secret_keys, None, wallet).await?;
+            rebuild = true;
+        }
+
+        if rebuild {
+            self.rebuild_witnesses(index, wallet)?;
+        }
+
+        self.saved_tree.put(Self::WALLET_KEY, index)
+    }
+
+    // The slab minting the oldest unspent coin in the wallet, leaving out
+    // the coins minted after slab `index`. Witnesses are rebuilt from the
+    // undo records, which the slabs up to a snapshot do not have.
+    fn first_own_coin_slab(&self, index: u64, wallet: &WalletPtr) -> Result<Option<u64>> {
+        let base = self.snapshot_index()?;
+
+        let mut first = None;
+        for coin in wallet.get_witnesses()?.keys() {
+            let coin: Coin = deserialize(coin)?;
+            match self.get_coin_slab(&coin)? {
+                Some(slab) if slab > index => {}
+                Some(slab) if slab > base => {
+                    first = Some(first.map_or(slab, |first: u64| first.min(slab)))
+                }
+                _ if base > 0 => {
+                    return Err(Error::SlabsStore(format!(
+                        "Coin {} in the wallet was minted by slab {} or before, which \
+                         the state only has as a snapshot. Its witness can not be \
+                         rebuilt, sync the state from the first slab instead.",
+                        hex::encode(coin.repr),
+                        base
+                    )))
+                }
+                _ => {
+                    return Err(Error::SlabsStore(format!(
+                        "Coin {} in the wallet was not minted by any slab",
+                        hex::encode(coin.repr)
+                    )))
+                }
+            }
+        }
+
+        Ok(first)
+    }
+
+    // Witnesses of the unspent coins in the wallet as of slab `index`. The
+    // tree is rebuilt from the slab minting the oldest of them, appending
+    // the coins of every slab after it from their undo records.
+    fn rebuild_witnesses(&self, index: u64, wallet: &WalletPtr) -> Result<()> {
+        let first = match self.first_own_coin_slab(index, wallet)? {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+
+        let own_coins = wallet.get_witnesses()?;
+        let mut tree = self.get_undo(first)?.tree;
+        let mut witnesses: HashMap<Vec<u8>, IncrementalWitness<MerkleNode>> = HashMap::new();
+
+        for i in first..=index {
+            for coin in self.get_undo(i)?.coins {
+                let node = MerkleNode::from_coin(&coin);
+                tree.append(node)?;
+
+                for witness in witnesses.values_mut() {
+                    witness.append(node)?;
+                }
+
+                let coin = serialize(&coin);
+                if own_coins.contains_key(&coin) {
+                    witnesses.insert(coin, IncrementalWitness::from_tree(&tree));
+                }
+            }
+        }
+
+        for (coin, witness) in witnesses {
+            wallet.update_witness(&coin, witness)?;
+        }
+
+        Ok(())
+    }
+
+    /// Apply a verified slab. Without a wallet only the state is updated,
+    /// as on the gateway.
     pub async fn apply(
         &mut self,
         update: StateUpdate,
+        slab: Slab,
         secret_keys: Vec<jubjub::Fr>,
-        notify: Option<async_channel::Sender<(jubjub::SubgroupPoint, u64)>>,
-        wallet: WalletPtr,
+        notify: Option<async_channel::Sender<(Address, u64)>>,
+        wallet: Option<WalletPtr>,
     ) -> Result<()> {
+        // The undo records of a rollback the wallet has not caught up with
+        // are needed before this slab replaces one of them
+        if let Some(wallet) = &wallet {
+            self.rollback_wallet(wallet)?;
+        }
+        let wallet_index = self.wallet_index()?;
+
+        // Everything this slab changes in the state is committed at once,
+        // so a crash never leaves the slabs, nullifiers and merkle roots out
+        // of step with each other. The wallet is updated after.
+        let mut batch = self.slabstore.batch();
+
+        let index = slab.get_index();
+        if self.slabstore.put_batch(&mut batch, slab)?.is_none() {
+            return Err(Error::SlabsStore(format!(
+                "Slab {} does not follow the last applied slab",
+                index
+            )));
+        }
+
+        let tree = self.tree.clone();
+        let mut undo = UndoRecord {
+            nullifiers: update.nullifiers.clone(),
+            coins: update.coins.clone(),
+            merkle_roots: vec![],
+            tree: tree.clone(),
+        };
+
         // Extend our list of nullifiers with the ones from the update
 
         debug!(target: "CLIENT STATE", "Extend nullifiers");
-        for nullifier in update.nullifiers {
-            self.nullifiers.put(nullifier, vec![] as Vec<u8>)?;
+        for nullifier in update.nullifiers.iter() {
+            self.nullifiers
+                .put_batch(&mut batch, nullifier.clone(), index)?;
         }
 
-        debug!(target: "CLIENT STATE", "Update merkle tree and witness ");
-        // Update merkle tree and witnesses
-        for (coin, enc_note) in update.coins.into_iter().zip(update.enc_notes.iter()) {
+        debug!(target: "CLIENT STATE", "Update merkle tree");
+        for coin in update.coins.iter() {
+            // Remember which slab minted the coin
+            self.coins.put_batch(&mut batch, coin.clone(), index)?;
+
             // Add the new coins to the merkle tree
-            let node = MerkleNode::from_coin(&coin);
+            let node = MerkleNode::from_coin(coin);
             self.tree.append(node).expect("Append to merkle tree");
 
             debug!(target: "CLIENT STATE", "Keep track of all merkle roots");
 
             // Keep track of all merkle roots that have existed
-            self.merkle_roots.put(self.tree.root(), vec![] as Vec<u8>)?;
+            self.merkle_roots
+                .put_batch(&mut batch, self.tree.root(), vec![] as Vec<u8>)?;
+            undo.merkle_roots.push(self.tree.root());
+        }
 
-            debug!(target: "CLIENT STATE", "Update witness");
+        self.undo.put_batch(&mut batch, index, undo)?;
+        self.saved_tree
+            .put_batch(&mut batch, Self::TREE_KEY, (index, self.tree.clone()))?;
+
+        debug!(target: "CLIENT STATE", "Commit slab {}", index);
+        batch.commit()?;
+
+        let wallet = match wallet {
+            Some(wallet) => wallet,
+            None => return Ok(()),
+        };
+
+        if wallet_index + 1 < index {
+            // An earlier slab did not make it into the wallet
+            return self.sync_wallet(&secret_keys, &wallet).await;
+        }
+
+        Self::update_wallet(tree, &update, &secret_keys, notify, &wallet).await?;
+        self.saved_tree.put(Self::WALLET_KEY, index)
+    }
+
+    // Add the coins and notes of a committed slab to the wallet, given the
+    // tree before the slab. Coins and notes are put by key, so they can be
+    // put again, but the witnesses only move on if the wallet had not seen
+    // the slab yet.
+    async fn update_wallet(
+        mut tree: CommitmentTree<MerkleNode>,
+        update: &StateUpdate,
+        secret_keys: &[jubjub::Fr],
+        notify: Option<async_channel::Sender<(Address, u64)>>,
+        wallet: &WalletPtr,
+    ) -> Result<()> {
+        // Notes are encrypted to the viewing keys of the secret keys, and
+        // the wallet may hold view-only keys as well. Each key decrypts the
+        // notes of the slab in one batch.
+        let received: Vec<Vec<Option<Note>>> = secret_keys
+            .iter()
+            .map(|secret| {
+                EncryptedNote::decrypt_batch(&update.enc_notes, &ViewingKey::from_secret(secret))
+            })
+            .collect();
+        // Notes we sent are found with the outgoing viewing keys, except
+        // for change which comes back to one of our own addresses
+        let out_keys: Vec<OutgoingViewingKey> = secret_keys
+            .iter()
+            .map(OutgoingViewingKey::from_secret)
+            .collect();
+        let own_addresses: Vec<Address> = secret_keys.iter().map(Address::from_secret).collect();
+        let watch_keys = wallet.get_view_keys()?;
+        let viewed: Vec<Vec<Option<Note>>> = watch_keys
+            .iter()
+            .map(|view_key| EncryptedNote::decrypt_batch(&update.enc_notes, view_key))
+            .collect();
+
+        debug!(target: "CLIENT STATE", "Update witness");
+        for (i, (coin, enc_note)) in update.coins.iter().zip(update.enc_notes.iter()).enumerate() {
+            let node = MerkleNode::from_coin(coin);
+            tree.append(node).expect("Append to merkle tree");
 
             // Also update all the coin witnesses
             for (coin, witness) in wallet.get_witnesses()?.iter_mut() {
@@ -461,8 +1516,8 @@ impl State {
 
             debug!(target: "CLIENT STATE", "iterate over secret_keys to decrypt note");
 
-            for secret in secret_keys.iter() {
-                if let Some(note) = Self::try_decrypt_note(enc_note, *secret) {
+            for (secret, notes) in secret_keys.iter().zip(received.iter()) {
+                if let Some(note) = &notes[i] {
                     // We need to keep track of the witness for this coin.
                     // This allows us to prove inclusion of the coin in the merkle tree with ZK.
                     // Just as we update the merkle tree with every new coin, so we do the same with
@@ -473,7 +1528,7 @@ impl State {
                     // coins are added)
 
                     // Make a new witness for this coin
-                    let witness = IncrementalWitness::from_tree(&self.tree);
+                    let witness = IncrementalWitness::from_tree(&tree);
 
                     let mut nullifier = [0; 32];
                     nullifier.copy_from_slice(
@@ -498,28 +1553,52 @@ impl State {
                     };
 
                     wallet.put_own_coins(own_coin)?;
-                    let pub_key = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
 
                     debug!(target: "CLIENT STATE", "Received a coin: amount {} ", note.value);
 
                     debug!(target: "CLIENT STATE", "Send a notification");
 
                     if let Some(ch) = notify.clone() {
-                        ch.send((pub_key, note.value)).await?
+                        ch.send((Address::from_secret(secret), note.value)).await?
                     }
                 }
             }
-        }
-        Ok(())
-    }
 
-    fn try_decrypt_note(ciphertext: &EncryptedNote, secret: jubjub::Fr) -> Option<Note> {
-        match ciphertext.decrypt(&secret) {
-            // ... and return the decrypted note for this coin.
-            Ok(note) => Some(note),
-            // We weren't able to decrypt the note with our key.
-            Err(_) => None,
+            // Coins of view-only keys are kept for their notes, they can't be spent
+            for (view_key, notes) in watch_keys.iter().zip(viewed.iter()) {
+                if let Some(note) = &notes[i] {
+                    debug!(target: "CLIENT STATE", "Viewed a coin: amount {} ", note.value);
+
+                    wallet.put_viewed_note(&ViewedNote {
+                        coin: coin.clone(),
+                        view_public: view_key.public(),
+                        note: note.clone(),
+                    })?;
+                }
+            }
+
+            // Recover our own sends, so the history survives a restore.
+            // Sends made by this wallet are already recorded the same way.
+            for ovk in out_keys.iter() {
+                if let Ok((recipient, note)) = enc_note.decrypt_outgoing(ovk) {
+                    if !own_addresses.contains(&recipient) {
+                        debug!(target: "CLIENT STATE", "Sent a coin: amount {} ", note.value);
+
+                        wallet.put_transaction(&SentTransaction {
+                            tx_id: update.tx_id,
+                            coin: coin.clone(),
+                            token_id: note.token_id,
+                            value: note.value,
+                            recipient,
+                            memo: note.memo,
+                        })?;
+                    }
+                    break;
+                }
+            }
         }
+
+        Ok(())
     }
 }
 
@@ -548,6 +1627,10 @@ impl std::fmt::Display for ClientFailed {
             ClientFailed::EmptyPassword => f.write_str("Password is empty. Cannot create database"),
             ClientFailed::WalletInitialized => f.write_str("Wallet already initalized"),
             ClientFailed::KeyExists => f.write_str("Keypair already exists"),
+            ClientFailed::NoSeed => f.write_str("Wallet has no seed to derive keys from"),
+            ClientFailed::InvalidMnemonic(i) => {
+                write!(f, "Invalid mnemonic phrase: {}", i)
+            }
 
             ClientFailed::ClientError(i) => {
                 write!(f, "{}", i)
@@ -573,3 +1656,80 @@ impl From<crate::state::VerifyFailed> for ClientFailed {
 }
 
 pub type ClientResult<T> = std::result::Result<T, ClientFailed>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ff::{Field, PrimeField};
+    use rand::rngs::OsRng;
+    use std::path::PathBuf;
+
+    use crate::blockchain::MemStore;
+    use crate::crypto::{setup_mint_prover, setup_spend_prover};
+    use crate::util::join_config_path;
+    use crate::wallet::WalletDb;
+
+    #[test]
+    fn test_rollback_refuses_coins_before_snapshot() -> Result<()> {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+        let new_state = || {
+            State::new(
+                MemStore::new(),
+                PreparedKey::new(&mint_params.vk),
+                PreparedKey::new(&spend_params.vk),
+                vec![],
+            )
+        };
+
+        // A gateway with two slabs, whose snapshot at the second starts the client
+        let mut gateway = new_state()?;
+        for i in 1..=2u8 {
+            let slab = gateway.next_slabs(vec![(vec![i], &[])])?.remove(0);
+            gateway.skip(slab)?;
+        }
+        let mut state = new_state()?;
+        state.load_snapshot(gateway.snapshot(2)?)?;
+        let slab = state.next_slabs(vec![(vec![3], &[])])?.remove(0);
+        state.skip(slab)?;
+
+        // A coin the state does not know about, as if it was minted before the snapshot
+        let walletdb_path = join_config_path(&PathBuf::from("test_rollback_wallet.db"))?;
+        let wallet = WalletDb::new(&walletdb_path, "darkfi".into())?;
+        async_std::task::block_on(wallet.init_db())?;
+
+        let coin = Coin::new(bls12_381::Scalar::random(&mut OsRng).to_repr());
+        let mut tree = CommitmentTree::empty();
+        tree.append(MerkleNode::from_coin(&coin))?;
+        wallet.put_own_coins(OwnCoin {
+            coin: coin.clone(),
+            note: Note {
+                serial: jubjub::Fr::random(&mut OsRng),
+                value: 110,
+                token_id: jubjub::Fr::random(&mut OsRng),
+                coin_blind: jubjub::Fr::random(&mut OsRng),
+                valcom_blind: jubjub::Fr::random(&mut OsRng),
+                memo: Memo::default(),
+            },
+            secret: jubjub::Fr::random(&mut OsRng),
+            witness: IncrementalWitness::from_tree(&tree),
+            nullifier: Nullifier::new(coin.repr),
+        })?;
+
+        assert!(state.rollback(1, None).is_err());
+
+        // Its witness can't be rebuilt, so nothing is rolled back
+        let err = state.rollback(2, Some(wallet.clone())).unwrap_err();
+        assert!(err.to_string().contains("only has as a snapshot"));
+        assert_eq!(state.slabstore.get_last_index()?, 3);
+
+        wallet.forget_coin(&coin)?;
+        state.rollback(2, Some(wallet))?;
+        assert_eq!(state.slabstore.get_last_index()?, 2);
+
+        std::fs::remove_file(walletdb_path)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use bellman::groth16;
+use bls12_381::{multi_miller_loop, Bls12, G1Affine, G1Projective, G2Prepared, Gt, Scalar};
+use group::Curve;
+use rand::rngs::OsRng;
+use rand_core::RngCore;
+
+/// Verifying key of a circuit, prepared both for checking proofs one at a
+/// time and for checking many of them together with `BatchVerifier`.
+pub struct PreparedKey {
+    pvk: groth16::PreparedVerifyingKey<Bls12>,
+    alpha_g1_beta_g2: Gt,
+    neg_gamma_g2: G2Prepared,
+    neg_delta_g2: G2Prepared,
+    ic: Vec<G1Affine>,
+}
+
+impl PreparedKey {
+    pub fn new(vk: &groth16::VerifyingKey<Bls12>) -> Self {
+        Self {
+            pvk: groth16::prepare_verifying_key(vk),
+            alpha_g1_beta_g2: bls12_381::pairing(&vk.alpha_g1, &vk.beta_g2),
+            neg_gamma_g2: (-vk.gamma_g2).into(),
+            neg_delta_g2: (-vk.delta_g2).into(),
+            ic: vk.ic.clone(),
+        }
+    }
+
+    /// The key as bellman prepares it, for checking a single proof.
+    pub fn pvk(&self) -> &groth16::PreparedVerifyingKey<Bls12> {
+        &self.pvk
+    }
+}
+
+/// Collects Groth16 proofs, possibly for different circuits, and checks
+/// them all with a single multi-pairing.
+///
+/// Each proof's equation is multiplied by a random scalar before they are
+/// added up, so one invalid proof can not be cancelled out by another. A
+/// failed batch does not say which proof is invalid: callers check them
+/// one at a time to find out.
+#[derive(Default)]
+pub struct BatchVerifier<'a> {
+    items: Vec<BatchItem<'a>>,
+}
+
+struct BatchItem<'a> {
+    key: &'a PreparedKey,
+    proof: &'a groth16::Proof<Bls12>,
+    public_input: Vec<Scalar>,
+}
+
+impl<'a> BatchVerifier<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn queue(
+        &mut self,
+        key: &'a PreparedKey,
+        proof: &'a groth16::Proof<Bls12>,
+        public_input: &[Scalar],
+    ) {
+        self.items.push(BatchItem {
+            key,
+            proof,
+            public_input: public_input.to_vec(),
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Check every queued proof at once. True if they are all valid.
+    pub fn verify(&self) -> bool {
+        // For every proof with randomizer r:
+        //   r * (A * B + inputs * (-gamma) + C * (-delta)) = r * alpha * beta
+        // The gamma and delta terms of proofs sharing a key are summed in G1
+        // first, so each key only adds two pairings to the miller loop.
+        let mut keys: Vec<KeyTerms> = vec![];
+        let mut ab = vec![];
+
+        for item in self.items.iter() {
+            if item.public_input.len() + 1 != item.key.ic.len() {
+                return false;
+            }
+
+            let r = Scalar::from_raw([OsRng.next_u64(), OsRng.next_u64(), 0, 0]);
+
+            let mut acc = G1Projective::from(item.key.ic[0]);
+            for (input, ic) in item.public_input.iter().zip(item.key.ic.iter().skip(1)) {
+                acc += ic * input;
+            }
+
+            let terms = match keys.iter_mut().find(|t| std::ptr::eq(t.key, item.key)) {
+                Some(terms) => terms,
+                None => {
+                    keys.push(KeyTerms::new(item.key));
+                    keys.last_mut().unwrap()
+                }
+            };
+            terms.inputs += acc * r;
+            terms.c += item.proof.c * r;
+            terms.r_sum += r;
+
+            ab.push((
+                (item.proof.a * r).to_affine(),
+                G2Prepared::from(item.proof.b),
+            ));
+        }
+
+        let mut rhs = Gt::identity();
+        let mut sums = vec![];
+        for terms in keys.iter() {
+            rhs += terms.key.alpha_g1_beta_g2 * terms.r_sum;
+            sums.push((terms.inputs.to_affine(), terms.c.to_affine()));
+        }
+
+        let mut pairs: Vec<(&G1Affine, &G2Prepared)> = ab.iter().map(|(a, b)| (a, b)).collect();
+        for (terms, (inputs, c)) in keys.iter().zip(sums.iter()) {
+            pairs.push((inputs, &terms.key.neg_gamma_g2));
+            pairs.push((c, &terms.key.neg_delta_g2));
+        }
+
+        multi_miller_loop(&pairs).final_exponentiation() == rhs
+    }
+}
+
+// Sums over every proof in the batch that uses the same key
+struct KeyTerms<'a> {
+    key: &'a PreparedKey,
+    inputs: G1Projective,
+    c: G1Projective,
+    r_sum: Scalar,
+}
+
+impl<'a> KeyTerms<'a> {
+    fn new(key: &'a PreparedKey) -> Self {
+        Self {
+            key,
+            inputs: G1Projective::identity(),
+            c: G1Projective::identity(),
+            r_sum: Scalar::zero(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ff::Field;
+
+    use crate::crypto::{create_mint_proof, keys::Address, setup_mint_prover, setup_spend_prover};
+    use crate::state::VerifyFailed;
+    use crate::tx;
+
+    #[test]
+    fn test_batch_verify_mint_proofs() {
+        let params = setup_mint_prover();
+        let key = PreparedKey::new(&params.vk);
+
+        let public =
+            zcash_primitives::constants::SPENDING_KEY_GENERATOR * jubjub::Fr::random(&mut OsRng);
+        let proofs: Vec<_> = (0..3u64)
+            .map(|value| {
+                create_mint_proof(
+                    &params,
+                    value,
+                    jubjub::Fr::random(&mut OsRng),
+                    jubjub::Fr::random(&mut OsRng),
+                    jubjub::Fr::random(&mut OsRng),
+                    jubjub::Fr::random(&mut OsRng),
+                    public,
+                )
+            })
+            .collect();
+
+        let mut batch = BatchVerifier::new();
+        for (proof, revealed) in proofs.iter() {
+            batch.queue(&key, proof, &revealed.make_outputs());
+        }
+        assert_eq!(batch.len(), 3);
+        assert!(batch.verify());
+
+        // One proof checked against another's public values spoils the batch
+        let mut batch = BatchVerifier::new();
+        batch.queue(&key, &proofs[0].0, &proofs[0].1.make_outputs());
+        batch.queue(&key, &proofs[1].0, &proofs[2].1.make_outputs());
+        batch.queue(&key, &proofs[2].0, &proofs[2].1.make_outputs());
+        assert!(!batch.verify());
+
+        assert!(BatchVerifier::new().verify());
+    }
+
+    #[test]
+    fn test_verify_proofs_batch_fallback() {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+        let mint_pvk = PreparedKey::new(&mint_params.vk);
+        let spend_pvk = PreparedKey::new(&spend_params.vk);
+
+        let secret = jubjub::Fr::random(&mut OsRng);
+        let address = Address::from_secret(&secret);
+
+        let build_tx = || {
+            let token_id = jubjub::Fr::random(&mut OsRng);
+            let builder = tx::TransactionBuilder::new(
+                vec![tx::TransactionBuilderClearInputInfo {
+                    value: 110,
+                    token_id,
+                    signature_secret: secret,
+                }],
+                vec![],
+                vec![tx::TransactionBuilderOutputInfo {
+                    value: 110,
+                    token_id,
+                    address,
+                    memo: Default::default(),
+                }],
+            );
+            builder.build(&mint_params, &spend_params)
+        };
+
+        let txs = vec![build_tx(), build_tx(), build_tx()];
+        let txs_ref: Vec<&tx::Transaction> = txs.iter().collect();
+        let results = tx::verify_proofs_batch(&txs_ref, &mint_pvk, &spend_pvk);
+        assert!(results.iter().all(|result| result.is_ok()));
+
+        // The coin of the second tx no longer matches its mint proof
+        let mut txs = txs;
+        txs[1].outputs[0].revealed.coin = [0u8; 32];
+        let txs_ref: Vec<&tx::Transaction> = txs.iter().collect();
+        let results = tx::verify_proofs_batch(&txs_ref, &mint_pvk, &spend_pvk);
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(VerifyFailed::MintProof(0))));
+        assert!(results[2].is_ok());
+    }
+}
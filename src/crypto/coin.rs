@@ -2,7 +2,8 @@ use std::io;
 
 use crate::{
     error::Result,
-    serial::{Decodable, Encodable},
+    impl_vec,
+    serial::{Decodable, Encodable, VarInt},
 };
 
 #[derive(Clone, Debug, PartialEq)]
@@ -30,3 +31,4 @@ impl Decodable for Coin {
     }
 }
 
+impl_vec!(Coin);
@@ -26,11 +26,17 @@ pub fn sapling_ka_agree(esk: &jubjub::Fr, pk_d: &jubjub::ExtendedPoint) -> jubju
 ///
 /// Implements section 5.4.4.4 of the Zcash Protocol Specification.
 pub fn kdf_sapling(dhsecret: jubjub::SubgroupPoint, epk: &jubjub::ExtendedPoint) -> Blake2bHash {
+    kdf_sapling_encoded(&dhsecret.to_bytes(), &epk.to_bytes())
+}
+
+/// Sapling KDF given the encodings of the points, for callers which encode
+/// many points at once.
+pub fn kdf_sapling_encoded(dhsecret: &[u8; 32], epk: &[u8; 32]) -> Blake2bHash {
     Blake2bParams::new()
         .hash_length(32)
         .personal(KDF_SAPLING_PERSONALIZATION)
         .to_state()
-        .update(&dhsecret.to_bytes())
-        .update(&epk.to_bytes())
+        .update(dhsecret)
+        .update(epk)
         .finalize()
 }
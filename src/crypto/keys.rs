@@ -0,0 +1,228 @@
+use std::io;
+
+use blake2b_simd::Params;
+
+use crate::crypto::util::hash_to_scalar;
+use crate::error::Result;
+use crate::serial::{Decodable, Encodable};
+
+pub const VIEWING_KEY_PERSONALIZATION: &[u8; 16] = b"DarkFiViewingKey";
+pub const OUTGOING_VIEWING_KEY_PERSONALIZATION: &[u8; 16] = b"DarkFiOutViewKey";
+pub const HD_MASTER_PERSONALIZATION: &[u8; 16] = b"DarkFi_HD_Master";
+pub const HD_CHILD_PERSONALIZATION: &[u8; 16] = b"DarkFi_HD_Child_";
+pub const HD_SECRET_PERSONALIZATION: &[u8; 16] = b"DarkFi_HD_Secret";
+
+/// First index on the path to account keys, m / PURPOSE / account, so
+/// other kinds of keys can later be derived from the same seed.
+pub const HD_ACCOUNT_PURPOSE: u32 = 32;
+
+/// Incoming viewing key. It decrypts the notes of coins sent to an address,
+/// so it shows what the address received, but it can not spend them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ViewingKey(pub jubjub::Fr);
+
+impl ViewingKey {
+    /// Derive the viewing key of a spending key.
+    pub fn from_secret(secret: &jubjub::Fr) -> Self {
+        Self(hash_to_scalar(
+            VIEWING_KEY_PERSONALIZATION,
+            &secret.to_bytes(),
+            &[],
+        ))
+    }
+
+    /// The key notes are encrypted to
+    pub fn public(&self) -> jubjub::SubgroupPoint {
+        zcash_primitives::constants::SPENDING_KEY_GENERATOR * self.0
+    }
+}
+
+impl Encodable for ViewingKey {
+    fn encode<S: io::Write>(&self, s: S) -> Result<usize> {
+        self.0.encode(s)
+    }
+}
+
+impl Decodable for ViewingKey {
+    fn decode<D: io::Read>(d: D) -> Result<Self> {
+        Ok(Self(Decodable::decode(d)?))
+    }
+}
+
+/// Outgoing viewing key. Notes the sender encrypts are also encrypted to
+/// it, so the sender can recover what it sent from the slabs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutgoingViewingKey(pub [u8; 32]);
+
+impl OutgoingViewingKey {
+    /// Derive the outgoing viewing key of a spending key.
+    pub fn from_secret(secret: &jubjub::Fr) -> Self {
+        let hash = Params::new()
+            .hash_length(32)
+            .personal(OUTGOING_VIEWING_KEY_PERSONALIZATION)
+            .hash(&secret.to_bytes());
+        let mut ovk = [0u8; 32];
+        ovk.copy_from_slice(hash.as_bytes());
+        Self(ovk)
+    }
+}
+
+/// Where coins are sent. Coins belong to the spending public key, and
+/// their notes are encrypted to the viewing public key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Address {
+    pub public: jubjub::SubgroupPoint,
+    pub view_public: jubjub::SubgroupPoint,
+}
+
+impl Address {
+    /// The address of a spending key.
+    pub fn from_secret(secret: &jubjub::Fr) -> Self {
+        Self {
+            public: zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret,
+            view_public: ViewingKey::from_secret(secret).public(),
+        }
+    }
+}
+
+impl Encodable for Address {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.public.encode(&mut s)?;
+        len += self.view_public.encode(&mut s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for Address {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            public: Decodable::decode(&mut d)?,
+            view_public: Decodable::decode(d)?,
+        })
+    }
+}
+
+/// Spending key with a chain code, derived from a seed the way ZIP-32
+/// derives Sapling keys. Only hardened derivation is supported, so a child
+/// key can not be computed from the public key of its parent.
+#[derive(Clone)]
+pub struct ExtendedSecret {
+    pub secret: jubjub::Fr,
+    pub chain_code: [u8; 32],
+}
+
+impl ExtendedSecret {
+    /// The root key of a seed, such as the one of a mnemonic phrase.
+    pub fn master(seed: &[u8]) -> Self {
+        let hash = Params::new()
+            .hash_length(64)
+            .personal(HD_MASTER_PERSONALIZATION)
+            .hash(seed);
+        Self::from_hash(hash.as_array())
+    }
+
+    pub fn child(&self, index: u32) -> Self {
+        let hash = Params::new()
+            .hash_length(64)
+            .personal(HD_CHILD_PERSONALIZATION)
+            .key(&self.chain_code)
+            .to_state()
+            .update(&self.secret.to_bytes())
+            .update(&index.to_le_bytes())
+            .finalize();
+        Self::from_hash(hash.as_array())
+    }
+
+    /// Spending key of an account, the viewing key and address follow
+    /// from it.
+    pub fn account(seed: &[u8], account: u32) -> Self {
+        Self::master(seed).child(HD_ACCOUNT_PURPOSE).child(account)
+    }
+
+    // The left half becomes the key and the right half the chain code
+    fn from_hash(hash: &[u8; 64]) -> Self {
+        let mut chain_code = [0u8; 32];
+        chain_code.copy_from_slice(&hash[32..]);
+        Self {
+            secret: hash_to_scalar(HD_SECRET_PERSONALIZATION, &hash[..32], &[]),
+            chain_code,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bip39::{Language, Mnemonic, Seed};
+    use ff::Field;
+    use rand::rngs::OsRng;
+
+    use crate::crypto::note::{Memo, Note};
+
+    #[test]
+    fn test_viewing_key_decrypts_without_spending_key() {
+        let secret = jubjub::Fr::random(&mut OsRng);
+        let address = Address::from_secret(&secret);
+        let view_key = ViewingKey::from_secret(&secret);
+
+        assert_eq!(view_key.public(), address.view_public);
+        assert_ne!(address.public, address.view_public);
+
+        let note = Note {
+            serial: jubjub::Fr::random(&mut OsRng),
+            value: 110,
+            token_id: jubjub::Fr::random(&mut OsRng),
+            coin_blind: jubjub::Fr::random(&mut OsRng),
+            valcom_blind: jubjub::Fr::random(&mut OsRng),
+            memo: Memo::default(),
+        };
+
+        let encrypted_note = note
+            .encrypt(&address, &OutgoingViewingKey::from_secret(&secret))
+            .unwrap();
+        assert_eq!(encrypted_note.decrypt(&view_key).unwrap().value, 110);
+
+        // The spending key alone is not a viewing key
+        assert!(encrypted_note.decrypt(&ViewingKey(secret)).is_err());
+    }
+
+    #[test]
+    fn test_account_keys_derive_from_seed() {
+        let seed = [7u8; 64];
+
+        let account = ExtendedSecret::account(&seed, 0);
+        assert_eq!(account.secret, ExtendedSecret::account(&seed, 0).secret);
+        assert_eq!(
+            Address::from_secret(&account.secret),
+            Address::from_secret(&ExtendedSecret::account(&seed, 0).secret)
+        );
+
+        assert_ne!(account.secret, ExtendedSecret::account(&seed, 1).secret);
+        assert_ne!(
+            account.secret,
+            ExtendedSecret::account(&[8u8; 64], 0).secret
+        );
+    }
+
+    #[test]
+    fn test_account_keys_known_answers() {
+        // Keys restored from a mnemonic must never change between releases
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon \
+                      abandon abandon abandon abandon abandon abandon abandon abandon \
+                      abandon abandon abandon abandon abandon abandon abandon art";
+        let mnemonic = Mnemonic::from_phrase(phrase, Language::English).unwrap();
+        let seed = Seed::new(&mnemonic, "");
+        let account = |i| ExtendedSecret::account(seed.as_bytes(), i).secret;
+
+        assert_eq!(
+            hex::encode(account(0).to_bytes()),
+            "446232a2bf357677bdb47915de53b43903cf01bea712895268bb7998ddc46701"
+        );
+        assert_eq!(
+            hex::encode(account(1).to_bytes()),
+            "678e699640692c9774b735b3b8eea3414c13399a2745d19e1bfb8180d1f36b0a"
+        );
+    }
+}
@@ -7,13 +7,13 @@ use rand::rngs::OsRng;
 use std::io;
 use std::time::Instant;
 
+use super::util::value_commit;
 use crate::circuit::mint_contract::MintContract;
 use crate::error::Result;
 use crate::serial::{Decodable, Encodable};
 
 pub struct MintRevealedValues {
     pub value_commit: jubjub::SubgroupPoint,
-    pub token_commit: jubjub::SubgroupPoint,
     pub coin: [u8; 32],
 }
 
@@ -22,20 +22,11 @@ impl MintRevealedValues {
         value: u64,
         token_id: jubjub::Fr,
         randomness_value: &jubjub::Fr,
-        randomness_token: &jubjub::Fr,
         serial: &jubjub::Fr,
         randomness_coin: &jubjub::Fr,
         public: &jubjub::SubgroupPoint,
     ) -> Self {
-        let value_commit = (zcash_primitives::constants::VALUE_COMMITMENT_VALUE_GENERATOR
-            * jubjub::Fr::from(value))
-            + (zcash_primitives::constants::VALUE_COMMITMENT_RANDOMNESS_GENERATOR
-                * randomness_value);
-
-        let token_commit = (zcash_primitives::constants::VALUE_COMMITMENT_VALUE_GENERATOR
-            * token_id)
-            + (zcash_primitives::constants::VALUE_COMMITMENT_RANDOMNESS_GENERATOR
-                * randomness_token);
+        let value_commit = value_commit(value, &token_id, randomness_value);
 
         let mut coin = [0; 32];
         coin.copy_from_slice(
@@ -52,15 +43,11 @@ impl MintRevealedValues {
                 .as_bytes(),
         );
 
-        MintRevealedValues {
-            value_commit,
-            token_commit,
-            coin,
-        }
+        MintRevealedValues { value_commit, coin }
     }
 
-    fn make_outputs(&self) -> [bls12_381::Scalar; 6] {
-        let mut public_input = [bls12_381::Scalar::zero(); 6];
+    pub(crate) fn make_outputs(&self) -> [bls12_381::Scalar; 4] {
+        let mut public_input = [bls12_381::Scalar::zero(); 4];
 
         {
             let result = jubjub::ExtendedPoint::from(self.value_commit);
@@ -72,15 +59,6 @@ impl MintRevealedValues {
             public_input[1] = v;
         }
 
-        {
-            let result = jubjub::ExtendedPoint::from(self.token_commit);
-            let affine = result.to_affine();
-            let u = affine.get_u();
-            let v = affine.get_v();
-            public_input[2] = u;
-            public_input[3] = v;
-        }
-
         {
             // Pack the hash as inputs for proof verification.
             let hash = multipack::bytes_to_bits_le(&self.coin);
@@ -89,8 +67,8 @@ impl MintRevealedValues {
             // There are 2 chunks for a blake hash
             assert_eq!(hash.len(), 2);
 
-            public_input[4] = hash[0];
-            public_input[5] = hash[1];
+            public_input[2] = hash[0];
+            public_input[3] = hash[1];
         }
 
         public_input
@@ -101,7 +79,6 @@ impl Encodable for MintRevealedValues {
     fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
         let mut len = 0;
         len += self.value_commit.encode(&mut s)?;
-        len += self.token_commit.encode(&mut s)?;
         len += self.coin.encode(&mut s)?;
         Ok(len)
     }
@@ -111,7 +88,6 @@ impl Decodable for MintRevealedValues {
     fn decode<D: io::Read>(mut d: D) -> Result<Self> {
         Ok(Self {
             value_commit: Decodable::decode(&mut d)?,
-            token_commit: Decodable::decode(&mut d)?,
             coin: Decodable::decode(d)?,
         })
     }
@@ -125,7 +101,6 @@ pub fn setup_mint_prover() -> groth16::Parameters<Bls12> {
             value: None,
             token_id: None,
             randomness_value: None,
-            randomness_token: None,
             serial: None,
             randomness_coin: None,
             public: None,
@@ -142,7 +117,6 @@ pub fn create_mint_proof(
     value: u64,
     token_id: jubjub::Fr,
     randomness_value: jubjub::Fr,
-    randomness_token: jubjub::Fr,
     serial: jubjub::Fr,
     randomness_coin: jubjub::Fr,
     public: jubjub::SubgroupPoint,
@@ -151,7 +125,6 @@ pub fn create_mint_proof(
         value,
         token_id,
         &randomness_value,
-        &randomness_token,
         &serial,
         &randomness_coin,
         &public,
@@ -161,7 +134,6 @@ pub fn create_mint_proof(
         value: Some(value),
         token_id: Some(token_id),
         randomness_value: Some(randomness_value),
-        randomness_token: Some(randomness_token),
         serial: Some(serial),
         randomness_coin: Some(randomness_coin),
         public: Some(public),
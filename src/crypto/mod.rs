@@ -1,6 +1,8 @@
+pub mod batch_verifier;
 pub mod coin;
 pub mod diffie_hellman;
 pub mod fr_serial;
+pub mod keys;
 pub mod merkle;
 pub mod merkle_node;
 pub mod mint_proof;
@@ -14,6 +16,7 @@ use bellman::groth16;
 use bls12_381::Bls12;
 
 use crate::error::Result;
+pub use batch_verifier::{BatchVerifier, PreparedKey};
 pub use mint_proof::{create_mint_proof, setup_mint_prover, verify_mint_proof, MintRevealedValues};
 pub use spend_proof::{
     create_spend_proof, setup_spend_prover, verify_spend_proof, SpendRevealedValues,
@@ -36,14 +39,9 @@ pub fn save_params(filename: &str, params: &groth16::Parameters<Bls12>) -> Resul
     Ok(())
 }
 
-pub fn load_params(
-    filename: &str,
-) -> Result<(
-    groth16::Parameters<Bls12>,
-    groth16::PreparedVerifyingKey<Bls12>,
-)> {
+pub fn load_params(filename: &str) -> Result<(groth16::Parameters<Bls12>, PreparedKey)> {
     let buffer = std::fs::File::open(filename)?;
     let params = groth16::Parameters::<Bls12>::read(buffer, false)?;
-    let pvk = groth16::prepare_verifying_key(&params.vk);
+    let pvk = PreparedKey::new(&params.vk);
     Ok((params, pvk))
 }
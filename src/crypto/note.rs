@@ -1,19 +1,28 @@
+use blake2b_simd::{Hash as Blake2bHash, Params as Blake2bParams};
 use crypto_api_chachapoly::ChachaPolyIetf;
 use ff::Field;
+use group::{cofactor::CofactorGroup, Curve, GroupEncoding};
 use rand::rngs::OsRng;
 use std::io;
 
-use super::diffie_hellman::{kdf_sapling, sapling_ka_agree};
+use super::diffie_hellman::{kdf_sapling, kdf_sapling_encoded, sapling_ka_agree};
+use super::keys::{Address, OutgoingViewingKey, ViewingKey};
 use crate::error::{Error, Result};
 use crate::serial::{Decodable, Encodable, ReadExt, WriteExt};
 
+pub const MEMO_SIZE: usize = 512;
 pub const NOTE_PLAINTEXT_SIZE: usize = 32 +    // serial
     8 +     // value
     32 +    // token_id
     32 +    // coin_blind
-    32; // valcom_blind
+    32 +    // valcom_blind
+    MEMO_SIZE; // memo
 pub const AEAD_TAG_SIZE: usize = 16;
 pub const ENC_CIPHERTEXT_SIZE: usize = NOTE_PLAINTEXT_SIZE + AEAD_TAG_SIZE;
+pub const OUT_PLAINTEXT_SIZE: usize = 64 + // address
+    32; // ephemeral secret
+pub const OUT_CIPHERTEXT_SIZE: usize = OUT_PLAINTEXT_SIZE + AEAD_TAG_SIZE;
+pub const OUT_CIPHER_KEY_PERSONALIZATION: &[u8; 16] = b"DarkFi_OutCipher";
 
 #[derive(Clone)]
 pub struct Note {
@@ -22,6 +31,53 @@ pub struct Note {
     pub token_id: jubjub::Fr,
     pub coin_blind: jubjub::Fr,
     pub valcom_blind: jubjub::Fr,
+    pub memo: Memo,
+}
+
+/// Text for the recipient, encrypted along with the rest of the note.
+/// Shorter memos are padded with zeros, so a memo can't hold NUL bytes.
+#[derive(Clone, Debug)]
+pub struct Memo([u8; MEMO_SIZE]);
+
+impl Memo {
+    pub fn new(data: &[u8]) -> Result<Self> {
+        if data.len() > MEMO_SIZE {
+            return Err(Error::MemoTooLong(data.len()));
+        }
+        if data.contains(&0) {
+            return Err(Error::MemoHasNul);
+        }
+        let mut memo = [0u8; MEMO_SIZE];
+        memo[..data.len()].copy_from_slice(data);
+        Ok(Self(memo))
+    }
+
+    /// The memo without its padding
+    pub fn as_bytes(&self) -> &[u8] {
+        let len = self.0.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+        &self.0[..len]
+    }
+}
+
+impl Default for Memo {
+    fn default() -> Self {
+        Self([0u8; MEMO_SIZE])
+    }
+}
+
+impl Encodable for Memo {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        s.write_slice(&self.0)?;
+        Ok(MEMO_SIZE)
+    }
+}
+
+impl Decodable for Memo {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        let mut memo = [0u8; MEMO_SIZE];
+        d.read_slice(&mut memo[..])?;
+        Ok(Self(memo))
+    }
 }
 
 impl Encodable for Note {
@@ -32,6 +88,7 @@ impl Encodable for Note {
         len += self.token_id.encode(&mut s)?;
         len += self.coin_blind.encode(&mut s)?;
         len += self.valcom_blind.encode(&mut s)?;
+        len += self.memo.encode(&mut s)?;
         Ok(len)
     }
 }
@@ -43,16 +100,20 @@ impl Decodable for Note {
             value: Decodable::decode(&mut d)?,
             token_id: Decodable::decode(&mut d)?,
             coin_blind: Decodable::decode(&mut d)?,
-            valcom_blind: Decodable::decode(d)?,
+            valcom_blind: Decodable::decode(&mut d)?,
+            memo: Decodable::decode(d)?,
         })
     }
 }
 
 impl Note {
-    pub fn encrypt(&self, public: &jubjub::SubgroupPoint) -> Result<EncryptedNote> {
+    /// Encrypt the note to the viewing key of the address. The address and
+    /// the ephemeral secret are encrypted to the outgoing viewing key of
+    /// the sender, which can decrypt the note with them later.
+    pub fn encrypt(&self, address: &Address, ovk: &OutgoingViewingKey) -> Result<EncryptedNote> {
         let ephem_secret = jubjub::Fr::random(&mut OsRng);
         let ephem_public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * ephem_secret;
-        let shared_secret = sapling_ka_agree(&ephem_secret, public.into());
+        let shared_secret = sapling_ka_agree(&ephem_secret, &address.view_public.into());
         let key = kdf_sapling(shared_secret, &ephem_public.into());
 
         let mut input = Vec::new();
@@ -66,16 +127,48 @@ impl Note {
             ENC_CIPHERTEXT_SIZE
         );
 
+        let mut out_input = Vec::new();
+        address.encode(&mut out_input)?;
+        ephem_secret.encode(&mut out_input)?;
+
+        let out_key = out_cipher_key(ovk, &ephem_public);
+        let mut out_ciphertext = [0u8; OUT_CIPHERTEXT_SIZE];
+        assert_eq!(
+            ChachaPolyIetf::aead_cipher()
+                .seal_to(
+                    &mut out_ciphertext,
+                    &out_input,
+                    &[],
+                    out_key.as_ref(),
+                    &[0u8; 12]
+                )
+                .unwrap(),
+            OUT_CIPHERTEXT_SIZE
+        );
+
         Ok(EncryptedNote {
             ciphertext,
             ephem_public,
+            out_ciphertext,
         })
     }
 }
 
+// Key of the outgoing ciphertext, unique to each note by its ephemeral key
+fn out_cipher_key(ovk: &OutgoingViewingKey, ephem_public: &jubjub::SubgroupPoint) -> Blake2bHash {
+    Blake2bParams::new()
+        .hash_length(32)
+        .personal(OUT_CIPHER_KEY_PERSONALIZATION)
+        .to_state()
+        .update(&ovk.0)
+        .update(&ephem_public.to_bytes())
+        .finalize()
+}
+
 pub struct EncryptedNote {
     ciphertext: [u8; ENC_CIPHERTEXT_SIZE],
     ephem_public: jubjub::SubgroupPoint,
+    out_ciphertext: [u8; OUT_CIPHERTEXT_SIZE],
 }
 
 impl Encodable for EncryptedNote {
@@ -84,6 +177,8 @@ impl Encodable for EncryptedNote {
         s.write_slice(&self.ciphertext)?;
         len += ENC_CIPHERTEXT_SIZE;
         len += self.ephem_public.encode(&mut s)?;
+        s.write_slice(&self.out_ciphertext)?;
+        len += OUT_CIPHERTEXT_SIZE;
         Ok(len)
     }
 }
@@ -92,29 +187,105 @@ impl Decodable for EncryptedNote {
     fn decode<D: io::Read>(mut d: D) -> Result<Self> {
         let mut ciphertext = [0u8; ENC_CIPHERTEXT_SIZE];
         d.read_slice(&mut ciphertext[..])?;
+        let ephem_public = Decodable::decode(&mut d)?;
+        let mut out_ciphertext = [0u8; OUT_CIPHERTEXT_SIZE];
+        d.read_slice(&mut out_ciphertext[..])?;
         Ok(Self {
             ciphertext,
-            ephem_public: Decodable::decode(d)?,
+            ephem_public,
+            out_ciphertext,
         })
     }
 }
 
 impl EncryptedNote {
-    pub fn decrypt(&self, secret: &jubjub::Fr) -> Result<Note> {
-        let shared_secret = sapling_ka_agree(secret, &self.ephem_public.into());
-        let key = kdf_sapling(shared_secret, &self.ephem_public.into());
+    pub fn decrypt(&self, view_key: &ViewingKey) -> Result<Note> {
+        let shared_secret = sapling_ka_agree(&view_key.0, &self.ephem_public.into());
+        self.open(shared_secret)
+    }
 
-        let mut plaintext = [0; ENC_CIPHERTEXT_SIZE];
+    /// Decrypt the notes encrypted to the viewing key, None for the others.
+    /// Every note still needs its own key agreement and a decryption
+    /// attempt, and the scalar multiplication dominates the cost as it does
+    /// one by one. Batching only saves the work around it: the key
+    /// agreements share the wNAF of the key, and the points hashed into
+    /// the note keys are made affine with a single inversion.
+    pub fn decrypt_batch(notes: &[EncryptedNote], view_key: &ViewingKey) -> Vec<Option<Note>> {
+        let mut wnaf = group::Wnaf::new();
+        let mut key_wnaf = wnaf.scalar(&view_key.0);
+
+        // [8 ivk] epk, the same as sapling_ka_agree
+        let shared_secrets: Vec<jubjub::SubgroupPoint> = notes
+            .iter()
+            .map(|note| {
+                key_wnaf
+                    .base(jubjub::ExtendedPoint::from(note.ephem_public))
+                    .clear_cofactor()
+            })
+            .collect();
+
+        // The shared secrets, then the ephemeral keys
+        let points: Vec<jubjub::ExtendedPoint> = shared_secrets
+            .into_iter()
+            .map(jubjub::ExtendedPoint::from)
+            .chain(notes.iter().map(|note| note.ephem_public.into()))
+            .collect();
+        let mut affine = vec![jubjub::AffinePoint::identity(); points.len()];
+        jubjub::ExtendedPoint::batch_normalize(&points, &mut affine);
+        let (shared_secrets, ephem_publics) = affine.split_at(notes.len());
+
+        notes
+            .iter()
+            .zip(shared_secrets.iter().zip(ephem_publics))
+            .map(|(note, (shared_secret, ephem_public))| {
+                let key = kdf_sapling_encoded(&shared_secret.to_bytes(), &ephem_public.to_bytes());
+                note.open_with_key(key.as_ref()).ok()
+            })
+            .collect()
+    }
+
+    /// Decrypt a note we sent with our outgoing viewing key, along with the
+    /// address it was sent to.
+    pub fn decrypt_outgoing(&self, ovk: &OutgoingViewingKey) -> Result<(Address, Note)> {
+        let out_key = out_cipher_key(ovk, &self.ephem_public);
+
+        let mut out_plaintext = [0; OUT_CIPHERTEXT_SIZE];
         assert_eq!(
             ChachaPolyIetf::aead_cipher()
                 .open_to(
-                    &mut plaintext,
-                    &self.ciphertext,
+                    &mut out_plaintext,
+                    &self.out_ciphertext,
                     &[],
-                    key.as_ref(),
+                    out_key.as_ref(),
                     &[0u8; 12]
                 )
                 .map_err(|_| Error::NoteDecryptionFailed)?,
+            OUT_PLAINTEXT_SIZE
+        );
+
+        let mut d = &out_plaintext[..];
+        let address = Address::decode(&mut d)?;
+        let ephem_secret = jubjub::Fr::decode(&mut d)?;
+
+        if zcash_primitives::constants::SPENDING_KEY_GENERATOR * ephem_secret != self.ephem_public {
+            return Err(Error::NoteDecryptionFailed);
+        }
+
+        let shared_secret = sapling_ka_agree(&ephem_secret, &address.view_public.into());
+        Ok((address, self.open(shared_secret)?))
+    }
+
+    fn open(&self, shared_secret: jubjub::SubgroupPoint) -> Result<Note> {
+        let key = kdf_sapling(shared_secret, &self.ephem_public.into());
+        self.open_with_key(key.as_ref())
+    }
+
+    fn open_with_key(&self, key: &[u8]) -> Result<Note> {
+        let mut plaintext = [0; ENC_CIPHERTEXT_SIZE];
+        assert_eq!(
+            ChachaPolyIetf::aead_cipher()
+                .open_to(&mut plaintext, &self.ciphertext, &[], key, &[0u8; 12])
+                .map_err(|_| Error::NoteDecryptionFailed)?,
             NOTE_PLAINTEXT_SIZE
         );
 
@@ -130,13 +301,86 @@ fn test_note_encdec() {
         token_id: jubjub::Fr::random(&mut OsRng),
         coin_blind: jubjub::Fr::random(&mut OsRng),
         valcom_blind: jubjub::Fr::random(&mut OsRng),
+        memo: Memo::new(b"hello").unwrap(),
     };
 
     let secret = jubjub::Fr::random(&mut OsRng);
-    let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
+    let address = Address::from_secret(&secret);
+    let sender_ovk = OutgoingViewingKey::from_secret(&jubjub::Fr::random(&mut OsRng));
 
-    let encrypted_note = note.encrypt(&public).unwrap();
-    let note2 = encrypted_note.decrypt(&secret).unwrap();
+    let encrypted_note = note.encrypt(&address, &sender_ovk).unwrap();
+    let note2 = encrypted_note
+        .decrypt(&ViewingKey::from_secret(&secret))
+        .unwrap();
     assert_eq!(note.value, note2.value);
     assert_eq!(note.token_id, note2.token_id);
+    assert_eq!(note2.memo.as_bytes(), b"hello");
+
+    // The sender recovers the note and where it went
+    let (recipient, note3) = encrypted_note.decrypt_outgoing(&sender_ovk).unwrap();
+    assert_eq!(recipient, address);
+    assert_eq!(note3.value, 110);
+    assert_eq!(note3.memo.as_bytes(), b"hello");
+
+    // The recipient's keys can't
+    assert!(encrypted_note
+        .decrypt_outgoing(&OutgoingViewingKey::from_secret(&secret))
+        .is_err());
+
+    let mut data = vec![];
+    assert_eq!(
+        encrypted_note.encode(&mut data).unwrap(),
+        ENC_CIPHERTEXT_SIZE + 32 + OUT_CIPHERTEXT_SIZE
+    );
+    let decoded = EncryptedNote::decode(&data[..]).unwrap();
+    assert!(decoded.decrypt_outgoing(&sender_ovk).is_ok());
+}
+
+#[test]
+fn test_note_decrypt_batch() {
+    let secret = jubjub::Fr::random(&mut OsRng);
+    let address = Address::from_secret(&secret);
+    let other = Address::from_secret(&jubjub::Fr::random(&mut OsRng));
+    let ovk = OutgoingViewingKey::from_secret(&secret);
+
+    let notes: Vec<EncryptedNote> = (0..4u64)
+        .map(|value| {
+            let note = Note {
+                serial: jubjub::Fr::random(&mut OsRng),
+                value,
+                token_id: jubjub::Fr::random(&mut OsRng),
+                coin_blind: jubjub::Fr::random(&mut OsRng),
+                valcom_blind: jubjub::Fr::random(&mut OsRng),
+                memo: Memo::default(),
+            };
+            let recipient = if value % 2 == 0 { &address } else { &other };
+            note.encrypt(recipient, &ovk).unwrap()
+        })
+        .collect();
+
+    let view_key = ViewingKey::from_secret(&secret);
+    let found = EncryptedNote::decrypt_batch(&notes, &view_key);
+    let values: Vec<Option<u64>> = found
+        .iter()
+        .map(|note| note.as_ref().map(|n| n.value))
+        .collect();
+    assert_eq!(values, vec![Some(0), None, Some(2), None]);
+
+    // Same result as decrypting them one by one
+    for (note, found) in notes.iter().zip(found) {
+        assert_eq!(note.decrypt(&view_key).is_ok(), found.is_some());
+    }
+}
+
+#[test]
+fn test_memo_too_long() {
+    assert!(Memo::new(&[1u8; MEMO_SIZE]).is_ok());
+    assert!(Memo::new(&[1u8; MEMO_SIZE + 1]).is_err());
+}
+
+#[test]
+fn test_memo_rejects_nul() {
+    assert!(Memo::new(b"rent\0").is_err());
+    assert!(Memo::new(b"re\0nt").is_err());
+    assert_eq!(Memo::new(b"rent").unwrap().as_bytes(), b"rent");
 }
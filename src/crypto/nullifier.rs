@@ -2,7 +2,8 @@ use std::io;
 
 use crate::{
     error::Result,
-    serial::{Decodable, Encodable},
+    impl_vec,
+    serial::{Decodable, Encodable, VarInt},
 };
 
 
@@ -31,3 +32,5 @@ impl Decodable for Nullifier {
     }
 }
 
+impl_vec!(Nullifier);
+impl_vec!((Nullifier, u64));
@@ -10,13 +10,13 @@ use std::time::Instant;
 
 use super::merkle_node::{merkle_hash, MerkleNode, SAPLING_COMMITMENT_TREE_DEPTH};
 use super::nullifier::Nullifier;
+use super::util::value_commit;
 use crate::circuit::spend_contract::SpendContract;
 use crate::error::Result;
 use crate::serial::{Decodable, Encodable};
 
 pub struct SpendRevealedValues {
     pub value_commit: jubjub::SubgroupPoint,
-    pub token_commit: jubjub::SubgroupPoint,
     pub nullifier: Nullifier,
     // This should not be here, we just have it for debugging
     //coin: [u8; 32],
@@ -30,22 +30,13 @@ impl SpendRevealedValues {
         value: u64,
         token_id: jubjub::Fr,
         randomness_value: &jubjub::Fr,
-        randomness_token: &jubjub::Fr,
         serial: &jubjub::Fr,
         randomness_coin: &jubjub::Fr,
         secret: &jubjub::Fr,
         merkle_path: &[(bls12_381::Scalar, bool)],
         signature_secret: &jubjub::Fr,
     ) -> Self {
-        let value_commit = (zcash_primitives::constants::VALUE_COMMITMENT_VALUE_GENERATOR
-            * jubjub::Fr::from(value))
-            + (zcash_primitives::constants::VALUE_COMMITMENT_RANDOMNESS_GENERATOR
-                * randomness_value);
-
-        let token_commit = (zcash_primitives::constants::VALUE_COMMITMENT_VALUE_GENERATOR
-            * token_id)
-            + (zcash_primitives::constants::VALUE_COMMITMENT_RANDOMNESS_GENERATOR
-                * randomness_token);
+        let value_commit = value_commit(value, &token_id, randomness_value);
 
         let mut nullifier = [0; 32];
         nullifier.copy_from_slice(
@@ -99,15 +90,14 @@ impl SpendRevealedValues {
 
         SpendRevealedValues {
             value_commit,
-            token_commit,
             nullifier,
             merkle_root,
             signature_public,
         }
     }
 
-    fn make_outputs(&self) -> [bls12_381::Scalar; 9] {
-        let mut public_input = [bls12_381::Scalar::zero(); 9];
+    pub(crate) fn make_outputs(&self) -> [bls12_381::Scalar; 7] {
+        let mut public_input = [bls12_381::Scalar::zero(); 7];
 
         // CV
         {
@@ -120,17 +110,6 @@ impl SpendRevealedValues {
             public_input[1] = v;
         }
 
-        // CA
-        {
-            let result = jubjub::ExtendedPoint::from(self.token_commit);
-            let affine = result.to_affine();
-            //let (u, v) = (affine.get_u(), affine.get_v());
-            let u = affine.get_u();
-            let v = affine.get_v();
-            public_input[2] = u;
-            public_input[3] = v;
-        }
-
         // NF
         {
             // Pack the hash as inputs for proof verification.
@@ -140,8 +119,8 @@ impl SpendRevealedValues {
             // There are 2 chunks for a blake hash
             assert_eq!(hash.len(), 2);
 
-            public_input[4] = hash[0];
-            public_input[5] = hash[1];
+            public_input[2] = hash[0];
+            public_input[3] = hash[1];
         }
 
         // Not revealed. We leave this code here for debug
@@ -158,7 +137,7 @@ impl SpendRevealedValues {
             public_input[5] = hash[1];
         }*/
 
-        public_input[6] = self.merkle_root.into();
+        public_input[4] = self.merkle_root.into();
 
         {
             let result = jubjub::ExtendedPoint::from(self.signature_public);
@@ -166,8 +145,8 @@ impl SpendRevealedValues {
             //let (u, v) = (affine.get_u(), affine.get_v());
             let u = affine.get_u();
             let v = affine.get_v();
-            public_input[7] = u;
-            public_input[8] = v;
+            public_input[5] = u;
+            public_input[6] = v;
         }
 
         public_input
@@ -178,7 +157,6 @@ impl Encodable for SpendRevealedValues {
     fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
         let mut len = 0;
         len += self.value_commit.encode(&mut s)?;
-        len += self.token_commit.encode(&mut s)?;
         len += self.nullifier.encode(&mut s)?;
         len += self.merkle_root.encode(&mut s)?;
         len += self.signature_public.encode(s)?;
@@ -190,7 +168,6 @@ impl Decodable for SpendRevealedValues {
     fn decode<D: io::Read>(mut d: D) -> Result<Self> {
         Ok(Self {
             value_commit: Decodable::decode(&mut d)?,
-            token_commit: Decodable::decode(&mut d)?,
             nullifier: Decodable::decode(&mut d)?,
             merkle_root: Decodable::decode(&mut d)?,
             signature_public: Decodable::decode(d)?,
@@ -206,7 +183,6 @@ pub fn setup_spend_prover() -> groth16::Parameters<Bls12> {
             value: None,
             token_id: None,
             randomness_value: None,
-            randomness_token: None,
             serial: None,
             randomness_coin: None,
             secret: None,
@@ -228,7 +204,6 @@ pub fn create_spend_proof(
     value: u64,
     token_id: jubjub::Fr,
     randomness_value: jubjub::Fr,
-    randomness_token: jubjub::Fr,
     serial: jubjub::Fr,
     randomness_coin: jubjub::Fr,
     secret: jubjub::Fr,
@@ -246,7 +221,6 @@ pub fn create_spend_proof(
         value: Some(value),
         token_id: Some(token_id),
         randomness_value: Some(randomness_value),
-        randomness_token: Some(randomness_token),
         serial: Some(serial),
         randomness_coin: Some(randomness_coin),
         secret: Some(secret),
@@ -265,7 +239,6 @@ pub fn create_spend_proof(
         value,
         token_id,
         &randomness_value,
-        &randomness_token,
         &serial,
         &randomness_coin,
         &secret,
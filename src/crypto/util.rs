@@ -1,4 +1,59 @@
 use blake2b_simd::Params;
+use ff::PrimeField;
+use group::Curve;
+use lazy_static::lazy_static;
+use zcash_primitives::group_hash::group_hash;
+
+/// BLAKE2s personalization of the generators the value base of a token is
+/// derived with. No other hash uses it.
+pub const VALUE_BASE_PERSONALIZATION: &[u8; 8] = b"DarkF_vb";
+
+// The token id is hashed in windows of 3 bits
+const VALUE_BASE_WINDOWS: usize = (jubjub::Fr::NUM_BITS as usize).div_ceil(3);
+
+lazy_static! {
+    // For each window, its generator times 1 to 8. A window is never worth
+    // zero, so no token id gives the identity.
+    static ref VALUE_BASE_TABLE: Vec<Vec<jubjub::SubgroupPoint>> = (0..VALUE_BASE_WINDOWS)
+        .map(|i| {
+            let generator = find_group_hash(&(i as u32).to_le_bytes(), VALUE_BASE_PERSONALIZATION);
+            (1..=8u64)
+                .map(|k| generator * jubjub::Fr::from(k))
+                .collect()
+        })
+        .collect();
+
+    /// The same table as coordinates, for deriving the value base in the
+    /// circuits with `ecc::fixed_base_multiplication`
+    pub static ref VALUE_BASE_GENERATOR: Vec<Vec<(bls12_381::Scalar, bls12_381::Scalar)>> =
+        VALUE_BASE_TABLE
+            .iter()
+            .map(|window| {
+                window
+                    .iter()
+                    .map(|point| {
+                        let point = jubjub::ExtendedPoint::from(*point).to_affine();
+                        (point.get_u(), point.get_v())
+                    })
+                    .collect()
+            })
+            .collect();
+}
+
+// The first point the tag hashes to, trying one counter byte after another
+// as Sapling derives its generators
+fn find_group_hash(tag: &[u8], personalization: &[u8; 8]) -> jubjub::SubgroupPoint {
+    let mut tag = tag.to_vec();
+    tag.push(0);
+
+    loop {
+        if let Some(point) = group_hash(&tag, personalization) {
+            return point;
+        }
+        let counter = tag.last_mut().unwrap();
+        *counter = counter.checked_add(1).expect("Tag hashes to a point");
+    }
+}
 
 pub fn hash_to_scalar(persona: &[u8], a: &[u8], b: &[u8]) -> jubjub::Fr {
     let mut hasher = Params::new().hash_length(64).personal(persona).to_state();
@@ -7,3 +62,72 @@ pub fn hash_to_scalar(persona: &[u8], a: &[u8], b: &[u8]) -> jubjub::Fr {
     let ret = hasher.finalize();
     jubjub::Fr::from_bytes_wide(ret.as_array())
 }
+
+/// Generator the value of a coin is committed to. Every token has its own,
+/// so value commitments of different tokens can never cancel each other out.
+/// Each 3 bit window of the token id picks a multiple of its own generator,
+/// and the value base is their sum.
+pub fn value_base(token_id: &jubjub::Fr) -> jubjub::SubgroupPoint {
+    // Same bits the circuits hash, which drop the unused top bits of the repr
+    let bits = token_id
+        .to_le_bits()
+        .into_iter()
+        .cloned()
+        .take(jubjub::Fr::NUM_BITS as usize)
+        .collect::<Vec<bool>>();
+
+    bits.chunks(3)
+        .zip(VALUE_BASE_TABLE.iter())
+        .map(|(chunk, window)| {
+            let index = chunk
+                .iter()
+                .enumerate()
+                .fold(0, |index, (i, bit)| index | ((*bit as usize) << i));
+            window[index]
+        })
+        .sum()
+}
+
+/// Commitment to a value of the given token: V = v * V_token + r * G_VCR
+pub fn value_commit(
+    value: u64,
+    token_id: &jubjub::Fr,
+    blind: &jubjub::Fr,
+) -> jubjub::SubgroupPoint {
+    (value_base(token_id) * jubjub::Fr::from(value))
+        + (zcash_primitives::constants::VALUE_COMMITMENT_RANDOMNESS_GENERATOR * blind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bellman::gadgets::{boolean, test::TestConstraintSystem};
+    use bellman::ConstraintSystem;
+    use ff::Field;
+    use rand::rngs::OsRng;
+    use zcash_proofs::circuit::ecc;
+
+    #[test]
+    fn test_value_base_in_circuit() {
+        let token_id = jubjub::Fr::random(&mut OsRng);
+
+        let mut cs = TestConstraintSystem::new();
+        let bits = boolean::field_into_boolean_vec_le(cs.namespace(|| "token_id"), Some(token_id))
+            .unwrap();
+        let point = ecc::fixed_base_multiplication(
+            cs.namespace(|| "value_base"),
+            &VALUE_BASE_GENERATOR,
+            &bits,
+        )
+        .unwrap();
+        assert!(cs.is_satisfied());
+
+        let expected = jubjub::ExtendedPoint::from(value_base(&token_id)).to_affine();
+        assert_eq!(point.get_u().get_value(), Some(expected.get_u()));
+        assert_eq!(point.get_v().get_value(), Some(expected.get_v()));
+
+        // Tokens differ in their value base
+        assert_ne!(value_base(&token_id), value_base(&jubjub::Fr::zero()));
+    }
+}
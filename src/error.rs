@@ -1,6 +1,7 @@
 use std::fmt;
 
 use crate::client;
+use crate::crypto::note::MEMO_SIZE;
 use crate::state;
 use crate::vm::ZkVmError;
 
@@ -45,6 +46,8 @@ pub enum Error {
     Groth16Error,
     OperationFailed,
     NoteDecryptionFailed,
+    MemoTooLong(usize),
+    MemoHasNul,
     VerifyFailed,
     TreeFull,
 
@@ -62,6 +65,7 @@ pub enum Error {
     RocksdbError(String),
     RusqliteError(String),
     SlabsStore(String),
+    UnsupportedSchemaVersion(u32, u32),
 
     /// RPC errors
     JsonRpcError(String),
@@ -83,6 +87,7 @@ pub enum Error {
     ConfigNotFound,
     KeypairPathNotFound,
     CashierKeysNotFound,
+    ParamsNotFound(String),
     SetLoggerError,
     AsyncChannelSenderError,
     AsyncChannelReceiverError,
@@ -125,6 +130,10 @@ impl fmt::Display for Error {
             Error::Utf8Error => f.write_str("Malformed UTF8"),
             Error::StrUtf8Error(ref err) => write!(f, "Malformed UTF8: {}", err),
             Error::NoteDecryptionFailed => f.write_str("Unable to decrypt mint note"),
+            Error::MemoTooLong(len) => {
+                write!(f, "Memo is {} bytes, the limit is {}", len, MEMO_SIZE)
+            }
+            Error::MemoHasNul => f.write_str("Memo can not contain NUL bytes"),
             Error::ServicesError(ref err) => write!(f, "Services error: {}", err),
             Error::ZmqError(ref err) => write!(f, "ZmqError: {}", err),
             Error::VerifyFailed => f.write_str("Verify failed"),
@@ -138,6 +147,11 @@ impl fmt::Display for Error {
             Error::TryFromBigIntError => f.write_str("TryFromBigInt error"),
             Error::RocksdbError(ref err) => write!(f, "Rocksdb Error: {}", err),
             Error::SlabsStore(ref err) => write!(f, "SlabsStore Error: {}", err),
+            Error::UnsupportedSchemaVersion(found, supported) => write!(
+                f,
+                "Database schema version {} is not the supported version {}",
+                found, supported
+            ),
             Error::JsonRpcError(ref err) => write!(f, "JsonRpc Error: {}", err),
             Error::TreeFull => f.write_str("MerkleTree is full"),
             Error::NotSupportedNetwork => f.write_str("Not supported network"),
@@ -151,6 +165,9 @@ impl fmt::Display for Error {
             Error::ConfigNotFound => f.write_str("No config file detected. Please create one."),
             Error::KeypairPathNotFound => f.write_str("No keypair file detected."),
             Error::CashierKeysNotFound => f.write_str("No cashier public keys detected."),
+            Error::ParamsNotFound(ref path) => {
+                write!(f, "Trusted setup parameters not found at {}", path)
+            }
             Error::SetLoggerError => f.write_str("SetLoggerError"),
             Error::TokenParseError => f.write_str("Could not parse token parameter"),
             Error::TungsteniteError => f.write_str("TungsteniteError"),
@@ -12,6 +12,7 @@ pub mod client;
 pub mod crypto;
 pub mod endian;
 pub mod error;
+pub mod mempool;
 pub mod net;
 pub mod rpc;
 pub mod serial;
@@ -0,0 +1,342 @@
+use log::{debug, warn};
+
+use crate::{
+    crypto::nullifier::Nullifier,
+    serial::{Decodable, Encodable},
+    state::StateUpdate,
+    state::{verified_batch_state_transition, verified_state_transition, StateQuery},
+    state::{VerifyFailed, VerifyResult},
+    tx::Transaction,
+};
+
+/// What became of a transaction submitted to the mempool
+#[derive(Clone, Debug)]
+pub enum TxStatus {
+    /// Put into the slab with this index
+    Accepted(u64),
+    /// Dropped before it made it into a slab
+    Rejected(String),
+}
+
+/// Handed back to whoever submitted a transaction, to learn its status
+pub struct TxStatusReceiver(async_channel::Receiver<TxStatus>);
+
+impl TxStatusReceiver {
+    /// Wait until the transaction is put into a slab or dropped.
+    pub async fn wait(&self) -> TxStatus {
+        self.0
+            .recv()
+            .await
+            .unwrap_or_else(|_| TxStatus::Rejected("Dropped from the mempool".into()))
+    }
+}
+
+/// A transaction which passed `state_transition` and waits for a slab
+pub struct PendingTx {
+    pub id: [u8; 32],
+    // The encoded transaction, used as the slab payload
+    pub data: Vec<u8>,
+    pub update: StateUpdate,
+    status: async_channel::Sender<TxStatus>,
+}
+
+impl PendingTx {
+    pub fn accept(self, index: u64) {
+        let id = hex::encode(self.id);
+        debug!(target: "MEMPOOL", "Transaction {} accepted in slab {}", id, index);
+        // The submitter may have stopped listening
+        let _ = self.status.try_send(TxStatus::Accepted(index));
+    }
+
+    pub fn reject(self, reason: String) {
+        warn!(target: "MEMPOOL", "Transaction {} rejected: {}", hex::encode(self.id), reason);
+        let _ = self.status.try_send(TxStatus::Rejected(reason));
+    }
+}
+
+/// Transactions waiting to be put into slabs, oldest first. No two of them
+/// spend the same coin, and there are never more than `max_size`.
+pub struct Mempool {
+    pending: Vec<PendingTx>,
+    max_size: usize,
+}
+
+impl Mempool {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            pending: vec![],
+            max_size,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Whether a pending transaction spends the coin with this nullifier
+    pub fn is_pending(&self, nullifier: &Nullifier) -> bool {
+        self.pending
+            .iter()
+            .any(|pending| pending.update.nullifiers.contains(nullifier))
+    }
+
+    /// Check the transaction against the state and queue it. Its proofs and
+    /// signatures have to be verified already with `Transaction::verify`,
+    /// which is slow and best done before the state and the mempool are
+    /// locked. Transactions spending a coin which a pending transaction
+    /// already spends are rejected, and so is every transaction while the
+    /// mempool is full.
+    pub fn submit<S: StateQuery>(
+        &mut self,
+        state: &S,
+        tx: Transaction,
+    ) -> VerifyResult<TxStatusReceiver> {
+        if self.pending.len() >= self.max_size {
+            return Err(VerifyFailed::MempoolFull);
+        }
+
+        for (i, input) in tx.inputs.iter().enumerate() {
+            if self.is_pending(&input.revealed.nullifier) {
+                return Err(VerifyFailed::PendingNullifier(i));
+            }
+        }
+
+        let id = tx.id();
+        let mut data = vec![];
+        tx.encode(&mut data).expect("encode tx");
+
+        let update = verified_state_transition(state, tx)?;
+
+        let (status, receiver) = async_channel::bounded(1);
+        self.pending.push(PendingTx {
+            id,
+            data,
+            update,
+            status,
+        });
+
+        debug!(target: "MEMPOOL", "Transaction {} submitted", hex::encode(id));
+        Ok(TxStatusReceiver(receiver))
+    }
+
+    /// Take up to `max` of the oldest pending transactions, to put them
+    /// into the next slabs. The state may have moved on since they were
+    /// submitted, so each is checked again, together with the others in
+    /// the batch. Proofs and signatures were verified on submit and are not
+    /// checked again.
+    pub fn take<S: StateQuery>(
+        &mut self,
+        state: &S,
+        max: usize,
+    ) -> Vec<(PendingTx, VerifyResult<StateUpdate>)> {
+        let count = max.min(self.pending.len());
+        let batch: Vec<PendingTx> = self.pending.drain(..count).collect();
+
+        // The data was encoded from a transaction on submit
+        let txs = batch
+            .iter()
+            .map(|pending| Transaction::decode(&pending.data[..]).expect("decode pending tx"))
+            .collect();
+        let results = verified_batch_state_transition(state, txs);
+
+        batch.into_iter().zip(results).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bellman::groth16;
+    use bls12_381::Bls12;
+    use ff::Field;
+    use rand::rngs::OsRng;
+
+    use crate::crypto::{
+        coin::Coin,
+        keys::{Address, ViewingKey},
+        merkle::{CommitmentTree, IncrementalWitness},
+        merkle_node::MerkleNode,
+        setup_mint_prover, setup_spend_prover,
+    };
+    use crate::tx;
+
+    struct TestState {
+        cashier_public: jubjub::SubgroupPoint,
+        nullifiers: Vec<Nullifier>,
+    }
+
+    impl StateQuery for TestState {
+        fn is_valid_cashier_public_key(&self, public: &jubjub::SubgroupPoint) -> bool {
+            *public == self.cashier_public
+        }
+        fn is_valid_merkle(&self, _merkle: &MerkleNode) -> bool {
+            true
+        }
+        fn nullifier_exists(&self, nullifier: &Nullifier) -> bool {
+            self.nullifiers.contains(nullifier)
+        }
+        fn height(&self) -> crate::Result<u64> {
+            Ok(0)
+        }
+    }
+
+    fn test_state(cashier_secret: jubjub::Fr) -> TestState {
+        TestState {
+            cashier_public: zcash_primitives::constants::SPENDING_KEY_GENERATOR * cashier_secret,
+            nullifiers: vec![],
+        }
+    }
+
+    fn deposit(
+        mint_params: &groth16::Parameters<Bls12>,
+        spend_params: &groth16::Parameters<Bls12>,
+        cashier_secret: jubjub::Fr,
+        address: Address,
+        expires_at: Option<u64>,
+    ) -> Transaction {
+        let token_id = jubjub::Fr::random(&mut OsRng);
+        tx::TransactionBuilder {
+            expires_at,
+            ..tx::TransactionBuilder::new(
+                vec![tx::TransactionBuilderClearInputInfo {
+                    value: 110,
+                    token_id,
+                    signature_secret: cashier_secret,
+                }],
+                vec![],
+                vec![tx::TransactionBuilderOutputInfo {
+                    value: 110,
+                    token_id,
+                    address,
+                    memo: Default::default(),
+                }],
+            )
+        }
+        .build(mint_params, spend_params)
+    }
+
+    #[test]
+    fn test_mempool_conflicting_nullifiers() {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+
+        let cashier_secret = jubjub::Fr::random(&mut OsRng);
+        let secret = jubjub::Fr::random(&mut OsRng);
+        let address = Address::from_secret(&secret);
+
+        let mut state = test_state(cashier_secret);
+        let deposit = deposit(&mint_params, &spend_params, cashier_secret, address, None);
+
+        let note = deposit.outputs[0]
+            .enc_note
+            .decrypt(&ViewingKey::from_secret(&secret))
+            .unwrap();
+        let mut tree = CommitmentTree::empty();
+        let coin = Coin::new(deposit.outputs[0].revealed.coin);
+        tree.append(MerkleNode::from_coin(&coin)).unwrap();
+        let merkle_path = IncrementalWitness::from_tree(&tree).path().unwrap();
+
+        let spend = || {
+            tx::TransactionBuilder::new(
+                vec![],
+                vec![tx::TransactionBuilderInputInfo {
+                    merkle_path: merkle_path.clone(),
+                    secret,
+                    note: note.clone(),
+                }],
+                vec![tx::TransactionBuilderOutputInfo {
+                    value: 110,
+                    token_id: note.token_id,
+                    address,
+                    memo: Default::default(),
+                }],
+            )
+            .build(&mint_params, &spend_params)
+        };
+
+        let mut mempool = Mempool::new(10);
+
+        assert!(mempool.submit(&state, deposit).is_ok());
+        assert!(mempool.submit(&state, spend()).is_ok());
+
+        // A second spend of the same coin conflicts with the pending one
+        match mempool.submit(&state, spend()) {
+            Err(VerifyFailed::PendingNullifier(0)) => {}
+            _ => panic!("Two pending transactions spend the same coin"),
+        }
+        assert_eq!(mempool.len(), 2);
+
+        // Spent by a slab applied after the transaction was submitted
+        state.nullifiers = mempool.pending[1].update.nullifiers.clone();
+
+        let batch = mempool.take(&state, 10);
+        assert_eq!(batch.len(), 2);
+        assert!(mempool.is_empty());
+        assert!(batch[0].1.is_ok());
+        match batch[1].1 {
+            Err(VerifyFailed::DuplicateNullifier(0, _)) => {}
+            _ => panic!("The pending transaction spends a spent coin"),
+        }
+    }
+
+    #[test]
+    fn test_mempool_expiry_per_slab() {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+
+        let cashier_secret = jubjub::Fr::random(&mut OsRng);
+        let address = Address::from_secret(&jubjub::Fr::random(&mut OsRng));
+        let state = test_state(cashier_secret);
+
+        // Both could go into slab 1 when submitted
+        let mut mempool = Mempool::new(10);
+        for _ in 0..2 {
+            let tx = deposit(
+                &mint_params,
+                &spend_params,
+                cashier_secret,
+                address,
+                Some(1),
+            );
+            assert!(mempool.submit(&state, tx).is_ok());
+        }
+
+        // But only the first does, the second would go into slab 2
+        let batch = mempool.take(&state, 10);
+        assert!(batch[0].1.is_ok());
+        match batch[1].1 {
+            Err(VerifyFailed::Expired(1)) => {}
+            _ => panic!("A transaction was put into a slab after it expired"),
+        }
+    }
+    #[test]
+    fn test_mempool_max_size() {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+
+        let cashier_secret = jubjub::Fr::random(&mut OsRng);
+        let address = Address::from_secret(&jubjub::Fr::random(&mut OsRng));
+        let state = test_state(cashier_secret);
+
+        let mut mempool = Mempool::new(1);
+        let tx = deposit(&mint_params, &spend_params, cashier_secret, address, None);
+        assert!(mempool.submit(&state, tx).is_ok());
+
+        let tx = deposit(&mint_params, &spend_params, cashier_secret, address, None);
+        match mempool.submit(&state, tx) {
+            Err(VerifyFailed::MempoolFull) => {}
+            _ => panic!("A transaction was queued in a full mempool"),
+        }
+        assert_eq!(mempool.len(), 1);
+
+        // There is room again once the pending one goes into a slab
+        assert_eq!(mempool.take(&state, 10).len(), 1);
+        let tx = deposit(&mint_params, &spend_params, cashier_secret, address, None);
+        assert!(mempool.submit(&state, tx).is_ok());
+    }
+}
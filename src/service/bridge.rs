@@ -7,6 +7,7 @@ use futures::stream::FuturesUnordered;
 use futures::stream::StreamExt;
 use log::*;
 
+use crate::crypto::keys::Address;
 use crate::util::NetworkName;
 use crate::wallet::cashierdb::TokenKey;
 use crate::{Error, Result};
@@ -56,7 +57,7 @@ pub struct TokenSubscribtion {
 pub struct TokenNotification {
     pub network: NetworkName,
     pub token_id: jubjub::Fr,
-    pub drk_pub_key: jubjub::SubgroupPoint,
+    pub drk_pub_key: Address,
     pub received_balance: u64,
     pub decimals: u16,
 }
@@ -115,7 +116,7 @@ impl Bridge {
 
     pub async fn subscribe(
         self: Arc<Self>,
-        drk_pub_key: jubjub::SubgroupPoint,
+        drk_pub_key: Address,
         mint: Option<String>,
         executor: Arc<Executor<'_>>,
     ) -> BridgeSubscribtion {
@@ -134,7 +135,7 @@ impl Bridge {
         self: Arc<Self>,
         req: async_channel::Receiver<BridgeRequests>,
         rep: async_channel::Sender<BridgeResponse>,
-        drk_pub_key: jubjub::SubgroupPoint,
+        drk_pub_key: Address,
         mint: Option<String>,
         executor: Arc<Executor<'_>>,
     ) -> Result<()> {
@@ -238,7 +239,7 @@ impl Bridge {
 pub trait NetworkClient {
     async fn subscribe(
         self: Arc<Self>,
-        drk_pub_key: jubjub::SubgroupPoint,
+        drk_pub_key: Address,
         mint: Option<String>,
         executor: Arc<Executor<'_>>,
     ) -> Result<TokenSubscribtion>;
@@ -248,7 +249,7 @@ pub trait NetworkClient {
         self: Arc<Self>,
         private_key: Vec<u8>,
         public_key: Vec<u8>,
-        drk_pub_key: jubjub::SubgroupPoint,
+        drk_pub_key: Address,
         mint: Option<String>,
         executor: Arc<Executor<'_>>,
     ) -> Result<String>;
@@ -26,6 +26,7 @@ use secp256k1::{
 };
 
 use super::bridge::{NetworkClient, TokenNotification, TokenSubscribtion};
+use crate::crypto::keys::Address as DrkAddress;
 use crate::serial::{deserialize, serialize, Decodable, Encodable};
 use crate::util::{generate_id, NetworkName};
 use crate::{Error, Result};
@@ -195,7 +196,7 @@ impl BtcClient {
     async fn handle_subscribe_request(
         self: Arc<Self>,
         btc_keys: Account,
-        drk_pub_key: jubjub::SubgroupPoint,
+        drk_pub_key: DrkAddress,
     ) -> BtcResult<()> {
         debug!(
             target: "BTC BRIDGE",
@@ -357,7 +358,7 @@ impl BtcClient {
 impl NetworkClient for BtcClient {
     async fn subscribe(
         self: Arc<Self>,
-        drk_pub_key: jubjub::SubgroupPoint,
+        drk_pub_key: DrkAddress,
         _mint: Option<String>,
         executor: Arc<Executor<'_>>,
     ) -> Result<TokenSubscribtion> {
@@ -389,7 +390,7 @@ impl NetworkClient for BtcClient {
         self: Arc<Self>,
         private_key: Vec<u8>,
         _public_key: Vec<u8>,
-        drk_pub_key: jubjub::SubgroupPoint,
+        drk_pub_key: DrkAddress,
         _mint: Option<String>,
         executor: Arc<Executor<'_>>,
     ) -> Result<String> {
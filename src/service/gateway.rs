@@ -1,51 +1,147 @@
 use std::net::ToSocketAddrs;
 
-use async_std::sync::Arc;
+use async_std::sync::{Arc, Mutex};
 use std::convert::From;
 use std::net::SocketAddr;
+use std::time::Duration;
 
 use async_executor::Executor;
-use log::debug;
+use log::{debug, warn};
+use smol::Timer;
 use url::Url;
 
 use super::reqrep::{PeerId, Publisher, RepProtocol, Reply, ReqProtocol, Request, Subscriber};
-use crate::blockchain::{rocks::columns, RocksColumn, Slab, SlabStore};
-use crate::{serial::deserialize, serial::serialize, Error, Result};
+use crate::blockchain::{rocks::columns, KvStore, Rocks, RocksColumn, Slab, SlabStore, Snapshot};
+use crate::client::State;
+use crate::crypto::coin::Coin;
+use crate::mempool::{Mempool, TxStatus};
+use crate::serial::{deserialize, serialize, Decodable};
+use crate::tx::Transaction;
+use crate::{Error, Result};
 
 pub type GatewaySlabsSubscriber = async_channel::Receiver<Slab>;
 
+// Upper bound on the number of slabs served by a single GetSlabs request
+const MAX_SLABS_PER_REQUEST: u64 = 100;
+
+// How often the transactions waiting in the mempool are put into slabs
+const MEMPOOL_INTERVAL: Duration = Duration::from_secs(2);
+
+// Most transactions put into slabs at each interval
+const MEMPOOL_BATCH_SIZE: usize = 64;
+
+// Most transactions waiting in the mempool, more are refused until the
+// next slabs are built
+const MEMPOOL_MAX_SIZE: usize = 1024;
+
+// The last slab passed on to the subscribers. Every slab received from the
+// gateway has to extend it, otherwise the gateway has rewritten history.
+struct ChainTip {
+    index: u64,
+    hash: [u8; 32],
+}
+
+impl ChainTip {
+    fn new(slabstore: &SlabStore) -> Result<Self> {
+        match slabstore.get_last_slab()? {
+            Some(slab) => Ok(Self {
+                index: slab.get_index(),
+                hash: slab.hash(),
+            }),
+            None => Ok(Self {
+                index: 0,
+                hash: [0u8; 32],
+            }),
+        }
+    }
+
+    // Returns true if the slab is new and extends the tip, false if it was
+    // already seen or does not follow on yet, and an error on a fork.
+    fn extend(&mut self, slab: &Slab) -> Result<bool> {
+        let index = slab.get_index();
+
+        if index < self.index || (index == self.index && slab.hash() == self.hash) {
+            return Ok(false);
+        }
+
+        if slab.is_pruned() {
+            return Err(Error::SlabsStore(format!(
+                "Gateway has pruned slab {}, refresh the database to start from its snapshot",
+                index
+            )));
+        }
+
+        if index > self.index + 1 {
+            warn!(target: "GATEWAY CLIENT", "Slab {} received ahead of {}", index, self.index);
+            return Ok(false);
+        }
+
+        let linked = slab.verify_payload() && slab.get_header().prev_hash == self.hash;
+        if index == self.index || !linked {
+            return Err(Error::SlabsStore(format!(
+                "Gateway served slab {} which does not extend the local chain",
+                index
+            )));
+        }
+
+        self.index = index;
+        self.hash = slab.hash();
+        Ok(true)
+    }
+}
+
 #[repr(u8)]
 enum GatewayError {
-    NoError,
-    UpdateIndex,
-    IndexNotExist,
+    NoError = 0,
+    // 1 was replied to slabs which did not extend the gateway's last slab,
+    // from when clients built the slabs
+    IndexNotExist = 2,
+    FeeTooLow,
+    // The payload of the reply holds the reason
+    VerifyFailed,
 }
 
 #[repr(u8)]
 enum GatewayCommand {
-    PutSlab,
+    SubmitTx,
     GetSlab,
     GetLastIndex,
+    GetSlabs,
+    GetSnapshot,
 }
 
-pub struct GatewayService {
-    slabstore: Arc<SlabStore>,
+pub struct GatewayService<K: KvStore = Rocks> {
+    // Every transaction is checked against this state, and the slabs built
+    // from them are applied to it
+    state: Arc<Mutex<State<K>>>,
+    // Transactions submitted by clients, waiting for the next slabs
+    mempool: Arc<Mutex<Mempool>>,
+    slabstore: Arc<SlabStore<K>>,
+    snapshots: Arc<RocksColumn<columns::Snapshots, K>>,
     addr: SocketAddr,
     pub_addr: SocketAddr,
+    // Lowest fee for each token fees may be paid in
+    min_fees: Arc<Vec<(jubjub::Fr, u64)>>,
 }
 
-impl GatewayService {
+impl<K: KvStore> GatewayService<K> {
     pub fn new(
         addr: SocketAddr,
         pub_addr: SocketAddr,
-        rocks: RocksColumn<columns::Slabs>,
-    ) -> Result<Arc<GatewayService>> {
-        let slabstore = SlabStore::new(rocks)?;
-
-        Ok(Arc::new(GatewayService {
+        state: State<K>,
+        snapshots: RocksColumn<columns::Snapshots, K>,
+        min_fees: Vec<(jubjub::Fr, u64)>,
+    ) -> Result<Arc<Self>> {
+        let slabstore = state.slabstore.clone();
+
+        Ok(Arc::new(Self {
+            state: Arc::new(Mutex::new(state)),
+            mempool: Arc::new(Mutex::new(Mempool::new(MEMPOOL_MAX_SIZE))),
             slabstore,
+            snapshots: Arc::new(snapshots),
             addr,
             pub_addr,
+            min_fees: Arc::new(min_fees),
         }))
     }
 
@@ -63,20 +159,89 @@ impl GatewayService {
             publish_recv_queue.clone(),
         ));
 
-        let handle_request_task = executor.spawn(self.handle_request_loop(
-            send.clone(),
-            recv.clone(),
+        let build_slabs_task = executor.spawn(Self::build_slabs_loop(
+            self.state.clone(),
+            self.mempool.clone(),
             publish_queue.clone(),
-            executor.clone(),
         ));
 
+        let handle_request_task =
+            executor.spawn(self.handle_request_loop(send.clone(), recv.clone(), executor.clone()));
+
         protocol.run(executor.clone()).await?;
 
         let _ = publisher_task.cancel().await;
+        let _ = build_slabs_task.cancel().await;
         let _ = handle_request_task.cancel().await;
         Ok(())
     }
 
+    async fn build_slabs_loop(
+        state: Arc<Mutex<State<K>>>,
+        mempool: Arc<Mutex<Mempool>>,
+        publish_queue: async_channel::Sender<Vec<u8>>,
+    ) -> Result<()> {
+        loop {
+            Timer::after(MEMPOOL_INTERVAL).await;
+
+            for slab in Self::build_slabs(&state, &mempool).await? {
+                // publish to all subscribes
+                publish_queue.send(serialize(&slab)).await?;
+            }
+        }
+    }
+
+    /// Put the transactions waiting in the mempool into slabs, one each, and
+    /// apply them to the state. Every submitter learns the index of its
+    /// transaction's slab, or why it was dropped. Returns the new slabs.
+    pub async fn build_slabs(
+        state: &Mutex<State<K>>,
+        mempool: &Mutex<Mempool>,
+    ) -> Result<Vec<Slab>> {
+        // The slabs extend the last applied slab, so they are built and
+        // applied under the same lock
+        let mut state = state.lock().await;
+
+        let mut batch = vec![];
+        for (pending, result) in mempool.lock().await.take(&*state, MEMPOOL_BATCH_SIZE) {
+            match result {
+                Ok(update) => batch.push((pending, update)),
+                Err(e) => pending.reject(e.to_string()),
+            }
+        }
+
+        let txs: Vec<(Vec<u8>, &[Coin])> = batch
+            .iter()
+            .map(|(pending, update)| (pending.data.clone(), &update.coins[..]))
+            .collect();
+        let slabs = state.next_slabs(txs)?;
+
+        let mut applied = vec![];
+        let mut failed: Option<String> = None;
+
+        for ((pending, update), slab) in batch.into_iter().zip(slabs) {
+            // Every slab extends the one before, so once one can't be
+            // applied the rest of the batch can't either
+            if let Some(reason) = &failed {
+                pending.reject(reason.clone());
+                continue;
+            }
+
+            let index = slab.get_index();
+            if let Err(e) = state.apply(update, slab.clone(), vec![], None, None).await {
+                warn!(target: "GATEWAY DAEMON", "Apply slab {}: {}", index, e);
+                failed = Some(e.to_string());
+                pending.reject(e.to_string());
+                continue;
+            }
+
+            pending.accept(index);
+            applied.push(slab);
+        }
+
+        Ok(applied)
+    }
+
     async fn start_publisher(
         pub_addr: SocketAddr,
         service_name: String,
@@ -91,17 +256,22 @@ impl GatewayService {
         self: Arc<Self>,
         send_queue: async_channel::Sender<(PeerId, Reply)>,
         recv_queue: async_channel::Receiver<(PeerId, Request)>,
-        publish_queue: async_channel::Sender<Vec<u8>>,
         executor: Arc<Executor<'_>>,
     ) -> Result<()> {
         while let Ok(msg) = recv_queue.recv().await {
+            let state = self.state.clone();
+            let mempool = self.mempool.clone();
             let slabstore = self.slabstore.clone();
+            let snapshots = self.snapshots.clone();
             let _ = executor
                 .spawn(Self::handle_request(
                     msg,
+                    state,
+                    mempool,
                     slabstore,
+                    snapshots,
+                    self.min_fees.clone(),
                     send_queue.clone(),
-                    publish_queue.clone(),
                 ))
                 .detach();
         }
@@ -110,32 +280,87 @@ impl GatewayService {
 
     async fn handle_request(
         msg: (PeerId, Request),
-        slabstore: Arc<SlabStore>,
+        state: Arc<Mutex<State<K>>>,
+        mempool: Arc<Mutex<Mempool>>,
+        slabstore: Arc<SlabStore<K>>,
+        snapshots: Arc<RocksColumn<columns::Snapshots, K>>,
+        min_fees: Arc<Vec<(jubjub::Fr, u64)>>,
         send_queue: async_channel::Sender<(PeerId, Reply)>,
-        publish_queue: async_channel::Sender<Vec<u8>>,
     ) -> Result<()> {
         let request = msg.1;
         let peer = msg.0;
         match request.get_command() {
             0 => {
-                debug!(target: "GATEWAY DAEMON" ,"Received putslab msg");
-                // PUTSLAB
-                let slab = request.get_payload();
-
-                // add to slabstore
-                let error = slabstore.put(deserialize(&slab)?)?;
-
-                let mut reply = Reply::from(&request, GatewayError::NoError as u32, vec![]);
+                debug!(target: "GATEWAY DAEMON", "Received submittx msg");
+                // SUBMITTX
+                let tx = match Transaction::decode(&request.get_payload()[..]) {
+                    Ok(tx) => tx,
+                    Err(e) => {
+                        let reason = e.to_string().into_bytes();
+                        let reply =
+                            Reply::from(&request, GatewayError::VerifyFailed as u32, reason);
+                        send_queue.send((peer, reply)).await?;
+                        return Ok(());
+                    }
+                };
+
+                // Such a transaction can only have outputs worth nothing
+                if tx.clear_inputs.is_empty() && tx.inputs.is_empty() {
+                    let reason = b"Transaction has no inputs".to_vec();
+                    let reply = Reply::from(&request, GatewayError::VerifyFailed as u32, reason);
+                    send_queue.send((peer, reply)).await?;
+                    return Ok(());
+                }
 
-                if error.is_none() {
-                    reply.set_error(GatewayError::UpdateIndex as u32);
+                if !Self::pays_fee(&tx, &min_fees) {
+                    let reply = Reply::from(&request, GatewayError::FeeTooLow as u32, vec![]);
+                    send_queue.send((peer, reply)).await?;
+                    return Ok(());
                 }
 
-                // send reply
-                send_queue.send((peer, reply)).await?;
+                // The proofs are verified on another thread, before the
+                // state and the mempool are locked
+                let (mint_pvk, spend_pvk) = {
+                    let state = state.lock().await;
+                    (state.mint_pvk.clone(), state.spend_pvk.clone())
+                };
+                let tx_id = hex::encode(tx.id());
+                let verified =
+                    smol::unblock(move || tx.verify(&mint_pvk, &spend_pvk).map(|()| tx)).await;
+
+                let submitted = match verified {
+                    Ok(tx) => {
+                        let state = state.lock().await;
+                        mempool.lock().await.submit(&*state, tx)
+                    }
+                    Err(e) => Err(e),
+                };
+
+                let status = match submitted {
+                    Ok(status) => status,
+                    Err(reason) => {
+                        warn!(target: "GATEWAY DAEMON", "Reject transaction {}: {}", tx_id, reason);
+                        let reason = reason.to_string().into_bytes();
+                        let reply =
+                            Reply::from(&request, GatewayError::VerifyFailed as u32, reason);
+                        send_queue.send((peer, reply)).await?;
+                        return Ok(());
+                    }
+                };
+
+                // Only reply once the transaction is in a slab
+                let reply = match status.wait().await {
+                    TxStatus::Accepted(index) => {
+                        Reply::from(&request, GatewayError::NoError as u32, serialize(&index))
+                    }
+                    TxStatus::Rejected(reason) => Reply::from(
+                        &request,
+                        GatewayError::VerifyFailed as u32,
+                        reason.into_bytes(),
+                    ),
+                };
 
-                // publish to all subscribes
-                publish_queue.send(slab).await?;
+                send_queue.send((peer, reply)).await?;
             }
             1 => {
                 debug!(target: "GATEWAY DAEMON", "Received getslab msg");
@@ -163,17 +388,69 @@ impl GatewayService {
 
                 // GETLASTINDEX
             }
+            3 => {
+                debug!(target: "GATEWAY DAEMON", "Received getslabs msg");
+                let (start, end): (u64, u64) = deserialize(&request.get_payload())?;
+                let end = end.min(start.saturating_add(MAX_SLABS_PER_REQUEST - 1));
+                let slabs = slabstore.get_range(start, end)?;
+
+                let mut reply = Reply::from(&request, GatewayError::NoError as u32, vec![]);
+
+                if slabs.is_empty() {
+                    reply.set_error(GatewayError::IndexNotExist as u32);
+                } else {
+                    reply.set_payload(serialize(&slabs));
+                }
+
+                send_queue.send((peer, reply)).await?;
+
+                // GETSLABS
+            }
+            4 => {
+                debug!(target: "GATEWAY DAEMON", "Received getsnapshot msg");
+                let snapshot = snapshots.get(Snapshot::KEY)?;
+
+                let mut reply = Reply::from(&request, GatewayError::NoError as u32, vec![]);
+
+                if let Some(payload) = snapshot {
+                    reply.set_payload(payload);
+                } else {
+                    reply.set_error(GatewayError::IndexNotExist as u32);
+                }
+
+                send_queue.send((peer, reply)).await?;
+
+                // GETSNAPSHOT
+            }
             _ => {
                 return Err(Error::ServicesError("received wrong command"));
             }
         }
         Ok(())
     }
+
+    // Transactions have to pay at least the minimum fee of the token they
+    // pay it in, and tokens without a minimum are not accepted for fees.
+    // Only cashier deposits, which have clear inputs and spend no coins,
+    // create new coins without a fee.
+    //
+    // Fees are burned: they are taken out of the value commitments and no
+    // output pays them to the gateway or anyone else.
+    fn pays_fee(tx: &Transaction, min_fees: &[(jubjub::Fr, u64)]) -> bool {
+        let is_deposit = !tx.clear_inputs.is_empty() && tx.inputs.is_empty();
+        min_fees.is_empty()
+            || is_deposit
+            || min_fees
+                .iter()
+                .any(|(token_id, min_fee)| tx.fee.token_id == *token_id && tx.fee.value >= *min_fee)
+    }
 }
 
 pub struct GatewayClient {
     protocol: ReqProtocol,
+    addr: SocketAddr,
     slabstore: Arc<SlabStore>,
+    tip: Arc<Mutex<ChainTip>>,
     gateway_slabs_sub_s: async_channel::Sender<Slab>,
     gateway_slabs_sub_rv: GatewaySlabsSubscriber,
     is_running: bool,
@@ -190,6 +467,7 @@ impl GatewayClient {
         let protocol = ReqProtocol::new(addr_sock, String::from("GATEWAY CLIENT"));
 
         let slabstore = SlabStore::new(rocks)?;
+        let tip = Arc::new(Mutex::new(ChainTip::new(&slabstore)?));
 
         let (gateway_slabs_sub_s, gateway_slabs_sub_rv) = async_channel::unbounded::<Slab>();
 
@@ -203,7 +481,9 @@ impl GatewayClient {
 
         Ok(GatewayClient {
             protocol,
+            addr: addr_sock,
             slabstore,
+            tip,
             gateway_slabs_sub_s,
             gateway_slabs_sub_rv,
             is_running: false,
@@ -211,9 +491,9 @@ impl GatewayClient {
         })
     }
 
+    /// Connect to the gateway. Slabs are fetched once `sync` is called.
     pub async fn start(&mut self) -> Result<()> {
         self.protocol.start().await?;
-        self.sync().await?;
         self.is_running = true;
         Ok(())
     }
@@ -228,17 +508,19 @@ impl GatewayClient {
         if last_index < local_last_index {
             return Err(Error::SlabsStore(
                 "Local slabstore has higher index than gateway's slabstore.
-                 Run \" darkfid -r \" to refresh the database."
+                 Run \" darkfid --rollback <index> \" to roll back to the gateway's
+                 last index, or \" darkfid -r \" to refresh the database."
                     .into(),
             ));
         }
 
-        if last_index > 0 {
-            for index in (local_last_index + 1)..(last_index + 1) {
-                if self.get_slab(index).await?.is_none() {
-                    break;
-                }
+        let mut index = local_last_index + 1;
+        while index <= last_index {
+            let slabs = self.get_slabs(index, last_index).await?;
+            if slabs.is_empty() {
+                break;
             }
+            index += slabs.len() as u64;
         }
 
         debug!(target: "GATEWAY CLIENT","End Syncing");
@@ -260,34 +542,99 @@ impl GatewayClient {
 
         if let Some(slab) = rep {
             let slab: Slab = deserialize(&slab)?;
-            self.gateway_slabs_sub_s.send(slab.clone()).await?;
-            self.slabstore.put(slab.clone())?;
+            // The slab is stored once it has been applied to the state
+            if self.tip.lock().await.extend(&slab)? {
+                self.gateway_slabs_sub_s.send(slab.clone()).await?;
+            }
             return Ok(Some(slab));
         }
 
         Ok(None)
     }
 
-    pub async fn put_slab(&mut self, mut slab: Slab) -> Result<()> {
-        debug!(target: "GATEWAY CLIENT","Put slab");
+    /// Fetch the slabs in `start..=end`. The gateway may return fewer slabs
+    /// than requested, so callers should continue from the last one received.
+    pub async fn get_slabs(&mut self, start: u64, end: u64) -> Result<Vec<Slab>> {
+        debug!(target: "GATEWAY CLIENT","Get slabs");
 
-        loop {
-            let last_index = self.sync().await?;
-            slab.set_index(last_index + 1);
-            let slab = serialize(&slab);
+        let handle_error = Arc::new(handle_error);
+        let rep = self
+            .protocol
+            .request(
+                GatewayCommand::GetSlabs as u8,
+                serialize(&(start, end)),
+                handle_error,
+            )
+            .await?;
 
-            let handle_error = Arc::new(handle_error);
+        let mut slabs: Vec<Slab> = vec![];
 
-            let rep = self
-                .protocol
-                .request(GatewayCommand::PutSlab as u8, slab.clone(), handle_error)
-                .await?;
+        if let Some(rep) = rep {
+            slabs = deserialize(&rep)?;
+            // The slabs are stored once they have been applied to the state
+            let mut tip = self.tip.lock().await;
+            for slab in slabs.iter() {
+                if tip.extend(slab)? {
+                    self.gateway_slabs_sub_s.send(slab.clone()).await?;
+                }
+            }
+        }
 
-            if rep.is_some() {
-                break;
+        Ok(slabs)
+    }
+
+    /// Address of the gateway's request socket
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Send an encoded transaction to the gateway at `addr`. The gateway
+    /// checks it against its state and replies once the transaction is in a
+    /// slab, with the index of the slab, or with the reason it was rejected.
+    ///
+    /// That can take a while, so the transaction goes over a connection of
+    /// its own and other requests to the gateway are not held up.
+    pub async fn submit_tx(addr: SocketAddr, tx_id: [u8; 32], tx: Vec<u8>) -> Result<u64> {
+        debug!(target: "GATEWAY CLIENT","Submit tx");
+
+        let tx_id = hex::encode(tx_id);
+
+        let mut protocol = ReqProtocol::new(addr, String::from("GATEWAY CLIENT"));
+        protocol.start().await?;
+
+        let reply = match protocol
+            .request_reply(GatewayCommand::SubmitTx as u8, tx)
+            .await?
+        {
+            Some(reply) => reply,
+            None => {
+                return Err(Error::ClientFailed(format!(
+                    "Gateway sent no reply to transaction {}",
+                    tx_id
+                )))
             }
+        };
+
+        if !reply.has_error() {
+            return deserialize(&reply.get_payload());
         }
-        Ok(())
+
+        handle_error(reply.get_error());
+
+        let reason = match reply.get_error() {
+            code if code == GatewayError::FeeTooLow as u32 => {
+                "its fee is too low, or paid in a token the gateway takes no fees in".to_string()
+            }
+            code if code == GatewayError::VerifyFailed as u32 => {
+                String::from_utf8_lossy(&reply.get_payload()).into_owned()
+            }
+            code => format!("error code {}", code),
+        };
+
+        Err(Error::ClientFailed(format!(
+            "Gateway rejected transaction {}: {}",
+            tx_id, reason
+        )))
     }
 
     pub async fn get_last_index(&mut self) -> Result<u64> {
@@ -305,6 +652,30 @@ impl GatewayClient {
         Ok(0)
     }
 
+    /// Fetch the gateway's latest snapshot, if it has taken one.
+    pub async fn get_snapshot(&mut self) -> Result<Option<Snapshot>> {
+        debug!(target: "GATEWAY CLIENT","Get snapshot");
+
+        let handle_error = Arc::new(handle_error);
+
+        let rep = self
+            .protocol
+            .request(GatewayCommand::GetSnapshot as u8, vec![], handle_error)
+            .await?;
+
+        match rep {
+            Some(snapshot) => Ok(Some(deserialize(&snapshot)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Pick up the last stored slab again, after the slabstore was changed
+    /// outside of the gateway client.
+    pub async fn reload_tip(&self) -> Result<()> {
+        *self.tip.lock().await = ChainTip::new(&self.slabstore)?;
+        Ok(())
+    }
+
     pub fn get_slabstore(&self) -> Arc<SlabStore> {
         self.slabstore.clone()
     }
@@ -320,7 +691,7 @@ impl GatewayClient {
         executor
             .spawn(Self::subscribe_loop(
                 subscriber,
-                self.slabstore.clone(),
+                self.tip.clone(),
                 self.gateway_slabs_sub_s.clone(),
             ))
             .detach();
@@ -329,7 +700,7 @@ impl GatewayClient {
 
     async fn subscribe_loop(
         mut subscriber: Subscriber,
-        slabstore: Arc<SlabStore>,
+        tip: Arc<Mutex<ChainTip>>,
         gateway_slabs_sub_s: async_channel::Sender<Slab>,
     ) -> Result<()> {
         debug!(target: "GATEWAY CLIENT","Start subscribe loop");
@@ -337,8 +708,11 @@ impl GatewayClient {
         loop {
             let slab = subscriber.fetch::<Slab>().await?;
             debug!(target: "GATEWAY CLIENT","Received new slab");
-            gateway_slabs_sub_s.send(slab.clone()).await?;
-            slabstore.put(slab)?;
+            match tip.lock().await.extend(&slab) {
+                Ok(true) => gateway_slabs_sub_s.send(slab).await?,
+                Ok(false) => {}
+                Err(e) => warn!(target: "GATEWAY CLIENT", "Reject slab: {}", e),
+            }
         }
     }
 
@@ -349,12 +723,257 @@ impl GatewayClient {
 
 fn handle_error(status_code: u32) {
     match status_code {
-        1 => {
-            debug!(target: "GATEWAY SERVICE", "Reply has an Error: Index is not updated");
-        }
-        2 => {
+        code if code == GatewayError::IndexNotExist as u32 => {
             debug!(target: "GATEWAY SERVICE", "Reply has an Error: Index Not Exist");
         }
+        code if code == GatewayError::FeeTooLow as u32 => {
+            warn!(target: "GATEWAY SERVICE", "Reply has an Error: Fee Too Low");
+        }
+        code if code == GatewayError::VerifyFailed as u32 => {
+            warn!(target: "GATEWAY SERVICE", "Reply has an Error: Verify Failed");
+        }
         _ => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bellman::groth16;
+    use bls12_381::Bls12;
+    use ff::Field;
+    use rand::rngs::OsRng;
+
+    use crate::blockchain::MemStore;
+    use crate::crypto::{keys::Address, setup_mint_prover, setup_spend_prover, PreparedKey};
+    use crate::tx;
+
+    // A deposit of 110 to a new address, signed as a cashier would
+    fn deposit(
+        mint_params: &groth16::Parameters<Bls12>,
+        spend_params: &groth16::Parameters<Bls12>,
+        signature_secret: jubjub::Fr,
+    ) -> Transaction {
+        let token_id = jubjub::Fr::random(&mut OsRng);
+        tx::TransactionBuilder::new(
+            vec![tx::TransactionBuilderClearInputInfo {
+                value: 110,
+                token_id,
+                signature_secret,
+            }],
+            vec![],
+            vec![tx::TransactionBuilderOutputInfo {
+                value: 110,
+                token_id,
+                address: Address::from_secret(&jubjub::Fr::random(&mut OsRng)),
+                memo: Default::default(),
+            }],
+        )
+        .build(mint_params, spend_params)
+    }
+
+    #[test]
+    fn test_build_slabs_after_state_moved_on() -> Result<()> {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+
+        let cashier_secret = jubjub::Fr::random(&mut OsRng);
+        let cashier_public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * cashier_secret;
+
+        let state = Mutex::new(State::new(
+            MemStore::new(),
+            PreparedKey::new(&mint_params.vk),
+            PreparedKey::new(&spend_params.vk),
+            vec![cashier_public],
+        )?);
+        let first = Mutex::new(Mempool::new(MEMPOOL_MAX_SIZE));
+        let second = Mutex::new(Mempool::new(MEMPOOL_MAX_SIZE));
+
+        async_std::task::block_on(async {
+            // Both are submitted while the state is still empty
+            let status = {
+                let guard = state.lock().await;
+                let tx = deposit(&mint_params, &spend_params, cashier_secret);
+                let status = first.lock().await.submit(&*guard, tx)?;
+                let tx = deposit(&mint_params, &spend_params, cashier_secret);
+                second.lock().await.submit(&*guard, tx)?;
+                status
+            };
+
+            let earlier = GatewayService::<MemStore>::build_slabs(&state, &second).await?;
+            assert_eq!(earlier.len(), 1);
+            assert!(earlier[0].extends(None));
+
+            // The header is built against the state as it is now
+            let later = GatewayService::<MemStore>::build_slabs(&state, &first).await?;
+            assert_eq!(later.len(), 1);
+            assert!(later[0].extends(Some(&earlier[0])));
+
+            match status.wait().await {
+                TxStatus::Accepted(2) => {}
+                _ => panic!("The transaction was not put into slab 2"),
+            }
+
+            let slabstore = state.lock().await.slabstore.clone();
+            assert_eq!(slabstore.get_last_index()?, 2);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_invalid_tx_is_refused() -> Result<()> {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+
+        let cashier_secret = jubjub::Fr::random(&mut OsRng);
+        let cashier_public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * cashier_secret;
+
+        let state = State::new(
+            MemStore::new(),
+            PreparedKey::new(&mint_params.vk),
+            PreparedKey::new(&spend_params.vk),
+            vec![cashier_public],
+        )?;
+        let slabstore = state.slabstore.clone();
+        let state = Arc::new(Mutex::new(state));
+        let mempool = Arc::new(Mutex::new(Mempool::new(MEMPOOL_MAX_SIZE)));
+        let snapshots = Arc::new(RocksColumn::new(MemStore::new()));
+
+        // Signed by a key which is not a cashier's
+        let tx = deposit(&mint_params, &spend_params, jubjub::Fr::random(&mut OsRng));
+        let request = Request::new(GatewayCommand::SubmitTx as u8, serialize(&tx));
+
+        let (send_queue, replies) = async_channel::unbounded();
+
+        async_std::task::block_on(async {
+            GatewayService::<MemStore>::handle_request(
+                (vec![], request),
+                state.clone(),
+                mempool.clone(),
+                slabstore.clone(),
+                snapshots,
+                Arc::new(vec![]),
+                send_queue,
+            )
+            .await?;
+
+            let (_, reply) = replies.recv().await?;
+            assert_eq!(reply.get_error(), GatewayError::VerifyFailed as u32);
+
+            // Nothing is left to put into a slab and publish
+            assert!(mempool.lock().await.is_empty());
+            let slabs = GatewayService::<MemStore>::build_slabs(&state, &mempool).await?;
+            assert!(slabs.is_empty());
+            assert_eq!(slabstore.get_last_index()?, 0);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_tx_without_inputs_is_refused() -> Result<()> {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+
+        let cashier_secret = jubjub::Fr::random(&mut OsRng);
+        let cashier_public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * cashier_secret;
+
+        let state = State::new(
+            MemStore::new(),
+            PreparedKey::new(&mint_params.vk),
+            PreparedKey::new(&spend_params.vk),
+            vec![cashier_public],
+        )?;
+        let slabstore = state.slabstore.clone();
+        let state = Arc::new(Mutex::new(state));
+        let mempool = Arc::new(Mutex::new(Mempool::new(MEMPOOL_MAX_SIZE)));
+        let snapshots = Arc::new(RocksColumn::new(MemStore::new()));
+        let min_fees = vec![(jubjub::Fr::random(&mut OsRng), 10)];
+
+        // Balances and verifies, but only mints a coin worth nothing
+        let tx = tx::TransactionBuilder::new(
+            vec![],
+            vec![],
+            vec![tx::TransactionBuilderOutputInfo {
+                value: 0,
+                token_id: jubjub::Fr::random(&mut OsRng),
+                address: Address::from_secret(&jubjub::Fr::random(&mut OsRng)),
+                memo: Default::default(),
+            }],
+        )
+        .build(&mint_params, &spend_params);
+        assert!(!GatewayService::<MemStore>::pays_fee(&tx, &min_fees));
+
+        // Deposits still pay no fee
+        let deposit = deposit(&mint_params, &spend_params, cashier_secret);
+        assert!(GatewayService::<MemStore>::pays_fee(&deposit, &min_fees));
+
+        let request = Request::new(GatewayCommand::SubmitTx as u8, serialize(&tx));
+        let (send_queue, replies) = async_channel::unbounded();
+
+        async_std::task::block_on(async {
+            GatewayService::<MemStore>::handle_request(
+                (vec![], request),
+                state,
+                mempool.clone(),
+                slabstore,
+                snapshots,
+                Arc::new(min_fees),
+                send_queue,
+            )
+            .await?;
+
+            let (_, reply) = replies.recv().await?;
+            assert_eq!(reply.get_error(), GatewayError::VerifyFailed as u32);
+            assert!(mempool.lock().await.is_empty());
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_snapshot_from_state() -> Result<()> {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+
+        let cashier_secret = jubjub::Fr::random(&mut OsRng);
+        let cashier_public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * cashier_secret;
+
+        let state = Mutex::new(State::new(
+            MemStore::new(),
+            PreparedKey::new(&mint_params.vk),
+            PreparedKey::new(&spend_params.vk),
+            vec![cashier_public],
+        )?);
+        let mempool = Mutex::new(Mempool::new(MEMPOOL_MAX_SIZE));
+
+        async_std::task::block_on(async {
+            let mut slabs = vec![];
+            for _ in 0..2 {
+                let tx = deposit(&mint_params, &spend_params, cashier_secret);
+                mempool.lock().await.submit(&*state.lock().await, tx)?;
+                slabs.append(&mut GatewayService::<MemStore>::build_slabs(&state, &mempool).await?);
+            }
+            assert_eq!(slabs.len(), 2);
+
+            let state = state.lock().await;
+
+            // Slab 2 is taken back out of the state
+            let snapshot = state.snapshot(1)?;
+            assert_eq!(snapshot.get_index(), 1);
+            assert!(snapshot.verify());
+            assert_eq!(snapshot.tree.root(), slabs[0].get_header().merkle_root);
+            assert_eq!(snapshot.merkle_roots.len(), 1);
+
+            let snapshot = state.snapshot(2)?;
+            assert!(snapshot.verify());
+            assert_eq!(snapshot.merkle_roots.len(), 2);
+
+            assert!(state.snapshot(3).is_err());
+
+            Ok(())
+        })
+    }
+}
@@ -164,6 +164,20 @@ impl ReqProtocol {
         data: Vec<u8>,
         handle_error: Arc<dyn Fn(u32) + Send + Sync>,
     ) -> Result<Option<Vec<u8>>> {
+        match self.request_reply(command, data).await? {
+            Some(reply) if reply.has_error() => {
+                handle_error(reply.get_error());
+                Ok(None)
+            }
+            Some(reply) => Ok(Some(reply.get_payload())),
+            None => Ok(None),
+        }
+    }
+
+    /// Send a request and return the whole reply, so the caller can read the
+    /// payload of an error reply. Returns None if the reply does not belong
+    /// to the request.
+    pub async fn request_reply(&mut self, command: u8, data: Vec<u8>) -> Result<Option<Reply>> {
         let request = Request::new(command, data);
         let req = serialize(&request);
         let req = bytes::Bytes::from(req);
@@ -189,17 +203,12 @@ impl ReqProtocol {
                     reply.has_error()
                 );
 
-            if reply.has_error() {
-                handle_error(reply.get_error());
-                return Ok(None);
-            }
-
             if reply.get_id() != request.get_id() {
                 warn!("Reply id is not equal to Request id");
                 return Ok(None);
             }
 
-            Ok(Some(reply.get_payload()))
+            Ok(Some(reply))
         } else {
             Err(crate::Error::ZmqError(
                 "Couldn't parse ZmqMessage".to_string(),
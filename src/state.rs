@@ -1,24 +1,37 @@
 use std::fmt;
 
-use bellman::groth16;
-use bls12_381::Bls12;
 use log::debug;
 
 use crate::{
-    crypto::{coin::Coin, merkle_node::MerkleNode, note::EncryptedNote, nullifier::Nullifier},
-    tx,
+    crypto::{
+        coin::Coin, merkle_node::MerkleNode, note::EncryptedNote, nullifier::Nullifier, PreparedKey,
+    },
+    tx, Result,
 };
 
-pub trait ProgramState {
+/// What the checks of a transaction against the state need to know. These
+/// are all the checks except for the proofs and signatures.
+pub trait StateQuery {
     fn is_valid_cashier_public_key(&self, public: &jubjub::SubgroupPoint) -> bool;
     fn is_valid_merkle(&self, merkle: &MerkleNode) -> bool;
     fn nullifier_exists(&self, nullifier: &Nullifier) -> bool;
+    /// Index of the slab which revealed the nullifier, if it is known
+    fn nullifier_slab(&self, _nullifier: &Nullifier) -> Option<u64> {
+        None
+    }
+
+    /// Index of the last slab applied to the state. Transactions checked
+    /// against it go into the slab after it.
+    fn height(&self) -> Result<u64>;
+}
 
-    fn mint_pvk(&self) -> &groth16::PreparedVerifyingKey<Bls12>;
-    fn spend_pvk(&self) -> &groth16::PreparedVerifyingKey<Bls12>;
+pub trait ProgramState: StateQuery {
+    fn mint_pvk(&self) -> &PreparedKey;
+    fn spend_pvk(&self) -> &PreparedKey;
 }
 
 pub struct StateUpdate {
+    pub tx_id: [u8; 32],
     pub nullifiers: Vec<Nullifier>,
     pub coins: Vec<Coin>,
     pub enc_notes: Vec<EncryptedNote>,
@@ -30,29 +43,60 @@ pub type VerifyResult<T> = std::result::Result<T, VerifyFailed>;
 pub enum VerifyFailed {
     InvalidCashierKey(usize),
     InvalidMerkle(usize),
-    DuplicateNullifier(usize),
+    DuplicateNullifier(usize, Option<u64>),
+    DuplicateNullifierInTx(usize),
+    DuplicateNullifierInBatch(usize, usize),
+    PendingNullifier(usize),
+    // The mempool holds as many transactions as it can
+    MempoolFull,
     SpendProof(usize),
     MintProof(usize),
     ClearInputSignature(usize),
     InputSignature(usize),
     MissingFunds,
-    AssetMismatch,
+    NotYetValid(u64),
+    Expired(u64),
+    // The state could not be read
+    StateError(String),
 }
 
 impl std::error::Error for VerifyFailed {}
 
 impl fmt::Display for VerifyFailed {
     fn fmt(&self, f: &mut fmt::Formatter) -> std::fmt::Result {
-        match *self {
+        match self {
             VerifyFailed::InvalidCashierKey(i) => {
                 write!(f, "Invalid cashier public key for clear input {}", i)
             }
             VerifyFailed::InvalidMerkle(i) => {
                 write!(f, "Invalid merkle root for input {}", i)
             }
-            VerifyFailed::DuplicateNullifier(i) => {
+            VerifyFailed::DuplicateNullifier(i, None) => {
                 write!(f, "Duplicate nullifier for input {}", i)
             }
+            VerifyFailed::DuplicateNullifier(i, Some(slab)) => {
+                write!(
+                    f,
+                    "Duplicate nullifier for input {}, already spent in slab {}",
+                    i, slab
+                )
+            }
+            VerifyFailed::DuplicateNullifierInTx(i) => {
+                write!(f, "Input {} spends the same coin as an earlier input", i)
+            }
+            VerifyFailed::DuplicateNullifierInBatch(tx, i) => write!(
+                f,
+                "Input {} of transaction {} spends a coin spent earlier in the batch",
+                i, tx
+            ),
+            VerifyFailed::PendingNullifier(i) => write!(
+                f,
+                "Input {} spends a coin spent by a transaction in the mempool",
+                i
+            ),
+            VerifyFailed::MempoolFull => {
+                f.write_str("The mempool is full, try again after the next slab")
+            }
             VerifyFailed::SpendProof(i) => write!(f, "Spend proof for input {}", i),
             VerifyFailed::MintProof(i) => write!(f, "Mint proof for input {}", i),
             VerifyFailed::ClearInputSignature(i) => {
@@ -60,19 +104,135 @@ impl fmt::Display for VerifyFailed {
             }
             VerifyFailed::InputSignature(i) => write!(f, "Invalid signature for input {}", i),
             VerifyFailed::MissingFunds => {
-                f.write_str("Money in does not match money out (value commits)")
+                f.write_str("Money in does not match money out for every token (value commits)")
             }
-            VerifyFailed::AssetMismatch => {
-                f.write_str("Assets don't match some inputs or outputs (token commits)")
+            VerifyFailed::NotYetValid(index) => {
+                write!(f, "Transaction is only valid after slab {}", index)
             }
+            VerifyFailed::Expired(index) => write!(f, "Transaction expired at slab {}", index),
+            VerifyFailed::StateError(e) => write!(f, "Could not read the state: {}", e),
         }
     }
 }
 
 pub fn state_transition<S: ProgramState>(
-    state: &async_std::sync::MutexGuard<S>,
+    state: &S,
     tx: tx::Transaction,
 ) -> VerifyResult<StateUpdate> {
+    check_against_state(state, &tx, next_index(state)?)?;
+
+    debug!(target: "STATE TRANSITION", "Check the tx Verifies correctly");
+    // Check the tx verifies correctly
+    tx.verify(state.mint_pvk(), state.spend_pvk())?;
+
+    Ok(StateUpdate::from_tx(tx))
+}
+
+/// The part of `state_transition` which depends on the state, for a
+/// transaction that has already passed `Transaction::verify`, such as one
+/// checked on another thread during sync.
+pub fn verified_state_transition<S: StateQuery>(
+    state: &S,
+    tx: tx::Transaction,
+) -> VerifyResult<StateUpdate> {
+    check_against_state(state, &tx, next_index(state)?)?;
+    Ok(StateUpdate::from_tx(tx))
+}
+
+/// Run `state_transition` on transactions which are applied together, such
+/// as the transactions of one slab. On top of the checks for each of them,
+/// no two transactions in the batch may reveal the same nullifier. They can
+/// only spend coins which exist before the batch.
+pub fn batch_state_transition<S: ProgramState>(
+    state: &S,
+    txs: Vec<tx::Transaction>,
+) -> VerifyResult<Vec<StateUpdate>> {
+    let mut updates: Vec<StateUpdate> = vec![];
+
+    for (i, tx) in txs.into_iter().enumerate() {
+        let update = state_transition(state, tx)?;
+        check_spent_in_batch(i, &update, updates.iter())?;
+        updates.push(update);
+    }
+
+    Ok(updates)
+}
+
+/// `batch_state_transition` for transactions whose proofs and signatures
+/// have been verified already, such as the ones waiting in a mempool. An
+/// invalid transaction does not fail the batch: each one gets its own
+/// result, and only the valid ones count when looking for nullifiers
+/// revealed earlier in the batch.
+///
+/// Unlike `batch_state_transition`, every valid transaction goes into a
+/// slab of its own, in order, and its validity window is checked against
+/// the index of that slab.
+pub fn verified_batch_state_transition<S: StateQuery>(
+    state: &S,
+    txs: Vec<tx::Transaction>,
+) -> Vec<VerifyResult<StateUpdate>> {
+    let mut results: Vec<VerifyResult<StateUpdate>> = vec![];
+
+    for (i, tx) in txs.into_iter().enumerate() {
+        let earlier = results.iter().filter_map(|result| result.as_ref().ok());
+        let slabs_before = earlier.clone().count() as u64;
+        let result = next_index(state)
+            .and_then(|index| check_against_state(state, &tx, index + slabs_before))
+            .and_then(|()| {
+                let update = StateUpdate::from_tx(tx);
+                check_spent_in_batch(i, &update, earlier)?;
+                Ok(update)
+            });
+        results.push(result);
+    }
+
+    results
+}
+
+// Transaction `i` of a batch may not reveal a nullifier which one of the
+// transactions before it in the batch revealed
+fn check_spent_in_batch<'a>(
+    i: usize,
+    update: &StateUpdate,
+    earlier: impl Iterator<Item = &'a StateUpdate> + Clone,
+) -> VerifyResult<()> {
+    for (j, nullifier) in update.nullifiers.iter().enumerate() {
+        if earlier
+            .clone()
+            .any(|other| other.nullifiers.contains(nullifier))
+        {
+            return Err(VerifyFailed::DuplicateNullifierInBatch(i, j));
+        }
+    }
+    Ok(())
+}
+
+// Index of the slab after the last one applied to the state
+fn next_index<S: StateQuery>(state: &S) -> VerifyResult<u64> {
+    state
+        .height()
+        .map(|height| height + 1)
+        .map_err(|e| VerifyFailed::StateError(e.to_string()))
+}
+
+fn check_against_state<S: StateQuery>(
+    state: &S,
+    tx: &tx::Transaction,
+    index: u64,
+) -> VerifyResult<()> {
+    // Check the slab the tx would go into, at `index`, is inside its
+    // validity window
+    if let Some(valid_after) = tx.valid_after {
+        if index <= valid_after {
+            return Err(VerifyFailed::NotYetValid(valid_after));
+        }
+    }
+    if let Some(expires_at) = tx.expires_at {
+        if index > expires_at {
+            return Err(VerifyFailed::Expired(expires_at));
+        }
+    }
+
     // Check deposits are legit
 
     debug!(target: "STATE TRANSITION", "iterate clear_inputs");
@@ -104,31 +264,290 @@ pub fn state_transition<S: ProgramState>(
         let nullifier = &input.revealed.nullifier;
 
         if state.nullifier_exists(nullifier) {
-            return Err(VerifyFailed::DuplicateNullifier(i));
+            return Err(VerifyFailed::DuplicateNullifier(
+                i,
+                state.nullifier_slab(nullifier),
+            ));
+        }
+
+        // Nor should the transaction spend the same coin twice
+        if tx.inputs[..i]
+            .iter()
+            .any(|other| other.revealed.nullifier == *nullifier)
+        {
+            return Err(VerifyFailed::DuplicateNullifierInTx(i));
         }
     }
 
-    debug!(target: "STATE TRANSITION", "Check the tx Verifies correctly");
-    // Check the tx verifies correctly
-    tx.verify(state.mint_pvk(), state.spend_pvk())?;
+    Ok(())
+}
+
+impl StateUpdate {
+    fn from_tx(tx: tx::Transaction) -> Self {
+        let tx_id = tx.id();
+
+        let mut nullifiers = vec![];
+        for input in tx.inputs {
+            nullifiers.push(input.revealed.nullifier);
+        }
+
+        // Newly created coins for this tx
+        let mut coins = vec![];
+        let mut enc_notes = vec![];
+        for output in tx.outputs {
+            // Gather all the coins
+            coins.push(Coin::new(output.revealed.coin));
+            enc_notes.push(output.enc_note);
+        }
 
-    let mut nullifiers = vec![];
-    for input in tx.inputs {
-        nullifiers.push(input.revealed.nullifier);
+        Self {
+            tx_id,
+            nullifiers,
+            coins,
+            enc_notes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bellman::groth16;
+    use bls12_381::Bls12;
+    use ff::Field;
+    use rand::rngs::OsRng;
+
+    use crate::blockchain::MemStore;
+    use crate::client::State;
+    use crate::crypto::{
+        keys::{Address, ViewingKey},
+        merkle::{IncrementalWitness, MerklePath},
+        note::Note,
+        setup_mint_prover, setup_spend_prover,
+    };
+    use crate::Result;
+
+    // A state holding a single coin of value 110, returned with the secret,
+    // note and merkle path needed to spend it
+    fn state_with_coin(
+        mint_params: &groth16::Parameters<Bls12>,
+        spend_params: &groth16::Parameters<Bls12>,
+    ) -> Result<(State<MemStore>, jubjub::Fr, Note, MerklePath<MerkleNode>)> {
+        let cashier_secret = jubjub::Fr::random(&mut OsRng);
+        let cashier_public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * cashier_secret;
+        let secret = jubjub::Fr::random(&mut OsRng);
+        let address = Address::from_secret(&secret);
+
+        let mut state = State::new(
+            MemStore::new(),
+            PreparedKey::new(&mint_params.vk),
+            PreparedKey::new(&spend_params.vk),
+            vec![cashier_public],
+        )?;
+
+        let token_id = jubjub::Fr::random(&mut OsRng);
+        let deposit = tx::TransactionBuilder::new(
+            vec![tx::TransactionBuilderClearInputInfo {
+                value: 110,
+                token_id,
+                signature_secret: cashier_secret,
+            }],
+            vec![],
+            vec![tx::TransactionBuilderOutputInfo {
+                value: 110,
+                token_id,
+                address,
+                memo: Default::default(),
+            }],
+        )
+        .build(mint_params, spend_params);
+
+        let note = deposit.outputs[0]
+            .enc_note
+            .decrypt(&ViewingKey::from_secret(&secret))?;
+        let coin = Coin::new(deposit.outputs[0].revealed.coin);
+        state.tree.append(MerkleNode::from_coin(&coin))?;
+        state
+            .merkle_roots
+            .put(state.tree.root(), vec![] as Vec<u8>)?;
+        let merkle_path = IncrementalWitness::from_tree(&state.tree).path().unwrap();
+
+        Ok((state, secret, note, merkle_path))
+    }
+
+    // Spend the coin `times` times over in one transaction
+    fn spend_tx(
+        mint_params: &groth16::Parameters<Bls12>,
+        spend_params: &groth16::Parameters<Bls12>,
+        coin: &(jubjub::Fr, Note, MerklePath<MerkleNode>),
+        times: usize,
+    ) -> tx::Transaction {
+        let (secret, note, merkle_path) = coin;
+        let address = Address::from_secret(secret);
+
+        let inputs = (0..times)
+            .map(|_| tx::TransactionBuilderInputInfo {
+                merkle_path: merkle_path.clone(),
+                secret: *secret,
+                note: note.clone(),
+            })
+            .collect();
+
+        tx::TransactionBuilder::new(
+            vec![],
+            inputs,
+            vec![tx::TransactionBuilderOutputInfo {
+                value: note.value * times as u64,
+                token_id: note.token_id,
+                address,
+                memo: Default::default(),
+            }],
+        )
+        .build(mint_params, spend_params)
+    }
+
+    #[test]
+    fn test_state_transition_clear_input() -> Result<()> {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+
+        let cashier_secret = jubjub::Fr::random(&mut OsRng);
+        let cashier_public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * cashier_secret;
+
+        let state = State::new(
+            MemStore::new(),
+            PreparedKey::new(&mint_params.vk),
+            PreparedKey::new(&spend_params.vk),
+            vec![cashier_public],
+        )?;
+
+        let build_tx = |signature_secret| {
+            let token_id = jubjub::Fr::random(&mut OsRng);
+            let builder = tx::TransactionBuilder::new(
+                vec![tx::TransactionBuilderClearInputInfo {
+                    value: 110,
+                    token_id,
+                    signature_secret,
+                }],
+                vec![],
+                vec![tx::TransactionBuilderOutputInfo {
+                    value: 110,
+                    token_id,
+                    address: Address::from_secret(&cashier_secret),
+                    memo: Default::default(),
+                }],
+            );
+            builder.build(&mint_params, &spend_params)
+        };
+
+        let update = state_transition(&state, build_tx(cashier_secret))?;
+        assert!(update.nullifiers.is_empty());
+        assert_eq!(update.coins.len(), 1);
+
+        let other_secret = jubjub::Fr::random(&mut OsRng);
+        match state_transition(&state, build_tx(other_secret)) {
+            Err(VerifyFailed::InvalidCashierKey(0)) => {}
+            _ => panic!("Clear input from an unknown cashier was accepted"),
+        }
+
+        Ok(())
     }
 
-    // Newly created coins for this tx
-    let mut coins = vec![];
-    let mut enc_notes = vec![];
-    for output in tx.outputs {
-        // Gather all the coins
-        coins.push(Coin::new(output.revealed.coin));
-        enc_notes.push(output.enc_note);
+    #[test]
+    fn test_duplicate_nullifier_in_tx() -> Result<()> {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+
+        let (state, secret, note, merkle_path) = state_with_coin(&mint_params, &spend_params)?;
+        let coin = (secret, note, merkle_path);
+
+        assert!(state_transition(&state, spend_tx(&mint_params, &spend_params, &coin, 1)).is_ok());
+
+        match state_transition(&state, spend_tx(&mint_params, &spend_params, &coin, 2)) {
+            Err(VerifyFailed::DuplicateNullifierInTx(1)) => {}
+            _ => panic!("A transaction spent the same coin twice"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_duplicate_nullifier_in_batch() -> Result<()> {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+
+        let (state, secret, note, merkle_path) = state_with_coin(&mint_params, &spend_params)?;
+        let coin = (secret, note, merkle_path);
+
+        let txs = vec![spend_tx(&mint_params, &spend_params, &coin, 1)];
+        assert_eq!(batch_state_transition(&state, txs)?.len(), 1);
+
+        let txs = vec![
+            spend_tx(&mint_params, &spend_params, &coin, 1),
+            spend_tx(&mint_params, &spend_params, &coin, 1),
+        ];
+        match batch_state_transition(&state, txs) {
+            Err(VerifyFailed::DuplicateNullifierInBatch(1, 0)) => {}
+            _ => panic!("Two transactions in a batch spent the same coin"),
+        }
+
+        let txs = vec![
+            spend_tx(&mint_params, &spend_params, &coin, 1),
+            spend_tx(&mint_params, &spend_params, &coin, 1),
+        ];
+        let results = verified_batch_state_transition(&state, txs);
+        assert!(results[0].is_ok());
+        match results[1] {
+            Err(VerifyFailed::DuplicateNullifierInBatch(1, 0)) => {}
+            _ => panic!("Two transactions in a batch spent the same coin"),
+        }
+
+        Ok(())
     }
 
-    Ok(StateUpdate {
-        nullifiers,
-        coins,
-        enc_notes,
-    })
+    #[test]
+    fn test_transaction_validity_window() -> Result<()> {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+
+        let (state, secret, note, merkle_path) = state_with_coin(&mint_params, &spend_params)?;
+
+        // Nothing is applied yet, so the transaction would go into slab 1
+        let build_tx = |valid_after, expires_at| {
+            tx::TransactionBuilder {
+                valid_after,
+                expires_at,
+                ..tx::TransactionBuilder::new(
+                    vec![],
+                    vec![tx::TransactionBuilderInputInfo {
+                        merkle_path: merkle_path.clone(),
+                        secret,
+                        note: note.clone(),
+                    }],
+                    vec![tx::TransactionBuilderOutputInfo {
+                        value: note.value,
+                        token_id: note.token_id,
+                        address: Address::from_secret(&secret),
+                        memo: Default::default(),
+                    }],
+                )
+            }
+            .build(&mint_params, &spend_params)
+        };
+
+        assert!(state_transition(&state, build_tx(Some(0), Some(1))).is_ok());
+
+        match state_transition(&state, build_tx(Some(1), None)) {
+            Err(VerifyFailed::NotYetValid(1)) => {}
+            _ => panic!("A transaction was accepted before its slab"),
+        }
+
+        match state_transition(&state, build_tx(None, Some(0))) {
+            Err(VerifyFailed::Expired(0)) => {}
+            _ => panic!("An expired transaction was accepted"),
+        }
+
+        Ok(())
+    }
 }
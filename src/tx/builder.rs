@@ -1,14 +1,18 @@
 use bellman::groth16;
 use bls12_381::Bls12;
 use ff::Field;
-use rand::rngs::OsRng;
+use rand::{rngs::OsRng, RngCore};
 
 use super::{
     partial::{PartialTransaction, PartialTransactionClearInput, PartialTransactionInput},
-    Transaction, TransactionClearInput, TransactionInput, TransactionOutput,
+    Transaction, TransactionClearInput, TransactionFee, TransactionInput, TransactionOutput,
 };
 use crate::crypto::{
-    create_mint_proof, create_spend_proof, merkle::MerklePath, merkle_node::MerkleNode, note::Note,
+    create_mint_proof, create_spend_proof,
+    keys::{Address, OutgoingViewingKey},
+    merkle::MerklePath,
+    merkle_node::MerkleNode,
+    note::{Memo, Note},
     schnorr,
 };
 use crate::serial::Encodable;
@@ -17,6 +21,15 @@ pub struct TransactionBuilder {
     pub clear_inputs: Vec<TransactionBuilderClearInputInfo>,
     pub inputs: Vec<TransactionBuilderInputInfo>,
     pub outputs: Vec<TransactionBuilderOutputInfo>,
+    /// Paid on top of the outputs, so the inputs have to cover both
+    pub fee: TransactionFee,
+    /// Slab index the transaction is only valid after
+    pub valid_after: Option<u64>,
+    /// Last slab index the transaction can be included in
+    pub expires_at: Option<u64>,
+    /// Lets the sender recover the outputs from the slabs. Without one
+    /// only the recipients can decrypt them.
+    pub ovk: Option<OutgoingViewingKey>,
 }
 
 pub struct TransactionBuilderClearInputInfo {
@@ -34,10 +47,29 @@ pub struct TransactionBuilderInputInfo {
 pub struct TransactionBuilderOutputInfo {
     pub value: u64,
     pub token_id: jubjub::Fr,
-    pub public: jubjub::SubgroupPoint,
+    pub address: Address,
+    pub memo: Memo,
 }
 
 impl TransactionBuilder {
+    /// A transaction with no fee, no validity window and no outgoing
+    /// viewing key. Set the fields to add them.
+    pub fn new(
+        clear_inputs: Vec<TransactionBuilderClearInputInfo>,
+        inputs: Vec<TransactionBuilderInputInfo>,
+        outputs: Vec<TransactionBuilderOutputInfo>,
+    ) -> Self {
+        Self {
+            clear_inputs,
+            inputs,
+            outputs,
+            fee: TransactionFee::none(),
+            valid_after: None,
+            expires_at: None,
+            ovk: None,
+        }
+    }
+
     fn compute_remainder_blind(
         clear_inputs: &[PartialTransactionClearInput],
         input_blinds: &[jubjub::Fr],
@@ -66,7 +98,6 @@ impl TransactionBuilder {
         spend_params: &groth16::Parameters<Bls12>,
     ) -> Transaction {
         let mut clear_inputs = vec![];
-        let token_commit_blind: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
         for input in &self.clear_inputs {
             let signature_public =
                 zcash_primitives::constants::SPENDING_KEY_GENERATOR * input.signature_secret;
@@ -76,7 +107,6 @@ impl TransactionBuilder {
                 value: input.value,
                 token_id: input.token_id,
                 valcom_blind,
-                token_commit_blind,
                 signature_public,
             };
             clear_inputs.push(clear_input);
@@ -105,7 +135,6 @@ impl TransactionBuilder {
                 input.note.value,
                 input.note.token_id,
                 input.note.valcom_blind,
-                token_commit_blind,
                 input.note.serial,
                 input.note.coin_blind,
                 input.secret,
@@ -127,6 +156,12 @@ impl TransactionBuilder {
         let mut outputs = vec![];
         let mut output_blinds = vec![];
 
+        let ovk = self.ovk.unwrap_or_else(|| {
+            let mut ovk = [0u8; 32];
+            OsRng.fill_bytes(&mut ovk);
+            OutgoingViewingKey(ovk)
+        });
+
         for (i, output) in self.outputs.iter().enumerate() {
             let valcom_blind = if i == self.outputs.len() - 1 {
                 Self::compute_remainder_blind(&clear_inputs, &input_blinds, &output_blinds)
@@ -143,10 +178,9 @@ impl TransactionBuilder {
                 output.value,
                 output.token_id,
                 valcom_blind,
-                token_commit_blind,
                 serial,
                 coin_blind,
-                output.public,
+                output.address.public,
             );
 
             // Encrypted note
@@ -157,9 +191,10 @@ impl TransactionBuilder {
                 token_id: output.token_id,
                 coin_blind,
                 valcom_blind,
+                memo: output.memo.clone(),
             };
 
-            let encrypted_note = note.encrypt(&output.public).unwrap();
+            let encrypted_note = note.encrypt(&output.address, &ovk).unwrap();
 
             let output = TransactionOutput {
                 mint_proof,
@@ -173,6 +208,9 @@ impl TransactionBuilder {
             clear_inputs,
             inputs,
             outputs,
+            fee: self.fee,
+            valid_after: self.valid_after,
+            expires_at: self.expires_at,
         };
 
         let mut unsigned_tx_data = vec![];
@@ -203,6 +241,9 @@ impl TransactionBuilder {
             clear_inputs,
             inputs,
             outputs: partial_tx.outputs,
+            fee: partial_tx.fee,
+            valid_after: partial_tx.valid_after,
+            expires_at: partial_tx.expires_at,
         }
     }
 }
@@ -2,14 +2,15 @@ pub mod builder;
 pub mod partial;
 
 use bellman::groth16;
+use blake2b_simd::Params as Blake2bParams;
 use bls12_381::Bls12;
 use group::Group;
 use std::io;
 
 use self::partial::{PartialTransactionClearInput, PartialTransactionInput};
 use crate::crypto::{
-    note::EncryptedNote, schnorr, verify_mint_proof, verify_spend_proof, MintRevealedValues,
-    SpendRevealedValues,
+    note::EncryptedNote, schnorr, util::value_commit, verify_mint_proof, verify_spend_proof,
+    BatchVerifier, MintRevealedValues, PreparedKey, SpendRevealedValues,
 };
 use crate::error::Result;
 use crate::impl_vec;
@@ -21,17 +22,43 @@ pub use self::builder::{
     TransactionBuilderOutputInfo,
 };
 
+/// Personalization of the hash that gives a transaction its ID
+pub const TX_ID_PERSONALIZATION: &[u8; 16] = b"DarkFi_TxId_____";
+
 pub struct Transaction {
     pub clear_inputs: Vec<TransactionClearInput>,
     pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>,
+    pub fee: TransactionFee,
+    /// Only valid in slabs after this index
+    pub valid_after: Option<u64>,
+    /// Only valid in slabs up to and including this index
+    pub expires_at: Option<u64>,
+}
+
+/// Value a transaction pays to the network. It is public, so gateways can
+/// check it, and is taken out of the value commitments when they are summed.
+#[derive(Clone, Copy)]
+pub struct TransactionFee {
+    pub value: u64,
+    pub token_id: jubjub::Fr,
+}
+
+impl TransactionFee {
+    /// No fee, as a cashier's deposit pays. The token is zero, which is not
+    /// a real token, but a zero value commits to nothing in any token.
+    pub fn none() -> Self {
+        Self {
+            value: 0,
+            token_id: jubjub::Fr::zero(),
+        }
+    }
 }
 
 pub struct TransactionClearInput {
     pub value: u64,
     pub token_id: jubjub::Fr,
     pub valcom_blind: jubjub::Fr,
-    pub token_commit_blind: jubjub::Fr,
     pub signature_public: jubjub::SubgroupPoint,
     pub signature: schnorr::Signature,
 }
@@ -49,72 +76,114 @@ pub struct TransactionOutput {
 }
 
 impl Transaction {
+    /// Canonical ID of the transaction: the BLAKE2b hash of its encoding,
+    /// signatures included.
+    pub fn id(&self) -> [u8; 32] {
+        let mut data = vec![];
+        self.encode(&mut data).expect("encode tx");
+
+        let mut id = [0u8; 32];
+        id.copy_from_slice(
+            Blake2bParams::new()
+                .hash_length(32)
+                .personal(TX_ID_PERSONALIZATION)
+                .hash(&data)
+                .as_bytes(),
+        );
+        id
+    }
+
     fn encode_without_signature<S: io::Write>(&self, mut s: S) -> Result<usize> {
         let mut len = 0;
         len += self.clear_inputs.encode_without_signature(&mut s)?;
         len += self.inputs.encode_without_signature(&mut s)?;
-        len += self.outputs.encode(s)?;
+        len += self.outputs.encode(&mut s)?;
+        len += self.fee.encode(&mut s)?;
+        len += self.valid_after.encode(&mut s)?;
+        len += self.expires_at.encode(s)?;
         Ok(len)
     }
 
-    fn compute_pedersen_commit(value: jubjub::Fr, blind: &jubjub::Fr) -> jubjub::SubgroupPoint {
-        (zcash_primitives::constants::VALUE_COMMITMENT_VALUE_GENERATOR * value)
-            + (zcash_primitives::constants::VALUE_COMMITMENT_RANDOMNESS_GENERATOR * blind)
+    /// Queue the spend and mint proofs of the transaction, so they can be
+    /// checked together with those of other transactions.
+    pub fn queue_proofs<'a>(
+        &'a self,
+        batch: &mut BatchVerifier<'a>,
+        mint_pvk: &'a PreparedKey,
+        spend_pvk: &'a PreparedKey,
+    ) {
+        for input in self.inputs.iter() {
+            batch.queue(
+                spend_pvk,
+                &input.spend_proof,
+                &input.revealed.make_outputs(),
+            );
+        }
+        for output in self.outputs.iter() {
+            batch.queue(
+                mint_pvk,
+                &output.mint_proof,
+                &output.revealed.make_outputs(),
+            );
+        }
     }
 
-    fn verify_token_commitments(&self) -> bool {
-        assert_ne!(self.outputs.len(), 0);
-        let token_commit_value = self.outputs[0].revealed.token_commit;
-
-        let mut failed = self
-            .inputs
-            .iter()
-            .any(|input| input.revealed.token_commit != token_commit_value);
-        failed = failed
-            || self
-                .outputs
-                .iter()
-                .any(|output| output.revealed.token_commit != token_commit_value);
-        failed = failed
-            || self.clear_inputs.iter().any(|input| {
-                Self::compute_pedersen_commit(input.token_id, &input.token_commit_blind)
-                    != token_commit_value
-            });
-        !failed
+    /// Check the proofs one at a time, reporting the first invalid one.
+    pub fn verify_proofs(
+        &self,
+        mint_pvk: &PreparedKey,
+        spend_pvk: &PreparedKey,
+    ) -> state::VerifyResult<()> {
+        for (i, input) in self.inputs.iter().enumerate() {
+            if !verify_spend_proof(spend_pvk.pvk(), &input.spend_proof, &input.revealed) {
+                return Err(state::VerifyFailed::SpendProof(i));
+            }
+        }
+        for (i, output) in self.outputs.iter().enumerate() {
+            if !verify_mint_proof(mint_pvk.pvk(), &output.mint_proof, &output.revealed) {
+                return Err(state::VerifyFailed::MintProof(i));
+            }
+        }
+        Ok(())
     }
 
     pub fn verify(
         &self,
-        mint_pvk: &groth16::PreparedVerifyingKey<Bls12>,
-        spend_pvk: &groth16::PreparedVerifyingKey<Bls12>,
+        mint_pvk: &PreparedKey,
+        spend_pvk: &PreparedKey,
     ) -> state::VerifyResult<()> {
+        // All the proofs are checked in one go, and only when that fails
+        // one by one to find which of them is invalid
+        let mut batch = BatchVerifier::new();
+        self.queue_proofs(&mut batch, mint_pvk, spend_pvk);
+        if !batch.verify() {
+            self.verify_proofs(mint_pvk, spend_pvk)?;
+        }
+
+        self.verify_without_proofs()
+    }
+
+    /// Everything `verify` checks except the proofs, for when they have
+    /// already been checked in a batch.
+    pub fn verify_without_proofs(&self) -> state::VerifyResult<()> {
+        // Values are committed to with a different base for every token,
+        // so the commitments only add up to zero when each token balances
         let mut valcom_total = jubjub::SubgroupPoint::identity();
         for input in &self.clear_inputs {
-            let value = jubjub::Fr::from(input.value);
-            valcom_total += Self::compute_pedersen_commit(value, &input.valcom_blind);
+            valcom_total += value_commit(input.value, &input.token_id, &input.valcom_blind);
         }
-        for (i, input) in self.inputs.iter().enumerate() {
-            if !verify_spend_proof(spend_pvk, &input.spend_proof, &input.revealed) {
-                return Err(state::VerifyFailed::SpendProof(i));
-            }
+        for input in self.inputs.iter() {
             valcom_total += &input.revealed.value_commit;
         }
-        for (i, output) in self.outputs.iter().enumerate() {
-            if !verify_mint_proof(mint_pvk, &output.mint_proof, &output.revealed) {
-                return Err(state::VerifyFailed::MintProof(i));
-            }
+        for output in self.outputs.iter() {
             valcom_total -= &output.revealed.value_commit;
         }
+        valcom_total -= value_commit(self.fee.value, &self.fee.token_id, &jubjub::Fr::zero());
 
         if valcom_total != jubjub::SubgroupPoint::identity() {
             return Err(state::VerifyFailed::MissingFunds);
         }
 
-        // Verify token commitments match
-        if !self.verify_token_commitments() {
-            return Err(state::VerifyFailed::AssetMismatch);
-        }
-
         // Verify signatures
         let mut unsigned_tx_data = vec![];
         self.encode_without_signature(&mut unsigned_tx_data)
@@ -136,13 +205,42 @@ impl Transaction {
     }
 }
 
+/// Check the proofs of many transactions, such as those of a range of
+/// slabs, in one batch. If the batch fails each transaction is checked on
+/// its own, so the valid ones are still accepted.
+pub fn verify_proofs_batch(
+    txs: &[&Transaction],
+    mint_pvk: &PreparedKey,
+    spend_pvk: &PreparedKey,
+) -> Vec<state::VerifyResult<()>> {
+    let mut batch = BatchVerifier::new();
+    for tx in txs {
+        tx.queue_proofs(&mut batch, mint_pvk, spend_pvk);
+    }
+
+    if batch.verify() {
+        return txs.iter().map(|_| Ok(())).collect();
+    }
+
+    txs.iter()
+        .map(|tx| {
+            let mut batch = BatchVerifier::new();
+            tx.queue_proofs(&mut batch, mint_pvk, spend_pvk);
+            if batch.verify() {
+                Ok(())
+            } else {
+                tx.verify_proofs(mint_pvk, spend_pvk)
+            }
+        })
+        .collect()
+}
+
 impl TransactionClearInput {
     fn from_partial(partial: PartialTransactionClearInput, signature: schnorr::Signature) -> Self {
         Self {
             value: partial.value,
             token_id: partial.token_id,
             valcom_blind: partial.valcom_blind,
-            token_commit_blind: partial.token_commit_blind,
             signature_public: partial.signature_public,
             signature,
         }
@@ -153,7 +251,6 @@ impl TransactionClearInput {
         len += self.value.encode(&mut s)?;
         len += self.token_id.encode(&mut s)?;
         len += self.valcom_blind.encode(&mut s)?;
-        len += self.token_commit_blind.encode(&mut s)?;
         len += self.signature_public.encode(s)?;
         Ok(len)
     }
@@ -181,7 +278,10 @@ impl Encodable for Transaction {
         let mut len = 0;
         len += self.clear_inputs.encode(&mut s)?;
         len += self.inputs.encode(&mut s)?;
-        len += self.outputs.encode(s)?;
+        len += self.outputs.encode(&mut s)?;
+        len += self.fee.encode(&mut s)?;
+        len += self.valid_after.encode(&mut s)?;
+        len += self.expires_at.encode(s)?;
         Ok(len)
     }
 }
@@ -191,7 +291,28 @@ impl Decodable for Transaction {
         Ok(Self {
             clear_inputs: Decodable::decode(&mut d)?,
             inputs: Decodable::decode(&mut d)?,
-            outputs: Decodable::decode(d)?,
+            outputs: Decodable::decode(&mut d)?,
+            fee: Decodable::decode(&mut d)?,
+            valid_after: Decodable::decode(&mut d)?,
+            expires_at: Decodable::decode(d)?,
+        })
+    }
+}
+
+impl Encodable for TransactionFee {
+    fn encode<S: io::Write>(&self, mut s: S) -> Result<usize> {
+        let mut len = 0;
+        len += self.value.encode(&mut s)?;
+        len += self.token_id.encode(s)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for TransactionFee {
+    fn decode<D: io::Read>(mut d: D) -> Result<Self> {
+        Ok(Self {
+            value: Decodable::decode(&mut d)?,
+            token_id: Decodable::decode(d)?,
         })
     }
 }
@@ -202,7 +323,6 @@ impl Encodable for TransactionClearInput {
         len += self.value.encode(&mut s)?;
         len += self.token_id.encode(&mut s)?;
         len += self.valcom_blind.encode(&mut s)?;
-        len += self.token_commit_blind.encode(&mut s)?;
         len += self.signature_public.encode(&mut s)?;
         len += self.signature.encode(s)?;
         Ok(len)
@@ -215,7 +335,6 @@ impl Decodable for TransactionClearInput {
             value: Decodable::decode(&mut d)?,
             token_id: Decodable::decode(&mut d)?,
             valcom_blind: Decodable::decode(&mut d)?,
-            token_commit_blind: Decodable::decode(&mut d)?,
             signature_public: Decodable::decode(&mut d)?,
             signature: Decodable::decode(d)?,
         })
@@ -287,3 +406,160 @@ impl_vec_without_signature!(TransactionInput);
 impl_vec!(TransactionClearInput);
 impl_vec!(TransactionInput);
 impl_vec!(TransactionOutput);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ff::Field;
+    use rand::rngs::OsRng;
+
+    use crate::crypto::{keys::Address, setup_mint_prover, setup_spend_prover};
+    use crate::state::VerifyFailed;
+
+    #[test]
+    fn test_multi_asset_transaction() {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+        let mint_pvk = PreparedKey::new(&mint_params.vk);
+        let spend_pvk = PreparedKey::new(&spend_params.vk);
+
+        let secret = jubjub::Fr::random(&mut OsRng);
+        let address = Address::from_secret(&secret);
+        let token_a = jubjub::Fr::random(&mut OsRng);
+        let token_b = jubjub::Fr::random(&mut OsRng);
+
+        let build_tx = |outputs: &[(u64, jubjub::Fr)]| {
+            let builder = TransactionBuilder::new(
+                vec![
+                    TransactionBuilderClearInputInfo {
+                        value: 110,
+                        token_id: token_a,
+                        signature_secret: secret,
+                    },
+                    TransactionBuilderClearInputInfo {
+                        value: 50,
+                        token_id: token_b,
+                        signature_secret: secret,
+                    },
+                ],
+                vec![],
+                outputs
+                    .iter()
+                    .map(|(value, token_id)| TransactionBuilderOutputInfo {
+                        value: *value,
+                        token_id: *token_id,
+                        address,
+                        memo: Default::default(),
+                    })
+                    .collect(),
+            );
+            builder.build(&mint_params, &spend_params)
+        };
+
+        let tx = build_tx(&[(60, token_a), (50, token_b), (50, token_a)]);
+        assert!(tx.verify(&mint_pvk, &spend_pvk).is_ok());
+
+        // Same total value, but moved from one token to the other
+        let tx = build_tx(&[(50, token_a), (110, token_b)]);
+        assert!(matches!(
+            tx.verify(&mint_pvk, &spend_pvk),
+            Err(VerifyFailed::MissingFunds)
+        ));
+
+        let tx = build_tx(&[(110, token_a), (50, jubjub::Fr::random(&mut OsRng))]);
+        assert!(matches!(
+            tx.verify(&mint_pvk, &spend_pvk),
+            Err(VerifyFailed::MissingFunds)
+        ));
+    }
+
+    #[test]
+    fn test_transaction_fee() {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+        let mint_pvk = PreparedKey::new(&mint_params.vk);
+        let spend_pvk = PreparedKey::new(&spend_params.vk);
+
+        let secret = jubjub::Fr::random(&mut OsRng);
+        let address = Address::from_secret(&secret);
+        let token_id = jubjub::Fr::random(&mut OsRng);
+
+        let build_tx = |fee: u64| {
+            let builder = TransactionBuilder {
+                fee: TransactionFee {
+                    value: fee,
+                    token_id,
+                },
+                ..TransactionBuilder::new(
+                    vec![TransactionBuilderClearInputInfo {
+                        value: 110,
+                        token_id,
+                        signature_secret: secret,
+                    }],
+                    vec![],
+                    vec![TransactionBuilderOutputInfo {
+                        value: 100,
+                        token_id,
+                        address,
+                        memo: Default::default(),
+                    }],
+                )
+            };
+            builder.build(&mint_params, &spend_params)
+        };
+
+        let tx = build_tx(10);
+        assert!(tx.verify(&mint_pvk, &spend_pvk).is_ok());
+
+        let tx = build_tx(5);
+        assert!(matches!(
+            tx.verify(&mint_pvk, &spend_pvk),
+            Err(VerifyFailed::MissingFunds)
+        ));
+
+        // The fee must be paid in the token that is left over
+        let mut tx = build_tx(10);
+        tx.fee.token_id = jubjub::Fr::random(&mut OsRng);
+        assert!(matches!(
+            tx.verify(&mint_pvk, &spend_pvk),
+            Err(VerifyFailed::MissingFunds)
+        ));
+    }
+
+    #[test]
+    fn test_transaction_id() -> Result<()> {
+        let mint_params = setup_mint_prover();
+        let spend_params = setup_spend_prover();
+
+        let secret = jubjub::Fr::random(&mut OsRng);
+        let token_id = jubjub::Fr::random(&mut OsRng);
+        let tx = TransactionBuilder::new(
+            vec![TransactionBuilderClearInputInfo {
+                value: 110,
+                token_id,
+                signature_secret: secret,
+            }],
+            vec![],
+            vec![TransactionBuilderOutputInfo {
+                value: 110,
+                token_id,
+                address: Address::from_secret(&secret),
+                memo: Default::default(),
+            }],
+        )
+        .build(&mint_params, &spend_params);
+
+        // The ID survives a round trip through the wire format
+        let mut data = vec![];
+        tx.encode(&mut data)?;
+        let decoded = Transaction::decode(&data[..])?;
+        assert_eq!(decoded.id(), tx.id());
+
+        let mut changed = decoded;
+        changed.fee.value += 1;
+        assert_ne!(changed.id(), tx.id());
+
+        Ok(())
+    }
+}
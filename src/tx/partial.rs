@@ -2,7 +2,7 @@ use bellman::groth16;
 use bls12_381::Bls12;
 use std::io;
 
-use super::TransactionOutput;
+use super::{TransactionFee, TransactionOutput};
 use crate::crypto::SpendRevealedValues;
 use crate::error::Result;
 use crate::impl_vec;
@@ -12,13 +12,15 @@ pub struct PartialTransaction {
     pub clear_inputs: Vec<PartialTransactionClearInput>,
     pub inputs: Vec<PartialTransactionInput>,
     pub outputs: Vec<TransactionOutput>,
+    pub fee: TransactionFee,
+    pub valid_after: Option<u64>,
+    pub expires_at: Option<u64>,
 }
 
 pub struct PartialTransactionClearInput {
     pub value: u64,
     pub token_id: jubjub::Fr,
     pub valcom_blind: jubjub::Fr,
-    pub token_commit_blind: jubjub::Fr,
     pub signature_public: jubjub::SubgroupPoint,
 }
 
@@ -32,7 +34,10 @@ impl Encodable for PartialTransaction {
         let mut len = 0;
         len += self.clear_inputs.encode(&mut s)?;
         len += self.inputs.encode(&mut s)?;
-        len += self.outputs.encode(s)?;
+        len += self.outputs.encode(&mut s)?;
+        len += self.fee.encode(&mut s)?;
+        len += self.valid_after.encode(&mut s)?;
+        len += self.expires_at.encode(s)?;
         Ok(len)
     }
 }
@@ -42,7 +47,10 @@ impl Decodable for PartialTransaction {
         Ok(Self {
             clear_inputs: Decodable::decode(&mut d)?,
             inputs: Decodable::decode(&mut d)?,
-            outputs: Decodable::decode(d)?,
+            outputs: Decodable::decode(&mut d)?,
+            fee: Decodable::decode(&mut d)?,
+            valid_after: Decodable::decode(&mut d)?,
+            expires_at: Decodable::decode(d)?,
         })
     }
 }
@@ -53,7 +61,6 @@ impl Encodable for PartialTransactionClearInput {
         len += self.value.encode(&mut s)?;
         len += self.token_id.encode(&mut s)?;
         len += self.valcom_blind.encode(&mut s)?;
-        len += self.token_commit_blind.encode(&mut s)?;
         len += self.signature_public.encode(&mut s)?;
         Ok(len)
     }
@@ -64,7 +71,6 @@ impl Decodable for PartialTransactionClearInput {
             value: Decodable::decode(&mut d)?,
             token_id: Decodable::decode(&mut d)?,
             valcom_blind: Decodable::decode(&mut d)?,
-            token_commit_blind: Decodable::decode(&mut d)?,
             signature_public: Decodable::decode(&mut d)?,
         })
     }
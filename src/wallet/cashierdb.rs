@@ -6,6 +6,7 @@ use rusqlite::{named_params, params, Connection};
 
 use super::{Keypair, WalletApi};
 use crate::client::ClientFailed;
+use crate::crypto::keys::Address;
 use crate::util::NetworkName;
 use crate::{Error, Result};
 
@@ -31,7 +32,7 @@ pub struct WithdrawToken {
 }
 
 pub struct DepositToken {
-    pub drk_public_key: jubjub::SubgroupPoint,
+    pub drk_public_key: Address,
     pub token_key: TokenKey,
     pub token_id: jubjub::Fr,
     pub mint_address: String,
@@ -193,7 +194,7 @@ impl CashierDb {
 
     pub fn put_deposit_keys(
         &self,
-        d_key_public: &jubjub::SubgroupPoint,
+        d_key_public: &Address,
         token_key_private: &[u8],
         token_key_public: &[u8],
         network: &NetworkName,
@@ -304,7 +305,7 @@ impl CashierDb {
 
     pub fn get_deposit_token_keys_by_dkey_public(
         &self,
-        d_key_public: &jubjub::SubgroupPoint,
+        d_key_public: &Address,
         network: &NetworkName,
     ) -> Result<Vec<TokenKey>> {
         debug!(target: "CASHIERDB", "Check for existing dkey");
@@ -380,7 +381,7 @@ impl CashierDb {
 
         for key in keys_iter {
             let key = key?;
-            let drk_public_key: jubjub::SubgroupPoint = self.get_value_deserialized(key.0)?;
+            let drk_public_key: Address = self.get_value_deserialized(key.0)?;
             let private_key = key.1;
             let public_key = key.2;
             let token_id: jubjub::Fr = self.get_value_deserialized(key.3)?;
@@ -471,7 +472,7 @@ impl CashierDb {
 
     pub fn confirm_deposit_key_record(
         &self,
-        d_key_public: &jubjub::SubgroupPoint,
+        d_key_public: &Address,
         network: &NetworkName,
     ) -> Result<()> {
         debug!(target: "CASHIERDB", "Confirm withdraw keys");
@@ -568,7 +569,7 @@ mod tests {
         let network = NetworkName::Bitcoin;
 
         let secret2: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
-        let public2 = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret2;
+        let public2 = Address::from_secret(&secret2);
         let token_id: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
 
         wallet.put_deposit_keys(
@@ -4,4 +4,4 @@ pub mod walletdb;
 
 pub use cashierdb::{CashierDb, CashierDbPtr};
 pub use wallet_api::WalletApi;
-pub use walletdb::{Keypair, WalletDb, WalletPtr};
+pub use walletdb::{Keypair, SentTransaction, ViewedNote, WalletDb, WalletPtr};
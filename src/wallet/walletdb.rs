@@ -2,28 +2,53 @@ use async_std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
-use ff::Field;
+use bip39::{Language, Mnemonic, MnemonicType, Seed};
 use log::*;
-use rand::rngs::OsRng;
 use rusqlite::{named_params, params, Connection};
 
 use super::WalletApi;
 use crate::client::ClientFailed;
 use crate::crypto::{
-    coin::Coin, merkle::IncrementalWitness, merkle_node::MerkleNode, note::Note,
-    nullifier::Nullifier, OwnCoin, OwnCoins,
+    coin::Coin,
+    keys::{Address, ExtendedSecret, OutgoingViewingKey, ViewingKey},
+    merkle::IncrementalWitness,
+    merkle_node::MerkleNode,
+    note::{Memo, Note},
+    nullifier::Nullifier,
+    OwnCoin, OwnCoins,
 };
 use crate::serial;
 use crate::{Error, Result};
 
 pub type WalletPtr = Arc<WalletDb>;
 
+// Version of the tables created by schema.sql, kept in SQLite's
+// user_version. Wallets written by an older version are brought up to
+// date when they are opened.
+const WALLET_VERSION: u32 = 2;
+
 #[derive(Debug, Clone)]
 pub struct Keypair {
     pub public: jubjub::SubgroupPoint,
     pub private: jubjub::Fr,
 }
 
+impl Keypair {
+    /// The address coins are sent to, with notes for the viewing key
+    pub fn address(&self) -> Address {
+        Address::from_secret(&self.private)
+    }
+
+    pub fn viewing_key(&self) -> ViewingKey {
+        ViewingKey::from_secret(&self.private)
+    }
+
+    /// Recovers the notes sent from this keypair
+    pub fn outgoing_viewing_key(&self) -> OutgoingViewingKey {
+        OutgoingViewingKey::from_secret(&self.private)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Balance {
     pub token_id: jubjub::Fr,
@@ -49,6 +74,25 @@ impl Balances {
     }
 }
 
+/// An output sent from this wallet, keyed by its transaction ID and coin
+#[derive(Debug, Clone)]
+pub struct SentTransaction {
+    pub tx_id: [u8; 32],
+    pub coin: Coin,
+    pub token_id: jubjub::Fr,
+    pub value: u64,
+    pub recipient: Address,
+    pub memo: Memo,
+}
+
+/// A note received by an address the wallet only has the viewing key of
+#[derive(Clone)]
+pub struct ViewedNote {
+    pub coin: Coin,
+    pub view_public: jubjub::SubgroupPoint,
+    pub note: Note,
+}
+
 //#[derive(Clone)]
 pub struct WalletDb {
     pub path: PathBuf,
@@ -78,11 +122,10 @@ impl WalletDb {
     pub async fn init_db(&self) -> Result<()> {
         if !*self.initialized.lock().await {
             if !self.password.trim().is_empty() {
-                let contents = include_str!("../../sql/schema.sql");
                 let conn = Connection::open(&self.path)?;
                 debug!(target: "WALLETDB", "OPENED CONNECTION AT PATH {:?}", self.path);
                 conn.pragma_update(None, "key", &self.password)?;
-                conn.execute_batch(contents)?;
+                Self::create_tables(&conn)?;
                 *self.initialized.lock().await = true;
             } else {
                 debug!(
@@ -98,23 +141,187 @@ impl WalletDb {
         Ok(())
     }
 
+    // Create the tables of a new wallet, or migrate the tables of an
+    // existing one one version at a time until they match WALLET_VERSION.
+    fn create_tables(conn: &Connection) -> Result<()> {
+        let is_new = conn.query_row(
+            "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = 'keys'",
+            [],
+            |row| row.get::<_, i64>(0),
+        )? == 0;
+
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(include_str!("../../sql/schema.sql"))?;
+
+        let mut version: u32 = if is_new {
+            WALLET_VERSION
+        } else {
+            tx.query_row("PRAGMA user_version", [], |row| row.get(0))?
+        };
+
+        if version > WALLET_VERSION {
+            return Err(Error::UnsupportedSchemaVersion(version, WALLET_VERSION));
+        }
+
+        while version < WALLET_VERSION {
+            info!(
+                target: "WALLETDB",
+                "Migrate wallet from version {} to {}", version, version + 1
+            );
+            match version {
+                0 => Self::add_memo_columns(&tx)?,
+                1 => Self::key_transactions_by_coin(&tx)?,
+                _ => unreachable!(),
+            }
+            version += 1;
+        }
+
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Version 0 had no memos, coins and transactions stored before get an
+    // empty one. Wallets created before the version was kept may have the
+    // columns already.
+    fn add_memo_columns(conn: &Connection) -> Result<()> {
+        let empty = serial::serialize(&Memo::default());
+
+        for table in ["coins", "transactions"] {
+            let has_memo = conn.query_row(
+                "SELECT count(*) FROM pragma_table_info(?1) WHERE name = 'memo'",
+                params![table],
+                |row| row.get::<_, i64>(0),
+            )? > 0;
+            if has_memo {
+                continue;
+            }
+
+            conn.execute(
+                &format!(
+                    "ALTER TABLE {} ADD COLUMN memo BLOB NOT NULL DEFAULT x''",
+                    table
+                ),
+                [],
+            )?;
+            conn.execute(&format!("UPDATE {} SET memo = ?1", table), params![empty])?;
+        }
+
+        Ok(())
+    }
+
+    // Version 1 kept one send per transaction, so a transaction paying
+    // several recipients lost all but one. Sends stored before have no
+    // coin and get an all zero one.
+    fn key_transactions_by_coin(conn: &Connection) -> Result<()> {
+        let no_coin = serial::serialize(&Coin::new([0u8; 32]));
+
+        conn.execute_batch(
+            "ALTER TABLE transactions RENAME TO transactions_v1;
+            CREATE TABLE transactions(
+                tx_id BLOB NOT NULL,
+                coin BLOB NOT NULL,
+                token_id BLOB NOT NULL,
+                value INTEGER NOT NULL,
+                recipient BLOB NOT NULL,
+                memo BLOB NOT NULL,
+                PRIMARY KEY(tx_id, coin)
+            );",
+        )?;
+        conn.execute(
+            "INSERT INTO transactions (tx_id, coin, token_id, value, recipient, memo)
+            SELECT tx_id, ?1, token_id, value, recipient, memo FROM transactions_v1;",
+            params![no_coin],
+        )?;
+        conn.execute("DROP TABLE transactions_v1;", [])?;
+
+        Ok(())
+    }
+
+    /// Create a new seed and derive the first account from it. The
+    /// mnemonic phrase of the seed backs up every account of the wallet.
     pub fn key_gen(&self) -> Result<()> {
         debug!(target: "WALLETDB", "Attempting to generate keys...");
+        self.check_no_keys()?;
+        let mnemonic = Mnemonic::new(MnemonicType::Words24, Language::English);
+        self.put_mnemonic(mnemonic.phrase())?;
+        self.new_account()?;
+        Ok(())
+    }
+
+    /// Restore the seed of a wallet from its mnemonic phrase, and derive
+    /// its first `accounts` accounts. Accounts are derived in order, so
+    /// these are the same keys the wallet had.
+    pub fn restore(&self, phrase: &str, accounts: u32) -> Result<()> {
+        debug!(target: "WALLETDB", "Restore keys from mnemonic");
+        if accounts == 0 {
+            return Err(Error::from(ClientFailed::ClientError(
+                "A wallet has at least one account".into(),
+            )));
+        }
+        let mnemonic = Self::parse_mnemonic(phrase)?;
+        self.check_no_keys()?;
+        self.put_mnemonic(mnemonic.phrase())?;
+        for _ in 0..accounts {
+            self.new_account()?;
+        }
+        Ok(())
+    }
+
+    fn check_no_keys(&self) -> Result<()> {
         let conn = Connection::open(&self.path)?;
         conn.pragma_update(None, "key", &self.password)?;
         let mut stmt = conn.prepare("SELECT * FROM keys WHERE key_id > ?")?;
         let key_check = stmt.exists(params!["0"])?;
-        if !key_check {
-            let secret: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
-            let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * secret;
-            self.put_keypair(&public, &secret)?;
-        } else {
+        if key_check {
             debug!(target: "WALLETDB", "Keys already exist.");
             return Err(Error::from(ClientFailed::KeyExists));
         }
         Ok(())
     }
 
+    fn parse_mnemonic(phrase: &str) -> Result<Mnemonic> {
+        Mnemonic::from_phrase(phrase.trim(), Language::English)
+            .map_err(|err| Error::from(ClientFailed::InvalidMnemonic(err.to_string())))
+    }
+
+    fn put_mnemonic(&self, phrase: &str) -> Result<()> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.password)?;
+        // A wallet has a single seed
+        conn.execute(
+            "INSERT OR REPLACE INTO seed(seed_id, mnemonic) VALUES (1, ?1)",
+            params![phrase],
+        )?;
+        Ok(())
+    }
+
+    /// The mnemonic phrase of the seed, None for wallets with no seed
+    pub fn get_mnemonic(&self) -> Result<Option<String>> {
+        let conn = Connection::open(&self.path)?;
+        conn.pragma_update(None, "key", &self.password)?;
+        let mut stmt = conn.prepare("SELECT mnemonic FROM seed")?;
+        let mut rows = stmt.query_map([], |row| row.get(0))?;
+        Ok(rows.next().transpose()?)
+    }
+
+    /// Derive the next account from the seed. Accounts are numbered by
+    /// the order they are added in, so restoring them is deterministic.
+    pub fn new_account(&self) -> Result<Keypair> {
+        let phrase = self
+            .get_mnemonic()?
+            .ok_or_else(|| Error::from(ClientFailed::NoSeed))?;
+        let seed = Seed::new(&Self::parse_mnemonic(&phrase)?, "");
+
+        let account = self.get_keypairs()?.len() as u32;
+        debug!(target: "WALLETDB", "Derive account {}", account);
+
+        let private = ExtendedSecret::account(seed.as_bytes(), account).secret;
+        let public = zcash_primitives::constants::SPENDING_KEY_GENERATOR * private;
+        self.put_keypair(&public, &private)?;
+        Ok(Keypair { public, private })
+    }
+
     pub fn put_keypair(
         &self,
         key_public: &jubjub::SubgroupPoint,
@@ -176,6 +383,7 @@ impl WalletDb {
                 row.get(6)?,
                 row.get(7)?,
                 row.get(9)?,
+                row.get(10)?,
             ))
         })?;
 
@@ -191,6 +399,7 @@ impl WalletDb {
             let valcom_blind = self.get_value_deserialized(row.3)?;
             let value: u64 = row.4;
             let token_id = self.get_value_deserialized(row.5)?;
+            let memo = self.get_value_deserialized(row.9)?;
 
             let note = Note {
                 serial,
@@ -198,6 +407,7 @@ impl WalletDb {
                 token_id,
                 coin_blind,
                 valcom_blind,
+                memo,
             };
 
             let witness = self.get_value_deserialized(row.6)?;
@@ -237,14 +447,15 @@ impl WalletDb {
         let secret = self.get_value_serialized(&own_coin.secret)?;
         let is_spent = 0;
         let nullifier = self.get_value_serialized(&own_coin.nullifier)?;
+        let memo = self.get_value_serialized(&own_coin.note.memo)?;
 
         conn.execute(
             "INSERT OR REPLACE INTO coins
             (coin, serial, value, token_id, coin_blind, 
-            valcom_blind, witness, secret, is_spent, nullifier)
+            valcom_blind, witness, secret, is_spent, nullifier, memo)
             VALUES
             (:coin, :serial, :value, :token_id, :coin_blind, 
-             :valcom_blind, :witness, :secret, :is_spent, :nullifier);",
+             :valcom_blind, :witness, :secret, :is_spent, :nullifier, :memo);",
             named_params! {
                 ":coin": coin,
                 ":serial": serial,
@@ -256,6 +467,7 @@ impl WalletDb {
                 ":secret": secret,
                 ":is_spent": is_spent,
                 ":nullifier": nullifier,
+                ":memo": memo,
             },
         )?;
         Ok(())
@@ -295,6 +507,267 @@ impl WalletDb {
         Ok(())
     }
 
+    /// Mark the coin with this nullifier unspent again, when the slab
+    /// revealing the nullifier is rolled back
+    pub fn unspend_coin(&self, nullifier: &Nullifier) -> Result<()> {
+        debug!(target: "WALLETDB", "Unspend coin");
+
+        let nullifier = self.get_value_serialized(nullifier)?;
+
+        // open connection
+        let conn = Connection::open(&self.path)?;
+        // unlock database
+        conn.pragma_update(None, "key", &self.password)?;
+
+        let is_spent = 0;
+
+        conn.execute(
+            "UPDATE coins
+            SET is_spent = ?1
+            WHERE nullifier = ?2 ;",
+            params![is_spent, nullifier],
+        )?;
+
+        Ok(())
+    }
+
+    /// Forget a coin, own, viewed or sent, when the slab minting it is
+    /// rolled back
+    pub fn forget_coin(&self, coin: &Coin) -> Result<()> {
+        debug!(target: "WALLETDB", "Forget coin");
+
+        let coin = self.get_value_serialized(coin)?;
+
+        // open connection
+        let conn = Connection::open(&self.path)?;
+        // unlock database
+        conn.pragma_update(None, "key", &self.password)?;
+
+        conn.execute("DELETE FROM coins WHERE coin = ?1 ;", params![coin])?;
+        conn.execute("DELETE FROM viewed_notes WHERE coin = ?1 ;", params![coin])?;
+        conn.execute("DELETE FROM transactions WHERE coin = ?1 ;", params![coin])?;
+
+        Ok(())
+    }
+
+    pub fn put_transaction(&self, tx: &SentTransaction) -> Result<()> {
+        debug!(target: "WALLETDB", "Put transaction");
+
+        // open connection
+        let conn = Connection::open(&self.path)?;
+        // unlock database
+        conn.pragma_update(None, "key", &self.password)?;
+
+        let tx_id = self.get_value_serialized(&tx.tx_id)?;
+        let coin = self.get_value_serialized(&tx.coin)?;
+        let token_id = self.get_value_serialized(&tx.token_id)?;
+        let recipient = self.get_value_serialized(&tx.recipient)?;
+        let memo = self.get_value_serialized(&tx.memo)?;
+
+        conn.execute(
+            "INSERT OR REPLACE INTO transactions
+            (tx_id, coin, token_id, value, recipient, memo)
+            VALUES
+            (:tx_id, :coin, :token_id, :value, :recipient, :memo);",
+            named_params! {
+                ":tx_id": tx_id,
+                ":coin": coin,
+                ":token_id": token_id,
+                ":value": tx.value,
+                ":recipient": recipient,
+                ":memo": memo,
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn get_transactions(&self) -> Result<Vec<SentTransaction>> {
+        debug!(target: "WALLETDB", "Get transactions");
+
+        let conn = Connection::open(&self.path)?;
+        // unlock database
+        conn.pragma_update(None, "key", &self.password)?;
+
+        let mut stmt = conn.prepare("SELECT * FROM transactions")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+            ))
+        })?;
+
+        let mut txs = Vec::new();
+        for row in rows {
+            let row = row?;
+            txs.push(SentTransaction {
+                tx_id: self.get_value_deserialized(row.0)?,
+                coin: self.get_value_deserialized(row.1)?,
+                token_id: self.get_value_deserialized(row.2)?,
+                value: row.3,
+                recipient: self.get_value_deserialized(row.4)?,
+                memo: self.get_value_deserialized(row.5)?,
+            });
+        }
+
+        Ok(txs)
+    }
+
+    /// Every note received by the wallet, spent or not
+    pub fn get_received_notes(&self) -> Result<Vec<Note>> {
+        debug!(target: "WALLETDB", "Get received notes");
+
+        let conn = Connection::open(&self.path)?;
+        // unlock database
+        conn.pragma_update(None, "key", &self.password)?;
+
+        let mut stmt = conn
+            .prepare("SELECT serial, value, token_id, coin_blind, valcom_blind, memo FROM coins")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+            ))
+        })?;
+
+        let mut notes = Vec::new();
+        for row in rows {
+            let row = row?;
+            notes.push(Note {
+                serial: self.get_value_deserialized(row.0)?,
+                value: row.1,
+                token_id: self.get_value_deserialized(row.2)?,
+                coin_blind: self.get_value_deserialized(row.3)?,
+                valcom_blind: self.get_value_deserialized(row.4)?,
+                memo: self.get_value_deserialized(row.5)?,
+            });
+        }
+
+        Ok(notes)
+    }
+
+    /// Add a view-only key, whose notes are kept without being spendable
+    pub fn put_view_key(&self, view_key: &ViewingKey) -> Result<()> {
+        debug!(target: "WALLETDB", "Put view key");
+
+        let conn = Connection::open(&self.path)?;
+        // unlock database
+        conn.pragma_update(None, "key", &self.password)?;
+
+        let view_key = self.get_value_serialized(view_key)?;
+
+        conn.execute(
+            "INSERT OR IGNORE INTO view_keys(view_key) VALUES (?1)",
+            params![view_key],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_view_keys(&self) -> Result<Vec<ViewingKey>> {
+        debug!(target: "WALLETDB", "Get view keys");
+
+        let conn = Connection::open(&self.path)?;
+        // unlock database
+        conn.pragma_update(None, "key", &self.password)?;
+
+        let mut stmt = conn.prepare("SELECT view_key FROM view_keys")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+
+        let mut view_keys = Vec::new();
+        for row in rows {
+            view_keys.push(self.get_value_deserialized(row?)?);
+        }
+
+        Ok(view_keys)
+    }
+
+    pub fn put_viewed_note(&self, viewed: &ViewedNote) -> Result<()> {
+        debug!(target: "WALLETDB", "Put viewed note");
+
+        let conn = Connection::open(&self.path)?;
+        // unlock database
+        conn.pragma_update(None, "key", &self.password)?;
+
+        let coin = self.get_value_serialized(&viewed.coin.repr)?;
+        let view_public = self.get_value_serialized(&viewed.view_public)?;
+        let serial = self.get_value_serialized(&viewed.note.serial)?;
+        let token_id = self.get_value_serialized(&viewed.note.token_id)?;
+        let coin_blind = self.get_value_serialized(&viewed.note.coin_blind)?;
+        let valcom_blind = self.get_value_serialized(&viewed.note.valcom_blind)?;
+        let memo = self.get_value_serialized(&viewed.note.memo)?;
+
+        conn.execute(
+            "INSERT OR REPLACE INTO viewed_notes
+            (coin, view_public, serial, value, token_id, coin_blind, valcom_blind, memo)
+            VALUES
+            (:coin, :view_public, :serial, :value, :token_id, :coin_blind, :valcom_blind, :memo);",
+            named_params! {
+                ":coin": coin,
+                ":view_public": view_public,
+                ":serial": serial,
+                ":value": viewed.note.value,
+                ":token_id": token_id,
+                ":coin_blind": coin_blind,
+                ":valcom_blind": valcom_blind,
+                ":memo": memo,
+            },
+        )?;
+        Ok(())
+    }
+
+    /// Every note received by the view-only keys. Without the spending key
+    /// the wallet can not tell whether they were spent.
+    pub fn get_viewed_notes(&self) -> Result<Vec<ViewedNote>> {
+        debug!(target: "WALLETDB", "Get viewed notes");
+
+        let conn = Connection::open(&self.path)?;
+        // unlock database
+        conn.pragma_update(None, "key", &self.password)?;
+
+        let mut stmt = conn.prepare(
+            "SELECT coin, view_public, serial, value, token_id, coin_blind, valcom_blind, memo
+            FROM viewed_notes",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+                row.get(5)?,
+                row.get(6)?,
+                row.get(7)?,
+            ))
+        })?;
+
+        let mut notes = Vec::new();
+        for row in rows {
+            let row = row?;
+            notes.push(ViewedNote {
+                coin: Coin::new(self.get_value_deserialized(row.0)?),
+                view_public: self.get_value_deserialized(row.1)?,
+                note: Note {
+                    serial: self.get_value_deserialized(row.2)?,
+                    value: row.3,
+                    token_id: self.get_value_deserialized(row.4)?,
+                    coin_blind: self.get_value_deserialized(row.5)?,
+                    valcom_blind: self.get_value_deserialized(row.6)?,
+                    memo: self.get_value_deserialized(row.7)?,
+                },
+            });
+        }
+
+        Ok(notes)
+    }
+
     pub fn get_witnesses(&self) -> Result<HashMap<Vec<u8>, IncrementalWitness<MerkleNode>>> {
         let conn = Connection::open(&self.path)?;
         conn.pragma_update(None, "key", &self.password)?;
@@ -420,15 +893,15 @@ mod tests {
     use super::*;
     use crate::crypto::{coin::Coin, OwnCoin};
     use crate::util::join_config_path;
-    use ff::PrimeField;
+    use ff::{Field, PrimeField};
+    use rand::rngs::OsRng;
 
     pub fn init_db(path: &Path, password: String) -> Result<()> {
         if !password.trim().is_empty() {
-            let contents = include_str!("../../sql/schema.sql");
             let conn = Connection::open(path)?;
             debug!(target: "WALLETDB", "OPENED CONNECTION AT PATH {:?}", path);
             conn.pragma_update(None, "key", &password)?;
-            conn.execute_batch(contents)?;
+            WalletDb::create_tables(&conn)?;
         } else {
             debug!(
                 target: "WALLETDB", "Password is empty. You must set a password to use the wallet."
@@ -458,6 +931,7 @@ mod tests {
             token_id,
             coin_blind: jubjub::Fr::random(&mut OsRng),
             valcom_blind: jubjub::Fr::random(&mut OsRng),
+            memo: Memo::default(),
         };
 
         let coin = Coin::new(bls12_381::Scalar::random(&mut OsRng).to_repr());
@@ -516,6 +990,7 @@ mod tests {
             token_id,
             coin_blind: jubjub::Fr::random(&mut OsRng),
             valcom_blind: jubjub::Fr::random(&mut OsRng),
+            memo: Memo::default(),
         };
 
         let coin = Coin::new(bls12_381::Scalar::random(&mut OsRng).to_repr());
@@ -573,6 +1048,39 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    pub fn test_restore_keys_from_mnemonic() -> Result<()> {
+        let walletdb_path = join_config_path(&PathBuf::from("test8_wallet.db"))?;
+        let restored_path = join_config_path(&PathBuf::from("test9_wallet.db"))?;
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        let restored = WalletDb::new(&restored_path, password.clone())?;
+        init_db(&walletdb_path, password.clone())?;
+        init_db(&restored_path, password)?;
+
+        wallet.key_gen()?;
+        let second = wallet.new_account()?;
+        assert!(wallet.key_gen().is_err());
+
+        let phrase = wallet.get_mnemonic()?.unwrap();
+        assert_eq!(phrase.split_whitespace().count(), 24);
+        assert!(restored.restore("not a mnemonic", 2).is_err());
+        assert!(restored.restore(&phrase, 0).is_err());
+
+        restored.restore(&phrase, 2)?;
+
+        let keypairs = restored.get_keypairs()?;
+        assert_eq!(keypairs.len(), 2);
+        assert_eq!(keypairs[0].private, wallet.get_keypairs()?[0].private);
+        assert_eq!(keypairs[1].private, second.private);
+        assert_ne!(keypairs[0].private, keypairs[1].private);
+
+        std::fs::remove_file(walletdb_path)?;
+        std::fs::remove_file(restored_path)?;
+
+        Ok(())
+    }
+
     #[test]
     pub fn test_put_and_get_own_coins() -> Result<()> {
         let walletdb_path = join_config_path(&PathBuf::from("test4_wallet.db"))?;
@@ -591,6 +1099,7 @@ mod tests {
             token_id: jubjub::Fr::random(&mut OsRng),
             coin_blind: jubjub::Fr::random(&mut OsRng),
             valcom_blind: jubjub::Fr::random(&mut OsRng),
+            memo: Memo::new(b"rent")?,
         };
 
         let coin = Coin::new(bls12_381::Scalar::random(&mut OsRng).to_repr());
@@ -624,6 +1133,7 @@ mod tests {
         assert_eq!(own_coin.witness.root(), witness.root());
         assert_eq!(own_coin.witness.path(), witness.path());
         assert_eq!(own_coin.nullifier, nullifier);
+        assert_eq!(own_coin.note.memo.as_bytes(), b"rent");
 
         wallet.confirm_spend_coin(&own_coin.coin)?;
 
@@ -631,6 +1141,19 @@ mod tests {
 
         assert_eq!(own_coins.len(), 0);
 
+        // Spent notes stay in the history
+        let notes = wallet.get_received_notes()?;
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].memo.as_bytes(), b"rent");
+
+        // Rolling back the spend and then the mint
+        wallet.unspend_coin(&own_coin.nullifier)?;
+        assert_eq!(wallet.get_own_coins()?.len(), 1);
+
+        wallet.forget_coin(&own_coin.coin)?;
+        assert_eq!(wallet.get_own_coins()?.len(), 0);
+        assert_eq!(wallet.get_received_notes()?.len(), 0);
+
         wallet.put_own_coins(own_coin)?;
 
         let own_coins = wallet.get_own_coins()?;
@@ -668,6 +1191,7 @@ mod tests {
             token_id: jubjub::Fr::random(&mut OsRng),
             coin_blind: jubjub::Fr::random(&mut OsRng),
             valcom_blind: jubjub::Fr::random(&mut OsRng),
+            memo: Memo::default(),
         };
 
         let coin = Coin::new(bls12_381::Scalar::random(&mut OsRng).to_repr());
@@ -714,4 +1238,151 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    pub fn test_put_and_get_transactions() -> Result<()> {
+        let walletdb_path = join_config_path(&PathBuf::from("test6_wallet.db"))?;
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        init_db(&walletdb_path, password)?;
+
+        let secret: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
+        let tx = SentTransaction {
+            tx_id: [7u8; 32],
+            coin: Coin::new([1u8; 32]),
+            token_id: jubjub::Fr::random(&mut OsRng),
+            value: 110,
+            recipient: Address::from_secret(&secret),
+            memo: Memo::new(b"for the coffee")?,
+        };
+
+        wallet.put_transaction(&tx)?;
+        // Recording the same output again keeps a single row
+        wallet.put_transaction(&tx)?;
+
+        let txs = wallet.get_transactions()?;
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].tx_id, tx.tx_id);
+        assert_eq!(txs[0].coin, tx.coin);
+        assert_eq!(txs[0].token_id, tx.token_id);
+        assert_eq!(txs[0].value, 110);
+        assert_eq!(txs[0].recipient, tx.recipient);
+        assert_eq!(txs[0].memo.as_bytes(), b"for the coffee");
+
+        // A second recipient of the same transaction is kept as well
+        let other = SentTransaction {
+            coin: Coin::new([2u8; 32]),
+            value: 120,
+            ..tx
+        };
+        wallet.put_transaction(&other)?;
+
+        let txs = wallet.get_transactions()?;
+        assert_eq!(txs.len(), 2);
+        assert!(txs
+            .iter()
+            .any(|sent| sent.coin == other.coin && sent.value == 120));
+
+        std::fs::remove_file(walletdb_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_put_and_get_view_keys_and_notes() -> Result<()> {
+        let walletdb_path = join_config_path(&PathBuf::from("test7_wallet.db"))?;
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+        init_db(&walletdb_path, password)?;
+
+        let view_key = ViewingKey::from_secret(&jubjub::Fr::random(&mut OsRng));
+        wallet.put_view_key(&view_key)?;
+        // Importing the same key again is a no-op
+        wallet.put_view_key(&view_key)?;
+        assert_eq!(wallet.get_view_keys()?, vec![view_key]);
+
+        let viewed = ViewedNote {
+            coin: Coin::new([3u8; 32]),
+            view_public: view_key.public(),
+            note: Note {
+                serial: jubjub::Fr::random(&mut OsRng),
+                value: 110,
+                token_id: jubjub::Fr::random(&mut OsRng),
+                coin_blind: jubjub::Fr::random(&mut OsRng),
+                valcom_blind: jubjub::Fr::random(&mut OsRng),
+                memo: Memo::new(b"salary")?,
+            },
+        };
+        wallet.put_viewed_note(&viewed)?;
+
+        let notes = wallet.get_viewed_notes()?;
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].coin, viewed.coin);
+        assert_eq!(notes[0].view_public, view_key.public());
+        assert_eq!(notes[0].note.value, 110);
+        assert_eq!(notes[0].note.memo.as_bytes(), b"salary");
+
+        // View-only notes are not the wallet's own coins
+        assert!(wallet.get_own_coins()?.is_empty());
+
+        std::fs::remove_file(walletdb_path)?;
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_migrate_memo_columns() -> Result<()> {
+        let walletdb_path = join_config_path(&PathBuf::from("test10_wallet.db"))?;
+        let password: String = "darkfi".into();
+        let wallet = WalletDb::new(&walletdb_path, password.clone())?;
+
+        // The tables as they were before memos
+        let conn = Connection::open(&walletdb_path)?;
+        conn.pragma_update(None, "key", &password)?;
+        conn.execute_batch(
+            "CREATE TABLE keys(
+                key_id INTEGER PRIMARY KEY NOT NULL,
+                key_public BLOB NOT NULL,
+                key_private BLOB NOT NULL
+            );
+            CREATE TABLE transactions(
+                tx_id BLOB PRIMARY KEY NOT NULL,
+                token_id BLOB NOT NULL,
+                value INTEGER NOT NULL,
+                recipient BLOB NOT NULL
+            );",
+        )?;
+
+        let secret: jubjub::Fr = jubjub::Fr::random(&mut OsRng);
+        let token_id = jubjub::Fr::random(&mut OsRng);
+        conn.execute(
+            "INSERT INTO transactions (tx_id, token_id, value, recipient)
+            VALUES (?1, ?2, ?3, ?4);",
+            params![
+                serial::serialize(&[7u8; 32]),
+                serial::serialize(&token_id),
+                110,
+                serial::serialize(&Address::from_secret(&secret)),
+            ],
+        )?;
+
+        WalletDb::create_tables(&conn)?;
+        let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        assert_eq!(version, WALLET_VERSION);
+
+        let txs = wallet.get_transactions()?;
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].tx_id, [7u8; 32]);
+        assert_eq!(txs[0].coin, Coin::new([0u8; 32]));
+        assert_eq!(txs[0].value, 110);
+        assert!(txs[0].memo.as_bytes().is_empty());
+
+        // Opening it again leaves the tables as they are
+        WalletDb::create_tables(&conn)?;
+        assert_eq!(wallet.get_transactions()?.len(), 1);
+
+        std::fs::remove_file(walletdb_path)?;
+
+        Ok(())
+    }
 }